@@ -0,0 +1,119 @@
+//! 签名、xml 序列化/解析和下单流程的性能基准
+//!
+//! 运行: `cargo bench`; 下单使用固定响应的 `Transport`, 不访问网络
+
+#[macro_use]
+extern crate criterion;
+extern crate wechatpay;
+
+use std::collections::BTreeMap;
+
+use criterion::{black_box, Criterion};
+use wechatpay::{Transport, WechatpayClient, WechatpayError};
+
+const API_KEY: &str = "192006250b4c09247ec02edce69f6a2d";
+
+fn to_pairs(items: &[(&str, &str)]) -> BTreeMap<String, String> {
+    items
+        .iter()
+        .map(|&(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn request_pairs() -> BTreeMap<String, String> {
+    to_pairs(&[
+        ("appid", "wxd930ea5d5a258f4f"),
+        ("mch_id", "10000100"),
+        ("nonce_str", "ibuaiVcKdpRxkhJA"),
+        ("body", "APP支付测试"),
+        ("out_trade_no", "1415659990"),
+        ("total_fee", "1"),
+        ("spbill_create_ip", "14.23.150.211"),
+        ("notify_url", "https://example.com/notify"),
+        ("trade_type", "APP"),
+    ])
+}
+
+fn signed_response() -> String {
+    let mut pairs = to_pairs(&[
+        ("return_code", "SUCCESS"),
+        ("return_msg", "OK"),
+        ("appid", "wxd930ea5d5a258f4f"),
+        ("mch_id", "10000100"),
+        ("nonce_str", "IITRi8Iabbblz1Jc"),
+        ("result_code", "SUCCESS"),
+        ("prepay_id", "wx201411101639507cbf6ffd8b0779950874"),
+        ("trade_type", "APP"),
+    ]);
+    let sign = wechatpay::get_sign(&pairs, API_KEY);
+    pairs.insert("sign".to_string(), sign);
+    wechatpay::to_xml_str(&pairs)
+}
+
+/// 总是返回同一个响应
+struct FixedTransport {
+    body: Vec<u8>,
+}
+
+impl Transport for FixedTransport {
+    fn post(
+        &self,
+        _url: &str,
+        _body: &str,
+        _cert: Option<&str>,
+    ) -> Result<(u32, Vec<u8>), WechatpayError> {
+        Ok((200, self.body.clone()))
+    }
+}
+
+fn bench_get_sign(c: &mut Criterion) {
+    let pairs = request_pairs();
+    c.bench_function("get_sign", |b| {
+        b.iter(|| wechatpay::get_sign(black_box(&pairs), API_KEY))
+    });
+}
+
+fn bench_to_xml_str(c: &mut Criterion) {
+    let pairs = request_pairs();
+    c.bench_function("to_xml_str", |b| {
+        b.iter(|| wechatpay::to_xml_str(black_box(&pairs)))
+    });
+}
+
+fn bench_from_xml_str(c: &mut Criterion) {
+    let xml = signed_response();
+    c.bench_function("from_xml_str", |b| {
+        b.iter(|| wechatpay::from_xml_str(black_box(&xml)))
+    });
+}
+
+fn bench_pay(c: &mut Criterion) {
+    let client = WechatpayClient::new(
+        "wxd930ea5d5a258f4f",
+        "10000100",
+        API_KEY,
+        "https://example.com/notify",
+        "/path/to/apiclient_cert.pem",
+    )
+    .with_transport(FixedTransport {
+        body: signed_response().into_bytes(),
+    });
+    let params = to_pairs(&[
+        ("body", "APP支付测试"),
+        ("out_trade_no", "1415659990"),
+        ("total_fee", "1"),
+        ("spbill_create_ip", "14.23.150.211"),
+    ]);
+    c.bench_function("app_pay", |b| {
+        b.iter(|| client.app_pay(params.clone(), None).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_get_sign,
+    bench_to_xml_str,
+    bench_from_xml_str,
+    bench_pay
+);
+criterion_main!(benches);
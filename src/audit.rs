@@ -0,0 +1,42 @@
+//! 资金操作审计
+//!
+//! 下单、退款和下载对账单在调用接口之前先记录意图, 调用结束后记录结果。`AuditSink`
+//! 的实现应当在返回前完成持久化, 进程在请求过程中崩溃时也能从意图记录中找到未完成的操作。
+
+use WechatpayError;
+
+/// 审计记录的回调, 默认实现不做任何事
+///
+/// `operation` 为接口名 (如 `unifiedorder`, `micropay`, `refund`, `downloadbill`);
+/// `reference` 下单时为 `out_trade_no`, 退款时为 `out_refund_no`, 下载对账单时为
+/// `bill_date`; `trace_id` 为调用时 `RequestContext` 中的 `trace_id`, 和请求日志中的一致
+pub trait AuditSink: Send + Sync {
+    /// 即将调用接口, `amount` 为请求中的金额 (`total_fee` 或 `refund_fee`, 单位为【分】),
+    /// 没有金额的接口为空
+    fn record_intent(
+        &self,
+        operation: &str,
+        reference: &str,
+        amount: &str,
+        trace_id: Option<&str>,
+    ) {
+        let _ = (operation, reference, amount, trace_id);
+    }
+
+    /// 接口调用结束; 网络错误时交易状态未知, 需要查询确认
+    fn record_outcome(
+        &self,
+        operation: &str,
+        reference: &str,
+        outcome: Result<(), &WechatpayError>,
+        trace_id: Option<&str>,
+    ) {
+        let _ = (operation, reference, outcome, trace_id);
+    }
+}
+
+/// 不做任何记录, 为 client 的默认值
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {}
@@ -0,0 +1,373 @@
+//! [下载对账单]
+//!
+//! 对账单是以逗号分隔的文本, 第一行为表头, 中间为交易数据 (每个字段以 `` ` `` 开头),
+//! 最后两行为汇总数据。
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use time;
+
+use {format_year, Money, WechatpayError};
+
+/// 账单类型
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BillType {
+    /// `ALL`: 返回当日所有订单信息
+    All,
+    /// `SUCCESS`: 返回当日成功支付的订单
+    Success,
+    /// `REFUND`: 返回当日退款订单
+    Refund,
+    /// `RECHARGE_REFUND`: 返回当日充值退款订单
+    RechargeRefund,
+}
+
+impl fmt::Display for BillType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            BillType::All => "ALL",
+            BillType::Success => "SUCCESS",
+            BillType::Refund => "REFUND",
+            BillType::RechargeRefund => "RECHARGE_REFUND",
+        })
+    }
+}
+
+/// 对账单中的一条交易记录
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BillRow {
+    /// 交易时间
+    pub trade_time: String,
+    /// 设备号
+    pub device_info: String,
+    /// 微信订单号
+    pub transaction_id: String,
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 交易类型
+    pub trade_type: String,
+    /// 交易状态
+    pub trade_state: String,
+    /// 总金额, 单位为【分】
+    pub total_fee: u32,
+}
+
+/// 解析对账单文本, 忽略表头和汇总行
+///
+/// 金额无法解析时返回 `WechatpayError::InvalidField`, 而不是当作 0 参与对账
+pub fn parse_bill(data: &str) -> Result<Vec<BillRow>, WechatpayError> {
+    let mut lines = data
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty());
+    let header = match lines.next() {
+        Some(header) => header.split(',').map(|s| s.trim()).collect::<Vec<&str>>(),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut rows = Vec::new();
+    for line in lines {
+        // 汇总数据的表头不以 ` 开头, 之后的内容都不是交易记录
+        if !line.starts_with('`') {
+            break;
+        }
+        let record = header
+            .iter()
+            .cloned()
+            .zip(line.split(',').map(|s| s.trim().trim_start_matches('`')))
+            .collect::<BTreeMap<&str, &str>>();
+        let field = |name: &str| record.get(name).cloned().unwrap_or("").to_string();
+        let total_fee = field("总金额");
+        let total_fee = parse_yuan(&total_fee).ok_or_else(|| WechatpayError::InvalidField {
+            field: "total_fee".to_string(),
+            reason: format!(
+                "{:?} of order {:?} is not an amount in yuan",
+                total_fee,
+                field("商户订单号")
+            ),
+        })?;
+        rows.push(BillRow {
+            trade_time: field("交易时间"),
+            device_info: field("设备号"),
+            transaction_id: field("微信订单号"),
+            out_trade_no: field("商户订单号"),
+            trade_type: field("交易类型"),
+            trade_state: field("交易状态"),
+            total_fee,
+        });
+    }
+    Ok(rows)
+}
+
+/// 将以【元】为单位的金额 (如 `0.01`) 转换为【分】
+fn parse_yuan(value: &str) -> Option<u32> {
+    let cents = Money::from_yuan(value)?.cents();
+    if cents > u64::from(u32::MAX) {
+        return None;
+    }
+    Some(cents as u32)
+}
+
+/// 按设备号 (`device_info`) 对交易记录分组, 用于多终端对账
+///
+/// 每组内保持对账单中的顺序
+pub fn group_by_device(rows: Vec<BillRow>) -> HashMap<String, Vec<BillRow>> {
+    let mut groups = HashMap::new();
+    for row in rows {
+        groups
+            .entry(row.device_info.clone())
+            .or_insert_with(Vec::new)
+            .push(row);
+    }
+    groups
+}
+
+/// 金额不一致的订单
+#[derive(Clone, Debug, PartialEq)]
+pub struct AmountMismatch {
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 对账单中的金额, 单位为【分】
+    pub remote_fee: u32,
+    /// 商户系统中的金额, 单位为【分】
+    pub local_fee: u32,
+}
+
+/// 对账结果
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReconcileReport {
+    /// 微信有记录但商户系统没有的订单
+    pub only_remote: Vec<String>,
+    /// 商户系统有记录但微信没有的订单
+    pub only_local: Vec<String>,
+    /// 金额不一致的订单
+    pub amount_mismatches: Vec<AmountMismatch>,
+    /// 在对账单中出现多次的订单, 按各行金额之和与商户系统对比
+    pub duplicates: Vec<String>,
+}
+
+impl ReconcileReport {
+    /// 是否完全一致
+    pub fn is_consistent(&self) -> bool {
+        self.only_remote.is_empty()
+            && self.only_local.is_empty()
+            && self.amount_mismatches.is_empty()
+            && self.duplicates.is_empty()
+    }
+}
+
+/// 对比对账单记录和商户系统记录 (`out_trade_no` -> 金额【分】)
+pub fn reconcile_rows(rows: &[BillRow], local: &HashMap<String, u32>) -> ReconcileReport {
+    let mut report = ReconcileReport::default();
+    let mut remote = BTreeMap::new();
+    let mut duplicates = BTreeSet::new();
+    for row in rows {
+        let out_trade_no = row.out_trade_no.as_str();
+        if remote.contains_key(out_trade_no) {
+            duplicates.insert(out_trade_no);
+        }
+        let fee = remote.entry(out_trade_no).or_insert(0u32);
+        *fee = fee.saturating_add(row.total_fee);
+    }
+    report.duplicates = duplicates.into_iter().map(|s| s.to_string()).collect();
+
+    for (out_trade_no, &remote_fee) in &remote {
+        match local.get(*out_trade_no) {
+            None => report.only_remote.push(out_trade_no.to_string()),
+            Some(&local_fee) if local_fee != remote_fee => {
+                report.amount_mismatches.push(AmountMismatch {
+                    out_trade_no: out_trade_no.to_string(),
+                    remote_fee,
+                    local_fee,
+                });
+            }
+            _ => {}
+        }
+    }
+    let mut only_local = local
+        .keys()
+        .filter(|out_trade_no| !remote.contains_key(out_trade_no.as_str()))
+        .cloned()
+        .collect::<Vec<String>>();
+    only_local.sort();
+    report.only_local = only_local;
+    report
+}
+
+/// 解析 `yyyyMMdd` 格式的对账单日期
+pub(crate) fn parse_bill_date(bill_date: &str) -> Result<time::Tm, WechatpayError> {
+    let invalid = |reason: &str| WechatpayError::InvalidField {
+        field: "bill_date".to_string(),
+        reason: format!("{} {:?}", reason, bill_date),
+    };
+    if bill_date.len() != 8 || !bill_date.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid("expected yyyyMMdd, got"));
+    }
+    let tm = time::strptime(bill_date, "%Y%m%d").map_err(|_| invalid("invalid date"))?;
+    // strptime 不检查日期是否存在 (如 20140231)
+    let tm = time::at_utc(tm.to_timespec());
+    if format_bill_date(&tm) != bill_date {
+        return Err(invalid("invalid date"));
+    }
+    Ok(tm)
+}
+
+pub(crate) fn format_bill_date(tm: &time::Tm) -> String {
+    format!(
+        "{}{:02}{:02}",
+        format_year(tm),
+        i64::from(tm.tm_mon) + 1,
+        tm.tm_mday
+    )
+}
+
+/// 北京时间的当天日期
+pub(crate) fn beijing_today() -> String {
+    format_bill_date(&(time::now_utc() + time::Duration::hours(8)))
+}
+
+/// 检查对账单日期: 格式为 `yyyyMMdd`, 且早于 `today` (当天的对账单次日才生成)
+pub(crate) fn check_bill_date(bill_date: &str, today: &str) -> Result<(), WechatpayError> {
+    parse_bill_date(bill_date)?;
+    if bill_date >= today {
+        return Err(WechatpayError::InvalidField {
+            field: "bill_date".to_string(),
+            reason: format!("bill for {} is not ready before {}", bill_date, today),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        check_bill_date, group_by_device, parse_bill, parse_yuan, reconcile_rows, AmountMismatch,
+    };
+    use WechatpayError;
+
+    const SAMPLE_BILL: &str = "\
+交易时间,公众账号ID,商户号,子商户号,设备号,微信订单号,商户订单号,用户标识,交易类型,交易状态,付款银行,货币种类,总金额,代金券或立减优惠金额,商品名称,商户数据包,手续费,费率
+`2014-11-10 16:33:45,`wx2421b1c4370ec43b,`10000100,`0,`1000,`1001690740201411100005734289,`1415640626,`085e9858e3ba5186aafcbaed1,`MICROPAY,`SUCCESS,`OTHERS,`CNY,`0.01,`0.0,`被扫支付测试,`订单额外描述,`0,`0.60%
+`2014-11-10 16:46:14,`wx2421b1c4370ec43b,`10000100,`0,`1000,`1002780740201411100005729794,`1415635270,`085e9858e90ca40c0b5aee463,`MICROPAY,`SUCCESS,`OTHERS,`CNY,`1.00,`0.0,`被扫支付测试,`订单额外描述,`0.01,`0.60%
+`2014-11-10 16:47:33,`wx2421b1c4370ec43b,`10000100,`0,`1001,`1002780740201411100005729795,`1415635271,`085e9858e90ca40c0b5aee463,`NATIVE,`SUCCESS,`OTHERS,`CNY,`12.5,`0.0,`扫码支付测试,`,`0.08,`0.60%
+总交易单数,总交易额,总退款金额,总代金券或立减优惠退款金额,手续费总金额
+`3,`13.51,`0.0,`0.0,`0.09
+";
+
+    #[test]
+    fn test_parse_bill() {
+        let rows = parse_bill(SAMPLE_BILL).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].trade_time, "2014-11-10 16:33:45");
+        assert_eq!(rows[0].transaction_id, "1001690740201411100005734289");
+        assert_eq!(rows[0].out_trade_no, "1415640626");
+        assert_eq!(rows[0].trade_type, "MICROPAY");
+        assert_eq!(rows[0].trade_state, "SUCCESS");
+        assert_eq!(rows[0].total_fee, 1);
+        assert_eq!(rows[1].total_fee, 100);
+        assert_eq!(rows[2].total_fee, 1250);
+        assert!(parse_bill("").unwrap().is_empty());
+
+        let bill = SAMPLE_BILL.replace("`1.00,", "`1.0.0,");
+        match parse_bill(&bill) {
+            Err(WechatpayError::InvalidField { field, reason }) => {
+                assert_eq!(field, "total_fee");
+                assert!(reason.contains("1415635270"), "{}", reason);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_group_by_device() {
+        let rows = parse_bill(SAMPLE_BILL).unwrap();
+        let groups = group_by_device(rows);
+        assert_eq!(groups.len(), 2);
+        let outs = |device: &str| {
+            groups[device]
+                .iter()
+                .map(|row| row.out_trade_no.as_str())
+                .collect::<Vec<&str>>()
+        };
+        assert_eq!(outs("1000"), vec!["1415640626", "1415635270"]);
+        assert_eq!(outs("1001"), vec!["1415635271"]);
+    }
+
+    #[test]
+    fn test_parse_yuan() {
+        assert_eq!(parse_yuan("0.01"), Some(1));
+        assert_eq!(parse_yuan("12.5"), Some(1250));
+        assert_eq!(parse_yuan("3"), Some(300));
+        assert_eq!(parse_yuan("1.001"), None);
+        assert_eq!(parse_yuan("abc"), None);
+    }
+
+    #[test]
+    fn test_reconcile_rows() {
+        let rows = parse_bill(SAMPLE_BILL).unwrap();
+        let mut local = HashMap::new();
+        local.insert("1415640626".to_string(), 1);
+        local.insert("1415635270".to_string(), 99);
+        local.insert("1415000000".to_string(), 500);
+
+        let report = reconcile_rows(&rows, &local);
+        assert_eq!(report.only_remote, vec!["1415635271".to_string()]);
+        assert_eq!(report.only_local, vec!["1415000000".to_string()]);
+        assert_eq!(
+            report.amount_mismatches,
+            vec![AmountMismatch {
+                out_trade_no: "1415635270".to_string(),
+                remote_fee: 100,
+                local_fee: 99,
+            }]
+        );
+        assert!(!report.is_consistent());
+
+        local.remove("1415000000");
+        local.insert("1415635270".to_string(), 100);
+        local.insert("1415635271".to_string(), 1250);
+        assert!(reconcile_rows(&rows, &local).is_consistent());
+
+        // 同一订单出现多次时按金额之和对比, 并单独列出
+        let mut rows = rows;
+        let mut extra = rows[1].clone();
+        extra.total_fee = 50;
+        rows.push(extra);
+        let report = reconcile_rows(&rows, &local);
+        assert_eq!(report.duplicates, vec!["1415635270".to_string()]);
+        assert_eq!(
+            report.amount_mismatches,
+            vec![AmountMismatch {
+                out_trade_no: "1415635270".to_string(),
+                remote_fee: 150,
+                local_fee: 100,
+            }]
+        );
+        local.insert("1415635270".to_string(), 150);
+        let report = reconcile_rows(&rows, &local);
+        assert!(report.amount_mismatches.is_empty());
+        assert!(!report.is_consistent());
+    }
+
+    #[test]
+    fn test_check_bill_date() {
+        assert!(check_bill_date("20140603", "20140604").is_ok());
+        for &date in &[
+            "20140604",
+            "20140605",
+            "2014063",
+            "2014-06-03",
+            "20140231",
+            "20141301",
+            "２0140603",
+        ] {
+            match check_bill_date(date, "20140604") {
+                Err(WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "bill_date"),
+                result => panic!("expected {} to be rejected, got {:?}", date, result),
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! 商户证书检查
+//!
+//! 微信支付商户证书 (`apiclient_cert.pem`) 主题中的 CN 为商户号, 这里只解析出 CN,
+//! 用于在启动时发现加载了其他商户证书的配置错误。
+
+use std::fs;
+
+use {WechatpayClient, WechatpayError};
+
+impl WechatpayClient {
+    /// 创建 client 并检查商户证书, 见 `verify_cert`
+    pub fn new_checked(
+        appid: &str,
+        mch_id: &str,
+        api_key: &str,
+        notify_url: &str,
+        cert: &str,
+    ) -> Result<WechatpayClient, WechatpayError> {
+        let client = WechatpayClient::new(appid, mch_id, api_key, notify_url, cert);
+        client.verify_cert()?;
+        Ok(client)
+    }
+
+    /// 检查商户证书主题中的 CN 与 `mch_id` 一致, 不一致或证书无法读取时返回 `WechatpayError::Cert`
+    pub fn verify_cert(&self) -> Result<(), WechatpayError> {
+        let pem = fs::read_to_string(&self.cert)
+            .map_err(|e| WechatpayError::Cert(format!("{}: {}", self.cert, e)))?;
+        let common_name = subject_common_name(&pem)?;
+        if common_name != self.mch_id {
+            return Err(WechatpayError::Cert(format!(
+                "certificate CN {} does not match mch_id {}",
+                common_name, self.mch_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// 读取 PEM 格式证书主题 (subject) 中的 CN
+fn subject_common_name(pem: &str) -> Result<String, WechatpayError> {
+    let der =
+        pem_to_der(pem).ok_or_else(|| WechatpayError::Cert("not a PEM certificate".to_string()))?;
+    der_subject_common_name(&der)
+        .ok_or_else(|| WechatpayError::Cert("malformed certificate".to_string()))
+}
+
+/// 取出第一个 `CERTIFICATE` 块并做 base64 解码
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let begin = "-----BEGIN CERTIFICATE-----";
+    let start = pem.find(begin)? + begin.len();
+    let end = start + pem[start..].find("-----END CERTIFICATE-----")?;
+
+    let mut der = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in pem[start..end].bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            der.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(der)
+}
+
+/// 读取一个 DER 元素, 返回 (tag, 内容, 剩余数据)
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, 2 + n)
+    };
+    let content = data.get(header..header + len)?;
+    Some((tag, content, &data[header + len..]))
+}
+
+/// `Certificate -> TBSCertificate -> subject` 中 `id-at-commonName` (2.5.4.3) 的值
+fn der_subject_common_name(der: &[u8]) -> Option<String> {
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    const OID: u8 = 0x06;
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (SEQUENCE, certificate, _) = read_tlv(der)? else {
+        return None;
+    };
+    let (SEQUENCE, mut tbs, _) = read_tlv(certificate)? else {
+        return None;
+    };
+    // version (可选, [0]), serialNumber, signature, issuer, validity, subject
+    let mut fields = Vec::new();
+    while !tbs.is_empty() && fields.len() < 6 {
+        let (tag, content, rest) = read_tlv(tbs)?;
+        if !(fields.is_empty() && tag == 0xa0) {
+            fields.push((tag, content));
+        }
+        tbs = rest;
+    }
+    let &(SEQUENCE, mut subject) = fields.get(4)? else {
+        return None;
+    };
+    while !subject.is_empty() {
+        let (SET, mut names, rest) = read_tlv(subject)? else {
+            return None;
+        };
+        while !names.is_empty() {
+            let (SEQUENCE, name, next) = read_tlv(names)? else {
+                return None;
+            };
+            let (OID, oid, value) = read_tlv(name)? else {
+                return None;
+            };
+            if oid == COMMON_NAME {
+                let (_, value, _) = read_tlv(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+            names = next;
+        }
+        subject = rest;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::subject_common_name;
+    use {get_nonce_str, WechatpayClient, WechatpayError};
+
+    /// 由测试 CA (`CN=Tenpay.com Root CA`) 签发, 主题为 `CN=1900009191`
+    const MERCHANT_CERT: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIB2DCCAX+gAwIBAgIUbBlLPzDZA0H0nBtspj8X6UEPHOkwCgYIKoZIzj0EAwIw
+PzELMAkGA1UEBhMCQ04xEzARBgNVBAoMClRlbnBheS5jb20xGzAZBgNVBAMMElRl
+bnBheS5jb20gUm9vdCBDQTAeFw0yNjEwMTUwOTQxMDhaFw0zNjEwMTIwOTQxMDha
+MFYxCzAJBgNVBAYTAkNOMRMwEQYDVQQKDApUZW5wYXkuY29tMR0wGwYDVQQLDBRU
+ZW5wYXkuY29tIENBIENlbnRlcjETMBEGA1UEAwwKMTkwMDAwOTE5MTBZMBMGByqG
+SM49AgEGCCqGSM49AwEHA0IABK9XjsiC9QJfBs19E35jjE7on6bftvn0wnlmw0SU
+n0Jcm/eKcqW8FRvjI1LeGW4D0DaBfHQvcEYyOSF87LThgkSjQjBAMB0GA1UdDgQW
+BBRC7OQKOP8+21wdLL141xej1wMgsTAfBgNVHSMEGDAWgBRsWemz3ufo+NtI+gbb
+3cJyx/KTTDAKBggqhkjOPQQDAgNHADBEAiBYIMGSxV81diYEbG5gG74ryElVK2AA
+eOKreu7QKJudHAIgKmgCzM1sisCrrOo1CFzI2irhfd/pPXWBxOA3jj0c6dY=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_subject_common_name() {
+        assert_eq!(subject_common_name(MERCHANT_CERT).unwrap(), "1900009191");
+        assert!(subject_common_name("").is_err());
+        assert!(subject_common_name(
+            "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_cert() {
+        // 并行运行的测试不能共用同一个文件
+        let file = env::temp_dir().join(format!(
+            "wechatpay_test_apiclient_cert_{}_{}.pem",
+            process::id(),
+            get_nonce_str()
+        ));
+        fs::write(&file, MERCHANT_CERT).unwrap();
+        let path = file.to_str().unwrap();
+        let client = |mch_id: &str| {
+            WechatpayClient::new_checked(
+                "wxd930ea5d5a258f4f",
+                mch_id,
+                "192006250b4c09247ec02edce69f6a2d",
+                "https://example.com/notify",
+                path,
+            )
+        };
+        let matched = client("1900009191");
+        let mismatched = client("10000100");
+        fs::remove_file(&file).unwrap();
+
+        assert!(matched.is_ok());
+        match mismatched {
+            Err(WechatpayError::Cert(reason)) => assert!(reason.contains("1900009191")),
+            _ => panic!("expected a cert error"),
+        }
+
+        let missing = WechatpayClient::new(
+            "wxd930ea5d5a258f4f",
+            "1900009191",
+            "192006250b4c09247ec02edce69f6a2d",
+            "https://example.com/notify",
+            "/path/to/apiclient_cert.pem",
+        );
+        match missing.verify_cert() {
+            Err(WechatpayError::Cert(_)) => {}
+            _ => panic!("expected a cert error"),
+        }
+    }
+}
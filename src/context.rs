@@ -0,0 +1,67 @@
+//! 请求上下文
+//!
+//! 调用方可以为当前线程中发出的请求指定 `trace_id`, 出现在请求日志中,
+//! 用于关联应用自身的调用链路。
+
+use std::cell::RefCell;
+
+thread_local! {
+    static CURRENT: RefCell<RequestContext> = RefCell::new(RequestContext::default());
+}
+
+/// 请求上下文
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestContext {
+    /// 调用方的链路 ID
+    pub trace_id: Option<String>,
+}
+
+/// 离开 `scope` (包括 panic) 时恢复之前的上下文
+struct Restore(Option<RequestContext>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+}
+
+impl RequestContext {
+    /// 带有 `trace_id` 的上下文
+    pub fn with_trace_id(trace_id: &str) -> RequestContext {
+        RequestContext {
+            trace_id: Some(trace_id.to_string()),
+        }
+    }
+
+    /// 在 `f` 执行期间将 `self` 作为当前线程的请求上下文
+    pub fn scope<T, F: FnOnce() -> T>(self, f: F) -> T {
+        let previous = CURRENT.with(|current| current.replace(self));
+        let _restore = Restore(Some(previous));
+        f()
+    }
+
+    /// 当前线程的请求上下文
+    pub fn current() -> RequestContext {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestContext;
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(RequestContext::current().trace_id, None);
+        let trace_id = RequestContext::with_trace_id("outer").scope(|| {
+            RequestContext::with_trace_id("inner").scope(|| {
+                assert_eq!(RequestContext::current().trace_id.unwrap(), "inner");
+            });
+            RequestContext::current().trace_id
+        });
+        assert_eq!(trace_id.unwrap(), "outer");
+        assert_eq!(RequestContext::current(), RequestContext::default());
+    }
+}
@@ -0,0 +1,296 @@
+//! [单品优惠] `detail` 字段
+//!
+//! 单品优惠活动的 `detail` 字段是一个 JSON 字符串, 这里按文档中的格式生成。
+
+use std::collections::BTreeMap;
+
+use json::json_string;
+use WechatpayError;
+
+/// 单品优惠商品信息 (`goods_detail` 数组中的一项)
+#[derive(Clone, Debug, Default)]
+pub struct GoodsDetail {
+    /// 商品编码: 由半角的大小写字母、数字、中划线、下划线中的一种或几种组成
+    pub goods_id: String,
+    /// 微信支付定义的统一商品编号 (没有可不传), 用于跨商户的单品优惠
+    pub wxpay_goods_id: Option<String>,
+    /// 商品名称
+    pub goods_name: Option<String>,
+    /// 商品数量
+    pub quantity: u32,
+    /// 商品单价, 单位为【分】
+    pub price: u32,
+}
+
+/// 商品详细描述 (`detail` 字段)
+#[derive(Clone, Debug, Default)]
+pub struct Detail {
+    /// 订单原价, 单位为【分】
+    pub cost_price: Option<u32>,
+    /// 商品小票ID
+    pub receipt_id: Option<String>,
+    /// 单品信息
+    pub goods_detail: Vec<GoodsDetail>,
+}
+
+impl GoodsDetail {
+    fn to_json(&self) -> String {
+        let mut fields = vec![format!("\"goods_id\":{}", json_string(&self.goods_id))];
+        if let Some(ref wxpay_goods_id) = self.wxpay_goods_id {
+            fields.push(format!(
+                "\"wxpay_goods_id\":{}",
+                json_string(wxpay_goods_id)
+            ));
+        }
+        if let Some(ref goods_name) = self.goods_name {
+            fields.push(format!("\"goods_name\":{}", json_string(goods_name)));
+        }
+        fields.push(format!("\"quantity\":{}", self.quantity));
+        fields.push(format!("\"price\":{}", self.price));
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl Detail {
+    /// 生成 `detail` 字段的 JSON 字符串, 未设置的可选字段不输出
+    pub fn to_json(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(cost_price) = self.cost_price {
+            fields.push(format!("\"cost_price\":{}", cost_price));
+        }
+        if let Some(ref receipt_id) = self.receipt_id {
+            fields.push(format!("\"receipt_id\":{}", json_string(receipt_id)));
+        }
+        let goods = self
+            .goods_detail
+            .iter()
+            .map(|goods| goods.to_json())
+            .collect::<Vec<String>>();
+        fields.push(format!("\"goods_detail\":[{}]", goods.join(",")));
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// 订单中的一项商品
+#[derive(Clone, Debug, Default)]
+pub struct LineItem {
+    /// 商品编码
+    pub goods_id: String,
+    /// 商品名称
+    pub name: String,
+    /// 商品数量
+    pub quantity: u32,
+    /// 商品单价, 单位为【分】
+    pub price: u32,
+}
+
+/// 由多项商品生成下单参数: `out_trade_no`, `total_fee` (各项金额之和), `body` 和 `detail`
+///
+/// `body` 为各商品名称, 超过 128 字节时为 `第一项商品名称等N件商品`
+/// (第一项商品名称过长时按字符截断);
+/// 其他参数 (如 `spbill_create_ip`) 由调用方补充
+pub fn build_order_from_items(
+    items: &[LineItem],
+    out_trade_no: &str,
+) -> Result<BTreeMap<String, String>, WechatpayError> {
+    if items.is_empty() {
+        return Err(WechatpayError::InvalidField {
+            field: "detail".to_string(),
+            reason: "an order needs at least one item".to_string(),
+        });
+    }
+    let total_fee = items
+        .iter()
+        .try_fold(0u32, |total, item| {
+            item.price
+                .checked_mul(item.quantity)
+                .and_then(|amount| total.checked_add(amount))
+        })
+        .ok_or_else(|| WechatpayError::InvalidField {
+            field: "total_fee".to_string(),
+            reason: "sum of the items overflows".to_string(),
+        })?;
+
+    let mut body = items
+        .iter()
+        .map(|item| item.name.as_str())
+        .collect::<Vec<&str>>()
+        .join("、");
+    if body.len() > 128 {
+        let suffix = format!("等{}件商品", items.len());
+        let name = &items[0].name;
+        let mut end = name.len().min(128 - suffix.len());
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        body = format!("{}{}", &name[..end], suffix);
+    }
+    let detail = Detail {
+        cost_price: Some(total_fee),
+        receipt_id: None,
+        goods_detail: items
+            .iter()
+            .map(|item| GoodsDetail {
+                goods_id: item.goods_id.clone(),
+                wxpay_goods_id: None,
+                goods_name: Some(item.name.clone()),
+                quantity: item.quantity,
+                price: item.price,
+            })
+            .collect(),
+    };
+
+    let mut params = BTreeMap::new();
+    params.insert("out_trade_no".to_string(), out_trade_no.to_string());
+    params.insert("total_fee".to_string(), total_fee.to_string());
+    params.insert("body".to_string(), body);
+    params.insert("detail".to_string(), detail.to_json());
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_order_from_items, Detail, GoodsDetail, LineItem};
+
+    #[test]
+    fn test_detail_to_json() {
+        // 文档示例 (去掉空白)
+        let expected = r#"
+{
+    "cost_price":608800,
+    "receipt_id":"wx123",
+    "goods_detail":[
+        {
+            "goods_id":"商品编码",
+            "goods_name":"",
+            "quantity":1,
+            "price":528800
+        },
+        {
+            "goods_id":"商品编码",
+            "goods_name":"iPhone6s 32G",
+            "quantity":1,
+            "price":608800
+        }
+    ]
+}
+"#
+        .lines()
+        .map(|line| line.trim())
+        .collect::<String>();
+
+        let detail = Detail {
+            cost_price: Some(608800),
+            receipt_id: Some("wx123".to_string()),
+            goods_detail: vec![
+                GoodsDetail {
+                    goods_id: "商品编码".to_string(),
+                    wxpay_goods_id: None,
+                    goods_name: Some("".to_string()),
+                    quantity: 1,
+                    price: 528800,
+                },
+                GoodsDetail {
+                    goods_id: "商品编码".to_string(),
+                    wxpay_goods_id: None,
+                    goods_name: Some("iPhone6s 32G".to_string()),
+                    quantity: 1,
+                    price: 608800,
+                },
+            ],
+        };
+        assert_eq!(detail.to_json(), expected);
+
+        let detail = Detail {
+            goods_detail: vec![GoodsDetail {
+                goods_id: "1001".to_string(),
+                quantity: 2,
+                price: 100,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            detail.to_json(),
+            r#"{"goods_detail":[{"goods_id":"1001","quantity":2,"price":100}]}"#
+        );
+    }
+
+    #[test]
+    fn test_wxpay_goods_id() {
+        let detail = Detail {
+            goods_detail: vec![GoodsDetail {
+                goods_id: "1001".to_string(),
+                wxpay_goods_id: Some("1001".to_string()),
+                goods_name: Some("iPhone6s 16G".to_string()),
+                quantity: 1,
+                price: 528800,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            detail.to_json(),
+            r#"{"goods_detail":[{"goods_id":"1001","wxpay_goods_id":"1001","goods_name":"iPhone6s 16G","quantity":1,"price":528800}]}"#
+        );
+
+        let detail = Detail {
+            goods_detail: vec![GoodsDetail {
+                goods_id: "商品编码".to_string(),
+                wxpay_goods_id: Some("WX0001".to_string()),
+                quantity: 1,
+                price: 100,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let json = detail.to_json();
+        assert!(json.contains(r#""goods_id":"商品编码""#));
+        assert!(json.contains(r#""wxpay_goods_id":"WX0001""#));
+    }
+
+    #[test]
+    fn test_build_order_from_items() {
+        let item = |goods_id: &str, name: &str, quantity: u32, price: u32| LineItem {
+            goods_id: goods_id.to_string(),
+            name: name.to_string(),
+            quantity,
+            price,
+        };
+        let items = vec![
+            item("1001", "咖啡", 2, 1500),
+            item("1002", "蛋糕", 1, 2800),
+            item("1003", "矿泉水", 3, 200),
+        ];
+        let params = build_order_from_items(&items, "1415659990").unwrap();
+        assert_eq!(params["out_trade_no"], "1415659990");
+        assert_eq!(params["total_fee"], "6400");
+        assert_eq!(params["body"], "咖啡、蛋糕、矿泉水");
+        assert_eq!(
+            params["detail"],
+            r#"{"cost_price":6400,"goods_detail":[{"goods_id":"1001","goods_name":"咖啡","quantity":2,"price":1500},{"goods_id":"1002","goods_name":"蛋糕","quantity":1,"price":2800},{"goods_id":"1003","goods_name":"矿泉水","quantity":3,"price":200}]}"#
+        );
+
+        let many = vec![
+            item("1001", &"咖啡".repeat(30), 1, 1),
+            item("1002", "蛋糕", 1, 1),
+        ];
+        let params = build_order_from_items(&many, "1415659990").unwrap();
+        assert_eq!(params["body"], format!("{}等2件商品", "咖啡".repeat(19)));
+
+        // 第一项商品名称过长时按字符截断, body 不超过 128 字节
+        let long = vec![
+            item("1001", &format!("ab{}", "咖啡".repeat(40)), 1, 1),
+            item("1002", "蛋糕", 1, 1),
+        ];
+        let params = build_order_from_items(&long, "1415659990").unwrap();
+        assert!(params["body"].len() <= 128);
+        assert_eq!(
+            params["body"],
+            format!("ab{}咖等2件商品", "咖啡".repeat(18))
+        );
+
+        assert!(build_order_from_items(&[], "1415659990").is_err());
+        let overflow = vec![item("1001", "咖啡", 2, u32::MAX / 2 + 1)];
+        assert!(build_order_from_items(&overflow, "1415659990").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! 接口域名
+//!
+//! 境外商户使用香港、美国等接入点, 接口路径与境内相同, 只有域名不同。
+
+/// 境内接入点
+const DEFAULT_BASE: &str = "https://api.mch.weixin.qq.com";
+
+/// 接口使用的接入点, 默认为境内的 `https://api.mch.weixin.qq.com`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoints {
+    base: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Endpoints {
+        Endpoints::new(DEFAULT_BASE)
+    }
+}
+
+impl Endpoints {
+    /// 使用自定义的接入点, 如 `https://api2.mch.weixin.qq.com` (备用域名) 或测试环境的地址
+    pub fn new(base: &str) -> Endpoints {
+        Endpoints {
+            base: base.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// 香港接入点 `https://apihk.mch.weixin.qq.com`
+    pub fn hong_kong() -> Endpoints {
+        Endpoints::new("https://apihk.mch.weixin.qq.com")
+    }
+
+    /// 美国接入点 `https://apius.mch.weixin.qq.com`
+    pub fn us() -> Endpoints {
+        Endpoints::new("https://apius.mch.weixin.qq.com")
+    }
+
+    /// 接入点的地址, 不以 `/` 结尾
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// 将境内接口地址 `url` 改写为当前接入点下的地址, 其他地址不变
+    pub fn url(&self, url: &str) -> String {
+        match url.strip_prefix(DEFAULT_BASE) {
+            Some(path) if path.starts_with('/') => format!("{}{}", self.base, path),
+            _ => url.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Endpoints;
+
+    #[test]
+    fn test_endpoints_url() {
+        let refund = "https://api.mch.weixin.qq.com/secapi/pay/refund";
+        assert_eq!(Endpoints::default().url(refund), refund);
+        assert_eq!(
+            Endpoints::hong_kong().url(refund),
+            "https://apihk.mch.weixin.qq.com/secapi/pay/refund"
+        );
+        assert_eq!(
+            Endpoints::us().url("https://api.mch.weixin.qq.com/pay/orderquery"),
+            "https://apius.mch.weixin.qq.com/pay/orderquery"
+        );
+        assert_eq!(
+            Endpoints::new("http://127.0.0.1:8080/").url(refund),
+            "http://127.0.0.1:8080/secapi/pay/refund"
+        );
+        // 不是境内接口地址时不改写
+        let other = "https://api.mch.weixin.qq.com.example.com/pay/orderquery";
+        assert_eq!(Endpoints::hong_kong().url(other), other);
+    }
+}
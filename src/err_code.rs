@@ -0,0 +1,215 @@
+//! [错误码]
+//!
+//! `result_code` 为 `FAIL` 时网关返回的 `err_code`, 各接口文档中列出的常见错误码在这里
+//! 有对应的变体, 其他错误码为 `ErrCode::Unknown`。
+
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+/// 业务错误码 (`err_code`)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ErrCode {
+    /// `SYSTEMERROR`: 系统超时或异常, 交易状态未知
+    SystemError,
+    /// `PARAM_ERROR`: 参数错误
+    ParamError,
+    /// `ORDERPAID`: 订单已支付
+    OrderPaid,
+    /// `ORDERCLOSED`: 订单已关闭
+    OrderClosed,
+    /// `ORDERREVERSED`: 订单已撤销
+    OrderReversed,
+    /// `ORDERNOTEXIST`: 订单不存在
+    OrderNotExist,
+    /// `OUT_TRADE_NO_USED`: 商户订单号重复
+    OutTradeNoUsed,
+    /// `NOAUTH`: 商户无此接口权限
+    NoAuth,
+    /// `NOTENOUGH`: 余额不足
+    NotEnough,
+    /// `NOTSUPORTCARD`: 不支持卡类型
+    NotSupportCard,
+    /// `AUTHCODEEXPIRE`: 二维码已过期
+    AuthCodeExpire,
+    /// `AUTH_CODE_ERROR`: 授权码参数错误
+    AuthCodeError,
+    /// `AUTH_CODE_INVALID`: 授权码检验错误
+    AuthCodeInvalid,
+    /// `BANKERROR`: 银行系统异常
+    BankError,
+    /// `USERPAYING`: 用户支付中, 需要用户输入密码
+    UserPaying,
+    /// `PAYERROR`: 支付失败
+    PayError,
+    /// `BUYER_MISMATCH`: 支付帐号错误
+    BuyerMismatch,
+    /// `APPID_NOT_EXIST`: APPID 不存在
+    AppidNotExist,
+    /// `MCHID_NOT_EXIST`: MCHID 不存在
+    MchidNotExist,
+    /// `APPID_MCHID_NOT_MATCH`: appid 和 mch_id 不匹配
+    AppidMchidNotMatch,
+    /// `SIGNERROR`: 签名错误
+    SignError,
+    /// `LACK_PARAMS`: 缺少参数
+    LackParams,
+    /// `XML_FORMAT_ERROR`: XML 格式错误
+    XmlFormatError,
+    /// `REQUIRE_POST_METHOD`: 请使用 post 方法
+    RequirePostMethod,
+    /// `NOTUTF8`: 编码格式错误
+    NotUtf8,
+    /// `INVALID_REQUEST`: 无效请求
+    InvalidRequest,
+    /// `TRADE_ERROR`: 交易错误
+    TradeError,
+    /// `FREQUENCY_LIMITED`: 频率限制
+    FrequencyLimited,
+    /// 其他错误码
+    Unknown(String),
+}
+
+const CODES: &[(&str, ErrCode)] = &[
+    ("SYSTEMERROR", ErrCode::SystemError),
+    ("PARAM_ERROR", ErrCode::ParamError),
+    ("ORDERPAID", ErrCode::OrderPaid),
+    ("ORDERCLOSED", ErrCode::OrderClosed),
+    ("ORDERREVERSED", ErrCode::OrderReversed),
+    ("ORDERNOTEXIST", ErrCode::OrderNotExist),
+    ("OUT_TRADE_NO_USED", ErrCode::OutTradeNoUsed),
+    ("NOAUTH", ErrCode::NoAuth),
+    ("NOTENOUGH", ErrCode::NotEnough),
+    ("NOTSUPORTCARD", ErrCode::NotSupportCard),
+    ("AUTHCODEEXPIRE", ErrCode::AuthCodeExpire),
+    ("AUTH_CODE_ERROR", ErrCode::AuthCodeError),
+    ("AUTH_CODE_INVALID", ErrCode::AuthCodeInvalid),
+    ("BANKERROR", ErrCode::BankError),
+    ("USERPAYING", ErrCode::UserPaying),
+    ("PAYERROR", ErrCode::PayError),
+    ("BUYER_MISMATCH", ErrCode::BuyerMismatch),
+    ("APPID_NOT_EXIST", ErrCode::AppidNotExist),
+    ("MCHID_NOT_EXIST", ErrCode::MchidNotExist),
+    ("APPID_MCHID_NOT_MATCH", ErrCode::AppidMchidNotMatch),
+    ("SIGNERROR", ErrCode::SignError),
+    ("LACK_PARAMS", ErrCode::LackParams),
+    ("XML_FORMAT_ERROR", ErrCode::XmlFormatError),
+    ("REQUIRE_POST_METHOD", ErrCode::RequirePostMethod),
+    ("NOTUTF8", ErrCode::NotUtf8),
+    ("INVALID_REQUEST", ErrCode::InvalidRequest),
+    ("TRADE_ERROR", ErrCode::TradeError),
+    ("FREQUENCY_LIMITED", ErrCode::FrequencyLimited),
+];
+
+impl ErrCode {
+    /// 解析网关返回的 `err_code`
+    pub fn from_response(s: &str) -> ErrCode {
+        CODES
+            .iter()
+            .find(|&&(code, _)| code == s)
+            .map(|(_, err_code)| err_code.clone())
+            .unwrap_or_else(|| ErrCode::Unknown(s.to_string()))
+    }
+
+    /// 网关返回的错误码
+    pub fn as_str(&self) -> &str {
+        match *self {
+            ErrCode::Unknown(ref code) => code,
+            ref err_code => CODES
+                .iter()
+                .find(|(_, known)| known == err_code)
+                .map(|&(code, _)| code)
+                .unwrap_or(""),
+        }
+    }
+
+    /// 可以用相同参数重新发起请求, 如 `BANKERROR`
+    pub fn is_retryable(&self) -> bool {
+        matches!(*self, ErrCode::BankError | ErrCode::FrequencyLimited)
+    }
+
+    /// 交易状态未知, 需要调用查询接口确认, 如 `SYSTEMERROR`, `USERPAYING`
+    pub fn is_unknown_state(&self) -> bool {
+        matches!(*self, ErrCode::SystemError | ErrCode::UserPaying)
+    }
+
+    /// 终态错误, 重试没有意义, 如 `ORDERPAID`, `OUT_TRADE_NO_USED`
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            *self,
+            ErrCode::OrderPaid
+                | ErrCode::OrderClosed
+                | ErrCode::OrderReversed
+                | ErrCode::OutTradeNoUsed
+                | ErrCode::NoAuth
+                | ErrCode::NotEnough
+                | ErrCode::NotSupportCard
+                | ErrCode::AuthCodeExpire
+                | ErrCode::AuthCodeInvalid
+                | ErrCode::BuyerMismatch
+                | ErrCode::AppidNotExist
+                | ErrCode::MchidNotExist
+                | ErrCode::AppidMchidNotMatch
+                | ErrCode::ParamError
+                | ErrCode::SignError
+                | ErrCode::LackParams
+                | ErrCode::XmlFormatError
+                | ErrCode::InvalidRequest
+                | ErrCode::TradeError
+        )
+    }
+}
+
+impl FromStr for ErrCode {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<ErrCode, Infallible> {
+        Ok(ErrCode::from_response(s))
+    }
+}
+
+impl fmt::Display for ErrCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl PartialEq<str> for ErrCode {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for ErrCode {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrCode;
+
+    #[test]
+    fn test_err_code() {
+        assert_eq!(ErrCode::from_response("ORDERPAID"), ErrCode::OrderPaid);
+        assert_eq!(ErrCode::from_response("SYSTEMERROR"), ErrCode::SystemError);
+        assert_eq!(
+            "NOTSUPORTCARD".parse::<ErrCode>().unwrap(),
+            ErrCode::NotSupportCard
+        );
+        assert_eq!(
+            ErrCode::from_response("FREQUENCY_LIMITED"),
+            ErrCode::FrequencyLimited
+        );
+        let unknown = ErrCode::from_response("SOMETHING_NEW");
+        assert_eq!(unknown, ErrCode::Unknown("SOMETHING_NEW".to_string()));
+        assert_eq!(unknown.to_string(), "SOMETHING_NEW");
+        assert_eq!(ErrCode::UserPaying.as_str(), "USERPAYING");
+        assert_eq!(ErrCode::NotEnough, "NOTENOUGH");
+        assert!(ErrCode::BankError.is_retryable());
+        assert!(ErrCode::UserPaying.is_unknown_state());
+        assert!(ErrCode::OutTradeNoUsed.is_terminal());
+        assert!(!unknown.is_terminal());
+    }
+}
@@ -0,0 +1,192 @@
+//! 可以持久化的错误记录
+//!
+//! `WechatpayError` 中的 `curl::Error` 等类型无法序列化, 失败的请求需要保存到数据库或
+//! 死信队列时先转换为 `ErrorRecord`。启用 `serde` feature 后 `ErrorRecord` 实现
+//! `Serialize`/`Deserialize`, 以 `kind` 字段区分错误类型。
+
+use WechatpayError;
+
+/// `WechatpayError` 的可序列化表示, 各字段与 `WechatpayError` 相同
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind"))]
+pub enum ErrorRecord {
+    MissingField {
+        operation: String,
+        field: String,
+    },
+    RedundantField {
+        operation: String,
+        field: String,
+    },
+    InvalidField {
+        field: String,
+        reason: String,
+    },
+    Protocol {
+        return_msg: String,
+    },
+    Business {
+        err_code: String,
+        err_code_des: String,
+    },
+    SignatureMismatch,
+    AmountMismatch {
+        sent: String,
+        received: String,
+    },
+    /// `code` 为 curl 的错误码 (`CURLcode`), `message` 为错误描述
+    Curl {
+        code: u32,
+        message: String,
+    },
+    Encoding {
+        label: String,
+    },
+    ResponseTooLarge {
+        limit: usize,
+    },
+    Cert {
+        reason: String,
+    },
+    IncompleteResponse,
+    Request,
+    Unknown,
+}
+
+impl<'a> From<&'a WechatpayError> for ErrorRecord {
+    fn from(err: &'a WechatpayError) -> ErrorRecord {
+        match *err {
+            WechatpayError::MissingField {
+                operation,
+                ref field,
+            } => ErrorRecord::MissingField {
+                operation: operation.to_string(),
+                field: field.clone(),
+            },
+            WechatpayError::RedundantField {
+                operation,
+                ref field,
+            } => ErrorRecord::RedundantField {
+                operation: operation.to_string(),
+                field: field.clone(),
+            },
+            WechatpayError::InvalidField {
+                ref field,
+                ref reason,
+            } => ErrorRecord::InvalidField {
+                field: field.clone(),
+                reason: reason.clone(),
+            },
+            WechatpayError::Protocol(ref return_msg) => ErrorRecord::Protocol {
+                return_msg: return_msg.clone(),
+            },
+            WechatpayError::Business {
+                ref err_code,
+                ref err_code_des,
+            } => ErrorRecord::Business {
+                err_code: err_code.to_string(),
+                err_code_des: err_code_des.clone(),
+            },
+            WechatpayError::SignatureMismatch => ErrorRecord::SignatureMismatch,
+            WechatpayError::AmountMismatch {
+                ref sent,
+                ref received,
+            } => ErrorRecord::AmountMismatch {
+                sent: sent.clone(),
+                received: received.clone(),
+            },
+            WechatpayError::Curl(ref e) => ErrorRecord::Curl {
+                code: e.code(),
+                message: e.to_string(),
+            },
+            WechatpayError::Encoding(ref label) => ErrorRecord::Encoding {
+                label: label.clone(),
+            },
+            WechatpayError::ResponseTooLarge { limit } => ErrorRecord::ResponseTooLarge { limit },
+            WechatpayError::Cert(ref reason) => ErrorRecord::Cert {
+                reason: reason.clone(),
+            },
+            WechatpayError::IncompleteResponse => ErrorRecord::IncompleteResponse,
+            WechatpayError::Request => ErrorRecord::Request,
+            WechatpayError::Unknown => ErrorRecord::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use curl;
+
+    use super::ErrorRecord;
+    use {ErrCode, WechatpayError};
+
+    fn errors() -> Vec<WechatpayError> {
+        vec![
+            WechatpayError::MissingField {
+                operation: "refund",
+                field: "refund_fee".to_string(),
+            },
+            WechatpayError::RedundantField {
+                operation: "unifiedorder",
+                field: "sign".to_string(),
+            },
+            WechatpayError::InvalidField {
+                field: "out_trade_no".to_string(),
+                reason: "length 33 is not within 1 to 32".to_string(),
+            },
+            WechatpayError::Protocol("签名错误".to_string()),
+            WechatpayError::Business {
+                err_code: ErrCode::NotEnough,
+                err_code_des: "余额不足".to_string(),
+            },
+            WechatpayError::SignatureMismatch,
+            WechatpayError::AmountMismatch {
+                sent: "1".to_string(),
+                received: "100".to_string(),
+            },
+            WechatpayError::Curl(curl::Error::new(7)),
+            WechatpayError::Encoding("gbk".to_string()),
+            WechatpayError::ResponseTooLarge { limit: 1024 },
+            WechatpayError::Cert("certificate CN 1900009191 does not match".to_string()),
+            WechatpayError::IncompleteResponse,
+            WechatpayError::Request,
+            WechatpayError::Unknown,
+        ]
+    }
+
+    #[test]
+    fn test_error_record() {
+        match ErrorRecord::from(&WechatpayError::Curl(curl::Error::new(7))) {
+            ErrorRecord::Curl { code, message } => {
+                assert_eq!(code, 7);
+                assert!(!message.is_empty());
+            }
+            record => panic!("unexpected record {:?}", record),
+        }
+        assert_eq!(
+            ErrorRecord::from(&errors()[0]),
+            ErrorRecord::MissingField {
+                operation: "refund".to_string(),
+                field: "refund_fee".to_string(),
+            }
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_error_record_json() {
+        extern crate serde_json;
+
+        for err in &errors() {
+            let record = ErrorRecord::from(err);
+            let json = serde_json::to_string(&record).unwrap();
+            assert_eq!(serde_json::from_str::<ErrorRecord>(&json).unwrap(), record);
+        }
+        let json = serde_json::to_string(&ErrorRecord::from(&errors()[4])).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"Business","err_code":"NOTENOUGH","err_code_des":"余额不足"}"#
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! 请求参数中 JSON 字段的生成和校验
+
+/// 生成带引号并转义的 JSON 字符串
+pub(crate) fn json_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+/// 检查是否为合法的 JSON 对象 (只检查语法)
+pub(crate) fn is_json_object(value: &str) -> bool {
+    let mut parser = Parser::new(value);
+    parser.skip_whitespace();
+    if parser.peek() != Some(b'{') || !parser.value() {
+        return false;
+    }
+    parser.skip_whitespace();
+    parser.pos == parser.bytes.len()
+}
+
+/// JSON 对象第一层的字段名 (未转义的原始内容), 不是合法的 JSON 对象时返回 `None`
+pub(crate) fn object_keys(value: &str) -> Option<Vec<&str>> {
+    if !is_json_object(value) {
+        return None;
+    }
+    let mut parser = Parser::new(value);
+    let mut keys = Vec::new();
+    parser.skip_whitespace();
+    parser.eat(b'{');
+    loop {
+        parser.skip_whitespace();
+        if parser.eat(b'}') {
+            return Some(keys);
+        }
+        let start = parser.pos;
+        parser.string();
+        keys.push(&value[start + 1..parser.pos - 1]);
+        parser.skip_whitespace();
+        parser.eat(b':');
+        parser.value();
+        parser.skip_whitespace();
+        parser.eat(b',');
+    }
+}
+
+/// 对象和数组最多嵌套的层数, 超过时视为不合法, 避免递归解析时栈溢出
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(value: &'a str) -> Parser<'a> {
+        Parser {
+            bytes: value.as_bytes(),
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> bool {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.sequence(b'{', b'}', true),
+            Some(b'[') => self.sequence(b'[', b']', false),
+            Some(b'"') => self.string(),
+            Some(b't') => self.literal(b"true"),
+            Some(b'f') => self.literal(b"false"),
+            Some(b'n') => self.literal(b"null"),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => false,
+        }
+    }
+
+    /// 对象或数组, `keyed` 为 `true` 时每一项为 `"key": value`
+    fn sequence(&mut self, open: u8, close: u8, keyed: bool) -> bool {
+        if self.depth == MAX_DEPTH {
+            return false;
+        }
+        self.depth += 1;
+        let valid = self.items(open, close, keyed);
+        self.depth -= 1;
+        valid
+    }
+
+    fn items(&mut self, open: u8, close: u8, keyed: bool) -> bool {
+        self.eat(open);
+        self.skip_whitespace();
+        if self.eat(close) {
+            return true;
+        }
+        loop {
+            self.skip_whitespace();
+            if keyed {
+                if !self.string() {
+                    return false;
+                }
+                self.skip_whitespace();
+                if !self.eat(b':') {
+                    return false;
+                }
+            }
+            if !self.value() {
+                return false;
+            }
+            self.skip_whitespace();
+            if self.eat(close) {
+                return true;
+            }
+            if !self.eat(b',') {
+                return false;
+            }
+        }
+    }
+
+    fn string(&mut self) -> bool {
+        if !self.eat(b'"') {
+            return false;
+        }
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            match b {
+                b'"' => return true,
+                b'\\' => match self.peek() {
+                    Some(b'"') | Some(b'\\') | Some(b'/') | Some(b'b') | Some(b'f')
+                    | Some(b'n') | Some(b'r') | Some(b't') => self.pos += 1,
+                    Some(b'u') => {
+                        let hex = self.bytes.get(self.pos + 1..self.pos + 5);
+                        match hex {
+                            Some(hex) if hex.iter().all(|b| b.is_ascii_hexdigit()) => self.pos += 5,
+                            _ => return false,
+                        }
+                    }
+                    _ => return false,
+                },
+                0x00..=0x1f => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn literal(&mut self, literal: &[u8]) -> bool {
+        if self.bytes[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> bool {
+        let start = self.pos;
+        self.eat(b'-');
+        let digits = |parser: &mut Parser| {
+            let start = parser.pos;
+            while let Some(b'0'..=b'9') = parser.peek() {
+                parser.pos += 1;
+            }
+            parser.pos > start
+        };
+        if !digits(self) {
+            return false;
+        }
+        if self.eat(b'.') && !digits(self) {
+            return false;
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if !digits(self) {
+                return false;
+            }
+        }
+        self.pos > start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_json_object, json_string, object_keys, MAX_DEPTH};
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\n""#);
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+    }
+
+    #[test]
+    fn test_object_keys() {
+        assert_eq!(
+            object_keys(r#" { "h5_info": {"type": "Wap"}, "a\"b": [1, {"c": 2}] } "#),
+            Some(vec!["h5_info", r#"a\"b"#])
+        );
+        assert_eq!(object_keys("{}"), Some(vec![]));
+        assert_eq!(object_keys(r#"{"a":1,}"#), None);
+    }
+
+    #[test]
+    fn test_is_json_object() {
+        for value in [
+            r#"{}"#,
+            r#"{"store_info":{"id":"SZTX001","name":"腾大餐厅","area_code":"440305"}}"#,
+            r#" {"a": [1, -2.5e3, true, false, null, "x\"é"]} "#,
+        ]
+        .iter()
+        {
+            assert!(is_json_object(value), "{}", value);
+        }
+        for value in [
+            "",
+            "[]",
+            r#""string""#,
+            r#"{"a":}"#,
+            r#"{"a":1,}"#,
+            r#"{a:1}"#,
+            r#"{"a":1} trailing"#,
+            r#"{"a":"\x"}"#,
+            r#"{"a":01.}"#,
+        ]
+        .iter()
+        {
+            assert!(!is_json_object(value), "{}", value);
+        }
+
+        // 嵌套层数有上限, 不会栈溢出
+        let nested = |depth: usize| {
+            format!(
+                "{{\"a\":{}1{}}}",
+                "[".repeat(depth - 1),
+                "]".repeat(depth - 1)
+            )
+        };
+        assert!(is_json_object(&nested(MAX_DEPTH)));
+        assert!(!is_json_object(&nested(MAX_DEPTH + 1)));
+        assert!(!is_json_object(&format!(
+            "{{\"a\":{}",
+            "[".repeat(1_000_000)
+        )));
+    }
+}
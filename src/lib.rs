@@ -1,59 +1,479 @@
 extern crate curl;
+extern crate encoding_rs;
+extern crate hmac;
+#[macro_use]
+extern crate log;
 extern crate md5;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+extern crate sha2;
 extern crate time;
 extern crate url;
 extern crate uuid;
 extern crate xml;
 
-use std::collections::BTreeMap;
-use std::io::{Read, Write};
-use std::string::ToString;
+mod audit;
+mod bill;
+mod cert;
+mod context;
+mod detail;
+mod endpoints;
+mod err_code;
+mod error_record;
+mod json;
+mod metrics;
+mod money;
+mod nonce;
+mod pending;
+mod preview;
+mod response;
+mod scene;
+mod signer;
 
-use curl::easy::Easy;
-use time::strftime;
-use url::form_urlencoded;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use curl::easy::{Easy2, Handler, List, ReadError, WriteError};
 use uuid::Uuid;
 use xml::writer::events;
 
+pub use audit::{AuditSink, NoopAuditSink};
+pub use bill::{
+    group_by_device, parse_bill, reconcile_rows, AmountMismatch, BillRow, BillType, ReconcileReport,
+};
+pub use context::RequestContext;
+pub use detail::{build_order_from_items, Detail, GoodsDetail, LineItem};
+pub use endpoints::Endpoints;
+pub use err_code::ErrCode;
+pub use error_record::ErrorRecord;
+pub use metrics::{Metrics, NoopMetrics};
+pub use money::Money;
+pub use nonce::{NonceGenerator, RandomNonce, SeededNonce};
+pub use pending::{MemoryPendingStore, PendingStore};
+pub use preview::OrderPreview;
+pub use response::{
+    HbInfo, MicropayOutcome, OrderQueryResult, PaymentNotification, RedPackInfo, RefundCoupon,
+    RefundNotification, RefundResponse, RefundStatus, TradeState, UnifiedOrderResponse,
+};
+pub use scene::{H5Info, StoreInfo};
+pub use signer::SigningKeyProvider;
+
 /// 货币种类: 人民币
-const _CURRENCY_CNY: &str = "CNY";
+const CURRENCY_CNY: &str = "CNY";
 /// 统一下单 URL
 const UNIFIEDORDER_URL: &str = "https://api.mch.weixin.qq.com/pay/unifiedorder";
 const MICROPAY_URL: &str = "https://api.mch.weixin.qq.com/pay/micropay";
 /// 查询订单 URL
 const ORDERQUERY_URL: &str = "https://api.mch.weixin.qq.com/pay/orderquery";
+/// 申请退款 URL
+const REFUND_URL: &str = "https://api.mch.weixin.qq.com/secapi/pay/refund";
+/// 完结分账 URL
+const PROFITSHARINGFINISH_URL: &str =
+    "https://api.mch.weixin.qq.com/secapi/pay/profitsharingfinish";
+/// 添加分账接收方 URL
+const PROFITSHARINGADDRECEIVER_URL: &str =
+    "https://api.mch.weixin.qq.com/pay/profitsharingaddreceiver";
+/// 删除分账接收方 URL
+const PROFITSHARINGREMOVERECEIVER_URL: &str =
+    "https://api.mch.weixin.qq.com/pay/profitsharingremovereceiver";
+/// 查询退款 URL
+const REFUNDQUERY_URL: &str = "https://api.mch.weixin.qq.com/pay/refundquery";
+/// 撤销订单 URL
+const REVERSE_URL: &str = "https://api.mch.weixin.qq.com/secapi/pay/reverse";
+/// 关闭订单 URL
+const CLOSEORDER_URL: &str = "https://api.mch.weixin.qq.com/pay/closeorder";
+/// 查询代金券批次 URL
+const QUERY_COUPON_STOCK_URL: &str =
+    "https://api.mch.weixin.qq.com/mmpaymkttransfers/query_coupon_stock";
+/// 查询代金券信息 URL
+const QUERY_COUPON_URL: &str = "https://api.mch.weixin.qq.com/promotion/query_coupon";
+/// 发放代金券 URL
+const SEND_COUPON_URL: &str = "https://api.mch.weixin.qq.com/mmpaymkttransfers/send_coupon";
+/// 发放裂变红包 URL
+const SENDGROUPREDPACK_URL: &str =
+    "https://api.mch.weixin.qq.com/mmpaymkttransfers/sendgroupredpack";
+/// 查询红包记录 URL
+const GETHBINFO_URL: &str = "https://api.mch.weixin.qq.com/mmpaymkttransfers/gethbinfo";
+/// 下载对账单 URL
+const DOWNLOADBILL_URL: &str = "https://api.mch.weixin.qq.com/pay/downloadbill";
+/// 只支持 MD5 签名的接口 (代金券、红包), 请求中不传 `sign_type`
+const MD5_ONLY_URLS: &[&str] = &[
+    QUERY_COUPON_STOCK_URL,
+    QUERY_COUPON_URL,
+    SEND_COUPON_URL,
+    SENDGROUPREDPACK_URL,
+    GETHBINFO_URL,
+];
+/// 返回结果中不带 `sign` 的接口 (红包)
+const UNSIGNED_URLS: &[&str] = &[SENDGROUPREDPACK_URL, GETHBINFO_URL];
 
-impl ToString for TradeType {
-    fn to_string(&self) -> String {
-        (match *self {
+impl fmt::Display for TradeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
             TradeType::Micro => "MICRO",
             TradeType::Jsapi => "JSAPI",
             TradeType::Native | TradeType::Qrcode => "NATIVE",
             TradeType::App => "APP",
+            TradeType::Mweb => "MWEB",
+        })
+    }
+}
+
+impl TradeType {
+    /// 解析网关返回的 `trade_type`
+    ///
+    /// 网关不区分 `Native` 和 `Qrcode`, `NATIVE` 总是解析为 `Native`;
+    /// 查询订单时刷卡支付返回的是 `MICROPAY`
+    pub fn from_response(s: &str) -> Option<TradeType> {
+        match s {
+            "MICRO" | "MICROPAY" => Some(TradeType::Micro),
+            "JSAPI" => Some(TradeType::Jsapi),
+            "NATIVE" => Some(TradeType::Native),
+            "APP" => Some(TradeType::App),
+            "MWEB" => Some(TradeType::Mweb),
+            _ => None,
+        }
+    }
+}
+
+/// [银行类型] (`bank_type`), 如 `CCB_DEBIT`, `ICBC_CREDIT`; 零钱等非银行卡支付为 `OTHERS`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BankType {
+    code: String,
+}
+
+/// 付款方式的分类, 由 `bank_type` 的后缀得出
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaymentMethodCategory {
+    /// `_DEBIT`: 借记卡
+    Debit,
+    /// `_CREDIT`: 信用卡
+    Credit,
+    /// 零钱、零钱通等其他方式
+    Other,
+}
+
+impl BankType {
+    /// 解析网关返回的 `bank_type`
+    pub fn from_response(s: &str) -> BankType {
+        BankType {
+            code: s.to_string(),
+        }
+    }
+
+    /// 网关返回的银行类型代码
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// 借记卡或信用卡
+    pub fn payment_method_category(&self) -> PaymentMethodCategory {
+        if self.code.ends_with("_DEBIT") {
+            PaymentMethodCategory::Debit
+        } else if self.code.ends_with("_CREDIT") {
+            PaymentMethodCategory::Credit
+        } else {
+            PaymentMethodCategory::Other
+        }
+    }
+}
+
+impl fmt::Display for BankType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.code)
+    }
+}
+
+/// 签名类型 (`sign_type`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignType {
+    /// `MD5`, 默认
+    Md5,
+    /// `HMAC-SHA256`
+    HmacSha256,
+}
+
+impl fmt::Display for SignType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SignType::Md5 => "MD5",
+            SignType::HmacSha256 => "HMAC-SHA256",
         })
-        .to_string()
     }
 }
 
-/// 银行类型
-pub enum BankType {}
+impl SignType {
+    /// 根据请求参数中的 `sign_type` 选择签名类型, 没有时为 `Md5`
+    fn from_params(pairs: &BTreeMap<String, String>) -> SignType {
+        match pairs.get("sign_type").map(|s| s.as_str()) {
+            Some("HMAC-SHA256") => SignType::HmacSha256,
+            _ => SignType::Md5,
+        }
+    }
+}
+
+/// 请求中 `sign` 的大小写, 默认为文档规定的大写
+///
+/// 校验响应签名时不区分大小写, 与此设置无关
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignCasing {
+    Upper,
+    Lower,
+}
+
+impl SignCasing {
+    fn apply(self, sign: String) -> String {
+        match self {
+            SignCasing::Upper => sign,
+            SignCasing::Lower => sign.to_ascii_lowercase(),
+        }
+    }
+}
+
+/// 参数中出现 xml 不允许的控制字符 (除 `\t`, `\n`, `\r` 外的 0x00-0x1F) 时的处理方式,
+/// 默认为 `Reject`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlCharPolicy {
+    /// 返回 `WechatpayError::InvalidField`
+    Reject,
+    /// 删除这些字符后再签名
+    Strip,
+}
+
+impl ControlCharPolicy {
+    fn apply(
+        self,
+        params: BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, WechatpayError> {
+        let mut params = params;
+        for (key, value) in params.iter_mut() {
+            if !value.contains(is_xml_control_char) {
+                continue;
+            }
+            match self {
+                ControlCharPolicy::Reject => {
+                    return Err(WechatpayError::InvalidField {
+                        field: key.clone(),
+                        reason: "contains control characters not allowed in xml".to_string(),
+                    });
+                }
+                ControlCharPolicy::Strip => value.retain(|c| !is_xml_control_char(c)),
+            }
+        }
+        Ok(params)
+    }
+}
+
+fn is_xml_control_char(c: char) -> bool {
+    c < ' ' && c != '\t' && c != '\n' && c != '\r'
+}
 
+#[derive(Clone, Copy)]
 enum ParamsCheckType {
     Required,
     Forbidden,
 }
 
 /// 错误类
+#[derive(Debug)]
 pub enum WechatpayError {
-    /// 缺少字段
-    MissingField(String),
+    /// 缺少字段, `operation` 为出错的接口 (如 `unifiedorder`, `refund`)
+    MissingField {
+        operation: &'static str,
+        field: String,
+    },
     /// 多余的字段
-    RedundantField(String),
+    RedundantField {
+        operation: &'static str,
+        field: String,
+    },
+    /// 字段的值不合法
+    InvalidField {
+        field: String,
+        reason: String,
+    },
+    /// 通信错误: `return_code` 为 `FAIL`, 内容为 `return_msg` (如签名错误、参数格式错误)
+    Protocol(String),
+    /// 业务错误: `result_code` 为 `FAIL`
+    Business {
+        err_code: ErrCode,
+        err_code_des: String,
+    },
+    /// 响应数据的签名校验失败
+    SignatureMismatch,
+    /// 响应中返回的 `total_fee` 与请求中的不一致
+    AmountMismatch {
+        sent: String,
+        received: String,
+    },
     Curl(curl::Error),
+    /// 无法按声明的编码解码响应数据
+    Encoding(String),
+    /// 响应数据超过了 `limit` 字节
+    ResponseTooLarge {
+        limit: usize,
+    },
+    /// 商户证书无法读取, 或与 `mch_id` 不匹配
+    Cert(String),
+    /// 响应的 xml 数据不完整 (缺少根元素的结束标签), 连接可能在传输中断开
+    IncompleteResponse,
     Request,
     Unknown,
 }
 
+impl WechatpayError {
+    /// 网络错误: 请求没有到达网关或没有收到正常的响应, 可以重试
+    pub fn is_network(&self) -> bool {
+        matches!(
+            *self,
+            WechatpayError::Curl(_) | WechatpayError::IncompleteResponse | WechatpayError::Request
+        )
+    }
+
+    /// 瞬时的 curl 错误 (连接失败、DNS 解析失败、收发数据出错) 和不完整的响应,
+    /// 请求会在重试次数内自动重试
+    ///
+    /// 证书错误等其他 curl 错误重试也不会成功, 不在此列
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            WechatpayError::IncompleteResponse => true,
+            WechatpayError::Curl(ref e) => {
+                e.is_couldnt_connect()
+                    || e.is_couldnt_resolve_host()
+                    || e.is_couldnt_resolve_proxy()
+                    || e.is_send_error()
+                    || e.is_recv_error()
+            }
+            _ => false,
+        }
+    }
+
+    /// 业务错误: 网关正常处理了请求, 但 `result_code` 为 `FAIL`,
+    /// 或返回的 `total_fee` 与请求中的不一致 (需要人工核对订单)
+    pub fn is_business(&self) -> bool {
+        matches!(
+            *self,
+            WechatpayError::Business { .. } | WechatpayError::AmountMismatch { .. }
+        )
+    }
+
+    /// 参数错误: 请求没有发出, 需要修改参数
+    pub fn is_validation(&self) -> bool {
+        matches!(
+            *self,
+            WechatpayError::MissingField { .. }
+                | WechatpayError::RedundantField { .. }
+                | WechatpayError::InvalidField { .. }
+        )
+    }
+
+    /// 签名校验错误: 响应数据可能被篡改或 API 密钥不一致
+    pub fn is_crypto(&self) -> bool {
+        matches!(*self, WechatpayError::SignatureMismatch)
+    }
+
+    /// 可以用相同参数重新发起请求的业务错误
+    ///
+    /// 例如 `BANKERROR`: 银行系统异常, 可以用原参数重试
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            WechatpayError::Business { ref err_code, .. } => err_code.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// 交易状态未知, 需要调用查询接口确认
+    ///
+    /// 例如 `SYSTEMERROR`, `USERPAYING`
+    pub fn is_unknown_state(&self) -> bool {
+        match *self {
+            WechatpayError::Business { ref err_code, .. } => err_code.is_unknown_state(),
+            _ => false,
+        }
+    }
+
+    /// 终态错误, 重试没有意义
+    ///
+    /// 例如 `ORDERPAID`, `OUT_TRADE_NO_USED`
+    pub fn is_terminal(&self) -> bool {
+        match *self {
+            WechatpayError::Business { ref err_code, .. } => err_code.is_terminal(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for WechatpayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WechatpayError::MissingField {
+                operation,
+                ref field,
+            } => write!(f, "{}: missing field {}", operation, field),
+            WechatpayError::RedundantField {
+                operation,
+                ref field,
+            } => write!(f, "{}: redundant field {}", operation, field),
+            WechatpayError::InvalidField {
+                ref field,
+                ref reason,
+            } => write!(f, "invalid field {}: {}", field, reason),
+            WechatpayError::Protocol(ref return_msg) => {
+                write!(f, "return_code FAIL: {}", return_msg)
+            }
+            WechatpayError::Business {
+                ref err_code,
+                ref err_code_des,
+            } => write!(f, "result_code FAIL: {} {}", err_code, err_code_des),
+            WechatpayError::SignatureMismatch => f.write_str("response signature mismatch"),
+            WechatpayError::AmountMismatch {
+                ref sent,
+                ref received,
+            } => write!(
+                f,
+                "total_fee mismatch: sent {}, received {}",
+                sent, received
+            ),
+            // 包括 curl 错误缓冲区 (`CURLOPT_ERRORBUFFER`) 中的详细原因, 如证书校验失败的具体原因
+            WechatpayError::Curl(ref e) => match e.extra_description() {
+                Some(detail) => write!(
+                    f,
+                    "curl error [{}] {}: {}",
+                    e.code(),
+                    e.description(),
+                    detail
+                ),
+                None => write!(f, "curl error [{}] {}", e.code(), e.description()),
+            },
+            WechatpayError::Encoding(ref label) => write!(f, "cannot decode response as {}", label),
+            WechatpayError::ResponseTooLarge { limit } => {
+                write!(f, "response exceeds {} bytes", limit)
+            }
+            WechatpayError::Cert(ref reason) => write!(f, "merchant certificate: {}", reason),
+            WechatpayError::IncompleteResponse => f.write_str("response xml is truncated"),
+            WechatpayError::Request => f.write_str("request failed"),
+            WechatpayError::Unknown => f.write_str("unknown error"),
+        }
+    }
+}
+
+impl error::Error for WechatpayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            WechatpayError::Curl(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<curl::Error> for WechatpayError {
     fn from(err: curl::Error) -> WechatpayError {
         WechatpayError::Curl(err)
@@ -61,23 +481,197 @@ impl From<curl::Error> for WechatpayError {
 }
 
 /// 订单标识
+#[derive(Clone, Debug)]
 pub enum OrderIdentifier {
     TransactionId(String),
     OutTradeNo(String),
 }
 
+impl OrderIdentifier {
+    /// 微信订单号, 去掉首尾空白后应为 28 位数字
+    pub fn transaction_id(s: &str) -> Result<OrderIdentifier, WechatpayError> {
+        OrderIdentifier::TransactionId(s.to_string()).normalize()
+    }
+
+    /// 商户订单号, 去掉首尾空白后应为 32 个字符以内的字母、数字或 `_-|*`
+    pub fn out_trade_no(s: &str) -> Result<OrderIdentifier, WechatpayError> {
+        OrderIdentifier::OutTradeNo(s.to_string()).normalize()
+    }
+
+    /// 去掉首尾空白并检查长度和字符, 不合法时返回 `WechatpayError::InvalidField`
+    pub fn normalize(self) -> Result<OrderIdentifier, WechatpayError> {
+        let invalid = |field: &str, reason: String| WechatpayError::InvalidField {
+            field: field.to_string(),
+            reason,
+        };
+        match self {
+            OrderIdentifier::TransactionId(s) => {
+                let s = s.trim();
+                if s.len() != 28 || !s.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid(
+                        "transaction_id",
+                        format!("{:?} is not a 28-digit transaction id", s),
+                    ));
+                }
+                Ok(OrderIdentifier::TransactionId(s.to_string()))
+            }
+            OrderIdentifier::OutTradeNo(s) => {
+                let s = s.trim();
+                if s.is_empty() || s.len() > 32 {
+                    return Err(invalid(
+                        "out_trade_no",
+                        format!("length {} is not within 1 to 32", s.len()),
+                    ));
+                }
+                if !s
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"_-|*".contains(&b))
+                {
+                    return Err(invalid(
+                        "out_trade_no",
+                        format!("{:?} contains characters other than [0-9A-Za-z_-|*]", s),
+                    ));
+                }
+                Ok(OrderIdentifier::OutTradeNo(s.to_string()))
+            }
+        }
+    }
+}
+
+/// 退款资金来源
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefundAccount {
+    /// `REFUND_SOURCE_UNSETTLED_FUNDS`: 未结算资金退款 (默认使用未结算资金退款)
+    UnsettledFunds,
+    /// `REFUND_SOURCE_RECHARGE_FUNDS`: 可用余额退款
+    RechargeFunds,
+}
+
+impl fmt::Display for RefundAccount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RefundAccount::UnsettledFunds => "REFUND_SOURCE_UNSETTLED_FUNDS",
+            RefundAccount::RechargeFunds => "REFUND_SOURCE_RECHARGE_FUNDS",
+        })
+    }
+}
+
 /// API 请求结果
 pub type WechatpayResult = Result<BTreeMap<String, String>, WechatpayError>;
 
+/// HTTP 传输层, 默认使用 curl
+pub trait Transport: Send + Sync {
+    /// 以 POST 方式发送 `body` 到 `url`, 返回 HTTP 状态码和响应内容
+    ///
+    /// `cert` 为需要使用的商户证书路径
+    fn post(
+        &self,
+        url: &str,
+        body: &str,
+        cert: Option<&str>,
+    ) -> Result<(u32, Vec<u8>), WechatpayError>;
+}
+
+/// `with_connection_reuse` 时每个 client 最多保留的空闲 curl 句柄数
+const MAX_IDLE_HANDLES: usize = 4;
+
+/// curl 回调: 发送请求数据并收集响应数据
+struct Collector {
+    body: Vec<u8>,
+    offset: usize,
+    data: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl Collector {
+    /// 响应数据超过 `limit` 字节时中止传输
+    fn new(body: Vec<u8>, limit: usize) -> Collector {
+        Collector {
+            body,
+            offset: 0,
+            data: Vec::new(),
+            limit,
+            exceeded: false,
+        }
+    }
+
+    fn take_data(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.data)
+    }
+}
+
+impl Handler for Collector {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let n = (&self.body[self.offset..]).read(buf).unwrap_or(0);
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        if self.data.len() + data.len() > self.limit {
+            // 返回的长度与 `data` 不一致时 curl 中止传输
+            self.exceeded = true;
+            return Ok(0);
+        }
+        self.data.extend_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+/// 本库使用的 curl 回调, 只用于 `WechatpayClient::with_curl_configure` 的参数类型,
+/// 内容不公开
+pub struct CurlHandler(Collector);
+
+impl Handler for CurlHandler {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        self.0.read(buf)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        self.0.write(data)
+    }
+}
+
+/// 进程内的全局 client, 由 `WechatpayClient::init_global` 设置
+static GLOBAL_CLIENT: OnceLock<WechatpayClient> = OnceLock::new();
+
 /// API Client
 pub struct WechatpayClient {
     appid: String,
     mch_id: String,
-    api_key: String,
+    signer: Box<dyn SigningKeyProvider>,
     notify_url: String,
     cert: String, // unused
+    transport: Option<Box<dyn Transport>>,
+    resolve: Vec<String>,
+    force_cert: Option<bool>,
+    spbill_create_ip: Option<String>,
+    pending: Option<Box<dyn PendingStore>>,
+    low_speed_limit: u32,
+    low_speed_time: Duration,
+    max_response_bytes: usize,
+    max_bill_bytes: usize,
+    sub_mch_id: Option<String>,
+    sub_appid: Option<String>,
+    sign_type: SignType,
+    explicit_sign_type: bool,
+    sign_casing: SignCasing,
+    control_chars: ControlCharPolicy,
+    require_https_notify: bool,
+    curl_configure: Option<Box<CurlConfigure>>,
+    auto_out_trade_no: bool,
+    metrics: Box<dyn Metrics>,
+    endpoints: Endpoints,
+    nonce: Box<dyn NonceGenerator>,
+    reuse_connections: bool,
+    idle_handles: Mutex<Vec<Easy2<CurlHandler>>>,
+    audit: Box<dyn AuditSink>,
 }
 
+/// 自定义 curl 选项的回调, 见 `WechatpayClient::with_curl_configure`
+pub type CurlConfigure = dyn Fn(&mut Easy2<CurlHandler>) -> Result<(), curl::Error> + Send + Sync;
+
 impl WechatpayClient {
     pub fn new(
         appid: &str,
@@ -89,17 +683,335 @@ impl WechatpayClient {
         WechatpayClient {
             appid: appid.to_string(),
             mch_id: mch_id.to_string(),
-            api_key: api_key.to_string(),
+            signer: Box::new(api_key.to_string()),
             notify_url: notify_url.to_string(),
             cert: cert.to_string(),
+            transport: None,
+            resolve: Vec::new(),
+            force_cert: None,
+            spbill_create_ip: None,
+            pending: None,
+            low_speed_limit: 100,
+            low_speed_time: Duration::from_secs(30),
+            max_response_bytes: 4 * 1024 * 1024,
+            max_bill_bytes: 256 * 1024 * 1024,
+            sub_mch_id: None,
+            sub_appid: None,
+            sign_type: SignType::Md5,
+            explicit_sign_type: false,
+            sign_casing: SignCasing::Upper,
+            control_chars: ControlCharPolicy::Reject,
+            require_https_notify: false,
+            curl_configure: None,
+            auto_out_trade_no: false,
+            metrics: Box::new(NoopMetrics),
+            endpoints: Endpoints::default(),
+            nonce: Box::new(RandomNonce),
+            reuse_connections: false,
+            idle_handles: Mutex::new(Vec::new()),
+            audit: Box::new(NoopAuditSink),
+        }
+    }
+
+    /// 设置进程内的全局 client, 只能设置一次, 已经设置过时返回传入的 `client`
+    pub fn init_global(client: WechatpayClient) -> Result<(), Box<WechatpayClient>> {
+        GLOBAL_CLIENT.set(client).map_err(Box::new)
+    }
+
+    /// 获取全局 client
+    ///
+    /// # Panics
+    ///
+    /// 在 `init_global` 之前调用时 panic
+    pub fn global() -> &'static WechatpayClient {
+        GLOBAL_CLIENT
+            .get()
+            .expect("WechatpayClient::init_global must be called before global()")
+    }
+
+    /// 强制所有接口使用 (`true`) 或不使用 (`false`) 商户证书, 忽略接口本身的要求
+    ///
+    /// 用于在沙箱或测试环境中单独排查证书问题
+    pub fn with_force_cert(mut self, force_cert: bool) -> WechatpayClient {
+        self.force_cert = Some(force_cert);
+        self
+    }
+
+    /// 更换 API 密钥, 之后的请求使用新密钥签名
+    ///
+    /// 签名时才读取密钥, 其他配置保持不变
+    pub fn set_api_key(&mut self, new_key: &str) {
+        self.signer = Box::new(new_key.to_string());
+    }
+
+    /// 使用外部签名 (例如 HSM/KMS) 代替内存中的 API 密钥, 请求签名和响应验签都由 `provider` 计算
+    pub fn with_signing_key_provider<P: SigningKeyProvider + 'static>(
+        mut self,
+        provider: P,
+    ) -> WechatpayClient {
+        self.signer = Box::new(provider);
+        self
+    }
+
+    /// 服务商模式: 代子商户 `sub_mch_id` 下单, `sub_appid` 为子商户的公众账号
+    ///
+    /// 设置 `sub_appid` 后公众号支付需要 `sub_openid` 而不是 `openid`
+    pub fn with_sub_merchant(
+        mut self,
+        sub_mch_id: &str,
+        sub_appid: Option<&str>,
+    ) -> WechatpayClient {
+        self.sub_mch_id = Some(sub_mch_id.to_string());
+        self.sub_appid = sub_appid.map(|s| s.to_string());
+        self
+    }
+
+    /// 请求参数中没有 `sign_type` 时使用的签名类型, 默认为 `Md5`
+    ///
+    /// `secapi` 系列接口 (退款、撤销订单、分账) 始终默认使用 `HMAC-SHA256`, 不受此设置影响;
+    /// 需要其他签名类型时在请求参数中显式传入 `sign_type`
+    pub fn with_sign_type(mut self, sign_type: SignType) -> WechatpayClient {
+        self.sign_type = sign_type;
+        self
+    }
+
+    /// 使用 `MD5` 签名时也在请求中加入 `sign_type=MD5` (参与签名), 默认关闭
+    ///
+    /// 没有 `sign_type` 时微信支付按 `MD5` 处理, 但部分商户类型要求显式传入
+    pub fn with_explicit_sign_type(mut self, enabled: bool) -> WechatpayClient {
+        self.explicit_sign_type = enabled;
+        self
+    }
+
+    /// 请求中 `sign` 的大小写, 默认为大写; 只在下游代理要求小写时使用
+    pub fn with_sign_casing(mut self, casing: SignCasing) -> WechatpayClient {
+        self.sign_casing = casing;
+        self
+    }
+
+    /// 参数中出现 xml 不允许的控制字符时的处理方式, 默认返回错误
+    pub fn with_control_char_policy(mut self, policy: ControlCharPolicy) -> WechatpayClient {
+        self.control_chars = policy;
+        self
+    }
+
+    /// 要求 `notify_url` (client 的默认值和下单参数中指定的) 使用 `https`, 默认关闭
+    pub fn with_require_https_notify(mut self, enabled: bool) -> WechatpayClient {
+        self.require_https_notify = enabled;
+        self
+    }
+
+    /// 在多次请求之间复用 curl 句柄, 默认关闭
+    ///
+    /// 复用的句柄保留连接、TLS 会话和 DNS 缓存, 连续调用接口时不必每次都重新握手。
+    /// 句柄每次使用前都会重置选项; 最多保留 `MAX_IDLE_HANDLES` 个空闲句柄,
+    /// 请求失败的句柄不再复用
+    pub fn with_connection_reuse(mut self, enabled: bool) -> WechatpayClient {
+        self.reuse_connections = enabled;
+        self
+    }
+
+    /// 下单和退款前后的审计记录, 默认不记录
+    pub fn with_audit_sink<A: AuditSink + 'static>(mut self, audit: A) -> WechatpayClient {
+        self.audit = Box::new(audit);
+        self
+    }
+
+    /// 替换 `nonce_str` 的生成方式, 默认为随机 UUID
+    ///
+    /// 测试中使用 `SeededNonce` 可以得到完全相同的签名请求
+    pub fn with_nonce_generator<N: NonceGenerator + 'static>(
+        mut self,
+        nonce: N,
+    ) -> WechatpayClient {
+        self.nonce = Box::new(nonce);
+        self
+    }
+
+    /// 下单参数中没有 `out_trade_no` 时用 `get_order_no` 生成, 默认关闭
+    ///
+    /// 生成的订单号会补充到 `pay` 的返回结果中, 调用方需要保存以便之后查询或退款
+    pub fn with_auto_out_trade_no(mut self, enabled: bool) -> WechatpayClient {
+        self.auto_out_trade_no = enabled;
+        self
+    }
+
+    /// 下单时默认的 `spbill_create_ip` (商户服务器 IP), 只在参数中没有该字段时使用
+    pub fn with_spbill_create_ip(mut self, ip: IpAddr) -> WechatpayClient {
+        self.spbill_create_ip = Some(ip.to_string());
+        self
+    }
+
+    /// 检测本机访问接口时使用的出口 IP, 作为默认的 `spbill_create_ip` (见 `with_spbill_create_ip`)
+    ///
+    /// 对接入点地址 `connect` 一个 UDP socket 后读取本地地址, 不会发送数据;
+    /// 经过 NAT 时得到的是内网地址。接入点无法解析或没有可用的路由时返回 `WechatpayError::Request`
+    pub fn detect_server_ip(&mut self) -> Result<(), WechatpayError> {
+        let target = url::Url::parse(self.endpoints.base())
+            .ok()
+            .and_then(|url| {
+                let host = url.host_str()?.to_string();
+                let port = url.port_or_known_default()?;
+                (host.as_str(), port).to_socket_addrs().ok()?.next()
+            })
+            .ok_or(WechatpayError::Request)?;
+        self.detect_server_ip_via(target)
+    }
+
+    fn detect_server_ip_via(&mut self, target: SocketAddr) -> Result<(), WechatpayError> {
+        let bind: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let ip = UdpSocket::bind(bind)
+            .and_then(|socket| {
+                socket.connect(target)?;
+                socket.local_addr()
+            })
+            .map_err(|e| {
+                warn!("failed to detect server ip via {}: {}", target, e);
+                WechatpayError::Request
+            })?
+            .ip();
+        self.spbill_create_ip = Some(ip.to_string());
+        Ok(())
+    }
+
+    /// 保存网络中断时未能提交的刷卡支付, 之后由 `flush_pending` 重新提交; 默认不保存
+    pub fn with_pending_store<S: PendingStore + 'static>(mut self, store: S) -> WechatpayClient {
+        self.pending = Some(Box::new(store));
+        self
+    }
+
+    /// 传输速度低于 `limit` 字节/秒并持续 `time` 时中止请求
+    /// (curl 的 `low_speed_limit`/`low_speed_time` 选项), 默认为 100 字节/秒, 30 秒
+    pub fn with_low_speed(mut self, limit: u32, time: Duration) -> WechatpayClient {
+        self.low_speed_limit = limit;
+        self.low_speed_time = time;
+        self
+    }
+
+    /// 响应数据的大小上限, 超过时返回 `WechatpayError::ResponseTooLarge`
+    ///
+    /// `max_bytes` 用于普通接口, 默认 4 MiB; `max_bill_bytes` 用于下载对账单, 默认 256 MiB
+    pub fn with_max_response_bytes(
+        mut self,
+        max_bytes: usize,
+        max_bill_bytes: usize,
+    ) -> WechatpayClient {
+        self.max_response_bytes = max_bytes;
+        self.max_bill_bytes = max_bill_bytes;
+        self
+    }
+
+    /// 接口 `url` 的响应数据大小上限
+    fn response_limit(&self, url: &str) -> usize {
+        if metrics::operation_name(url) == metrics::operation_name(DOWNLOADBILL_URL) {
+            self.max_bill_bytes
+        } else {
+            self.max_response_bytes
         }
     }
 
+    /// 将 `host:port` 固定解析到 `ip`, 不再依赖 DNS (curl 的 `resolve` 选项)
+    ///
+    /// 可以多次调用以固定多个地址
+    pub fn with_resolve(mut self, host: &str, port: u16, ip: IpAddr) -> WechatpayClient {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        self.resolve.push(format!("{}:{}:{}", host, port, ip));
+        self
+    }
+
+    /// 设置本库没有提供的 curl 选项 (如 `interface`, `dns_servers`)
+    ///
+    /// 每次请求在设置好标准选项之后、发送之前调用 `configure`, 返回错误时请求失败;
+    /// 使用 `with_transport` 时不调用
+    pub fn with_curl_configure<F>(mut self, configure: F) -> WechatpayClient
+    where
+        F: Fn(&mut Easy2<CurlHandler>) -> Result<(), curl::Error> + Send + Sync + 'static,
+    {
+        self.curl_configure = Some(Box::new(configure));
+        self
+    }
+
+    /// 使用其他接入点, 如境外商户使用 `Endpoints::hong_kong()`
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> WechatpayClient {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// 接口调用的指标回调, 默认为 `NoopMetrics`
+    pub fn with_metrics<M: Metrics + 'static>(mut self, metrics: M) -> WechatpayClient {
+        self.metrics = Box::new(metrics);
+        self
+    }
+
+    /// 使用自定义的传输层代替 curl (例如在测试中模拟微信支付网关)
+    pub fn with_transport<T: Transport + 'static>(mut self, transport: T) -> WechatpayClient {
+        self.transport = Some(Box::new(transport));
+        self
+    }
+
+    /// 当前生效的配置, 每行一项 `name = value`, 用于排查配置问题
+    ///
+    /// API 密钥和证书路径不输出原值, 只显示为 `***`
+    pub fn describe(&self) -> String {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+        let lines = vec![
+            ("appid", self.appid.clone()),
+            ("mch_id", self.mch_id.clone()),
+            ("sub_appid", optional(&self.sub_appid)),
+            ("sub_mch_id", optional(&self.sub_mch_id)),
+            ("api_key", "***".to_string()),
+            ("cert", "***".to_string()),
+            ("endpoint", self.endpoints.base().to_string()),
+            ("notify_url", self.notify_url.clone()),
+            (
+                "require_https_notify",
+                self.require_https_notify.to_string(),
+            ),
+            ("sign_type", self.sign_type.to_string()),
+            ("explicit_sign_type", self.explicit_sign_type.to_string()),
+            ("sign_casing", format!("{:?}", self.sign_casing)),
+            ("control_chars", format!("{:?}", self.control_chars)),
+            ("spbill_create_ip", optional(&self.spbill_create_ip)),
+            ("auto_out_trade_no", self.auto_out_trade_no.to_string()),
+            (
+                "force_cert",
+                self.force_cert
+                    .map_or_else(|| "-".to_string(), |force| force.to_string()),
+            ),
+            ("resolve", self.resolve.join(",")),
+            (
+                "low_speed",
+                format!(
+                    "{} bytes/s for {}s",
+                    self.low_speed_limit,
+                    self.low_speed_time.as_secs()
+                ),
+            ),
+            ("max_response_bytes", self.max_response_bytes.to_string()),
+            ("max_bill_bytes", self.max_bill_bytes.to_string()),
+            ("reuse_connections", self.reuse_connections.to_string()),
+            ("custom_transport", self.transport.is_some().to_string()),
+            ("curl_configure", self.curl_configure.is_some().to_string()),
+        ];
+        lines
+            .into_iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect()
+    }
+
     fn check_params(
         &self,
         params: &BTreeMap<String, String>,
         keys: Vec<&str>,
         check_type: ParamsCheckType,
+        operation: &'static str,
     ) -> Result<(), WechatpayError> {
         for key in keys.iter() {
             match check_type {
@@ -109,16 +1021,18 @@ impl WechatpayClient {
                         .unwrap_or(&"".to_string())
                         .is_empty()
                     {
-                        return Err(WechatpayError::MissingField(key.to_string()));
+                        return Err(WechatpayError::MissingField {
+                            operation,
+                            field: key.to_string(),
+                        });
                     }
                 }
                 ParamsCheckType::Forbidden => {
-                    if params
-                        .get(&key.to_string())
-                        .unwrap_or(&"".to_string())
-                        .is_empty()
-                    {
-                        return Err(WechatpayError::RedundantField(key.to_string()));
+                    if params.contains_key(&key.to_string()) {
+                        return Err(WechatpayError::RedundantField {
+                            operation,
+                            field: key.to_string(),
+                        });
                     }
                 }
             }
@@ -133,101 +1047,582 @@ impl WechatpayClient {
         retries: Option<u32>,
         require_cert: bool,
     ) -> WechatpayResult {
-        let api_key = self.api_key.to_string();
-        let sign_str = get_sign(&params, &api_key);
-        let mut params = params;
-        params.insert("sign".to_string(), sign_str);
+        self.request_with(url, params, retries, require_cert, from_xml_bytes)
+    }
 
-        let xml_str = to_xml_str(&params);
-        let mut handle = Easy::new();
-        handle.url(url)?;
-        if require_cert {
-            handle.ssl_cert(&self.cert)?;
+    /// 和 `request` 相同, 但由 `parse` 将响应数据解析为字段 (如展开重复的元素)
+    fn request_with<F>(
+        &self,
+        url: &str,
+        params: BTreeMap<String, String>,
+        retries: Option<u32>,
+        require_cert: bool,
+        parse: F,
+    ) -> WechatpayResult
+    where
+        F: FnOnce(&[u8]) -> WechatpayResult,
+    {
+        let sign_type = self.request_sign_type(&params, url);
+        self.measure(url, || {
+            let data = self.request_raw(url, params, retries, require_cert)?;
+            let pairs = parse(&data)?;
+            check_return(&pairs)?;
+            check_response_sign(&pairs, &*self.signer, url, sign_type)?;
+            check_result(pairs)
+        })
+    }
+
+    /// 调用 `f` 并记录接口 `url` 的指标
+    fn measure<T, F>(&self, url: &str, f: F) -> Result<T, WechatpayError>
+    where
+        F: FnOnce() -> Result<T, WechatpayError>,
+    {
+        let operation = metrics::operation_name(url);
+        self.metrics.on_request_start(operation);
+        let start = Instant::now();
+        let result = f();
+        self.metrics
+            .on_request_end(operation, start.elapsed(), result.as_ref().map(|_| ()));
+        result
+    }
+
+    /// 请求 `url` 使用的签名类型, 网关返回的数据使用相同的类型签名
+    fn request_sign_type(&self, params: &BTreeMap<String, String>, url: &str) -> SignType {
+        if MD5_ONLY_URLS.contains(&url) {
+            SignType::Md5
+        } else if params.contains_key("sign_type") {
+            SignType::from_params(params)
+        } else {
+            self.sign_type
         }
-        handle.read_function(move |buf| Ok(xml_str.as_bytes().read(buf).unwrap_or(0)))?;
+    }
 
-        let mut err = WechatpayError::Request;
-        for _ in 0..retries.unwrap_or(1) {
-            let mut data = Vec::<u8>::new();
-            {
-                let mut handle = handle.transfer();
-                handle.write_function(|text| {
-                    Ok(match data.write_all(text) {
-                        Ok(_) => text.len(),
-                        Err(_) => 0,
-                    })
-                })?;
-                handle.perform()?;
-            }
-
-            let status_code = match handle.response_code() {
-                Ok(code) => code,
+    /// 签名: 没有 `sign_type` 时使用 client 的默认签名类型, 然后加入 `sign`
+    ///
+    /// `md5_only` 的接口 (见 `MD5_ONLY_URLS`) 总是使用 MD5, 不传 `sign_type`
+    fn sign_params(
+        &self,
+        params: BTreeMap<String, String>,
+        md5_only: bool,
+    ) -> Result<BTreeMap<String, String>, WechatpayError> {
+        let mut params = self.control_chars.apply(params)?;
+        check_sign_fields(&params)?;
+        let explicit = self.explicit_sign_type || self.sign_type != SignType::Md5;
+        if md5_only {
+            params.remove("sign_type");
+        } else if explicit && !params.contains_key("sign_type") {
+            params.insert("sign_type".to_string(), self.sign_type.to_string());
+        }
+        let sign_str = self
+            .signer
+            .sign(&sign_source(&params, &[]), SignType::from_params(&params));
+        params.insert("sign".to_string(), self.sign_casing.apply(sign_str));
+        Ok(params)
+    }
+
+    /// 签名并发送请求, 返回原始的响应内容
+    fn request_raw(
+        &self,
+        url: &str,
+        params: BTreeMap<String, String>,
+        retries: Option<u32>,
+        require_cert: bool,
+    ) -> Result<Vec<u8>, WechatpayError> {
+        let params = self.sign_params(params, MD5_ONLY_URLS.contains(&url))?;
+        let xml_str = to_xml_str(&params);
+        let cert = if self.force_cert.unwrap_or(require_cert) {
+            Some(self.cert.as_str())
+        } else {
+            None
+        };
+
+        let url = &self.endpoints.url(url);
+        let trace_id = RequestContext::current()
+            .trace_id
+            .unwrap_or_else(|| "-".to_string());
+        let attempts = retries.unwrap_or(1);
+        for attempt in 1..=attempts {
+            info!("trace_id={} POST {}", trace_id, url);
+            let response = match self.transport {
+                Some(ref transport) => transport.post(url, &xml_str, cert),
+                None => self.curl_post(url, &xml_str, cert),
+            };
+            let (status_code, data) = match response {
+                Ok(response) => response,
                 Err(e) => {
-                    err = WechatpayError::from(e);
-                    0
+                    warn!("trace_id={} POST {} failed: {:?}", trace_id, url, e);
+                    if e.is_transient() && attempt < attempts {
+                        continue;
+                    }
+                    return Err(e);
                 }
             };
+            debug!("trace_id={} POST {} status={}", trace_id, url, status_code);
+            let limit = self.response_limit(url);
+            if data.len() > limit {
+                return Err(WechatpayError::ResponseTooLarge { limit });
+            }
             if status_code == 200 || status_code == 201 {
-                let s = String::from_utf8(data).unwrap();
-                return Ok(from_xml_str(s.as_ref()));
+                if !is_truncated_xml(&data) {
+                    return Ok(data);
+                }
+                warn!("trace_id={} POST {} response is truncated", trace_id, url);
+                if attempt == attempts {
+                    return Err(WechatpayError::IncompleteResponse);
+                }
             }
         }
-        Err(err)
+        Err(WechatpayError::Request)
     }
 
-    // let retries = if retries == 0 { 3 } else { retries };
-    pub fn pay(
+    /// 使用 curl 发送请求, 返回 HTTP 状态码和响应内容
+    fn curl_post(
         &self,
-        params: BTreeMap<String, String>,
-        trade_type: TradeType,
+        url: &str,
+        body: &str,
+        cert: Option<&str>,
+    ) -> Result<(u32, Vec<u8>), WechatpayError> {
+        let limit = self.response_limit(url);
+        let collector = Collector::new(body.as_bytes().to_vec(), limit);
+        let idle = if self.reuse_connections {
+            self.idle_handles.lock().unwrap().pop()
+        } else {
+            None
+        };
+        let mut handle = match idle {
+            Some(mut handle) => {
+                handle.reset();
+                *handle.get_mut() = CurlHandler(collector);
+                handle
+            }
+            None => Easy2::new(CurlHandler(collector)),
+        };
+        handle.url(url)?;
+        if let Some(cert) = cert {
+            handle.ssl_cert(cert)?;
+        }
+        if !self.resolve.is_empty() {
+            let mut list = List::new();
+            for entry in &self.resolve {
+                list.append(entry)?;
+            }
+            handle.resolve(list)?;
+        }
+        handle.low_speed_limit(self.low_speed_limit)?;
+        handle.low_speed_time(self.low_speed_time)?;
+        handle.post(true)?;
+        handle.post_field_size(body.len() as u64)?;
+        if let Some(ref configure) = self.curl_configure {
+            configure(&mut handle)?;
+        }
+        if let Err(e) = handle.perform() {
+            if handle.get_ref().0.exceeded {
+                return Err(WechatpayError::ResponseTooLarge { limit });
+            }
+            return Err(e.into());
+        }
+
+        let status_code = handle.response_code()?;
+        let data = handle.get_mut().0.take_data();
+        if self.reuse_connections {
+            let mut idle = self.idle_handles.lock().unwrap();
+            if idle.len() < MAX_IDLE_HANDLES {
+                idle.push(handle);
+            }
+        }
+        Ok((status_code, data))
+    }
+
+    /// 调用尚未封装的接口
+    ///
+    /// 补全 `appid`, `mch_id`, `nonce_str` (`params` 中已有时不覆盖), 签名后发送,
+    /// 并像其他接口一样检查返回结果
+    pub fn call(
+        &self,
+        url: &str,
+        params: BTreeMap<String, String>,
+        require_cert: bool,
         retries: Option<u32>,
     ) -> WechatpayResult {
-        self.check_params(&params, vec!["key", "sign"], ParamsCheckType::Forbidden)?;
         self.check_params(
             &params,
-            vec!["body", "out_trade_no", "total_fee", "spbill_create_ip"],
-            ParamsCheckType::Required,
+            vec!["key", "sign"],
+            ParamsCheckType::Forbidden,
+            "call",
         )?;
-        match trade_type {
-            TradeType::Native => {
-                self.check_params(&params, vec!["product_id"], ParamsCheckType::Required)?;
-            }
-            TradeType::Jsapi => {
-                self.check_params(&params, vec!["openid"], ParamsCheckType::Required)?;
-            }
-            TradeType::Micro => {
-                self.check_params(&params, vec!["auth_code"], ParamsCheckType::Required)?;
+        let mut params = params;
+        params
+            .entry("appid".to_string())
+            .or_insert_with(|| self.appid.clone());
+        params
+            .entry("mch_id".to_string())
+            .or_insert_with(|| self.mch_id.clone());
+        params
+            .entry("nonce_str".to_string())
+            .or_insert_with(|| self.nonce.nonce_str());
+        self.request(url, params, retries, require_cert)
+    }
+
+    // let retries = if retries == 0 { 3 } else { retries };
+    pub fn pay(
+        &self,
+        params: BTreeMap<String, String>,
+        trade_type: TradeType,
+        retries: Option<u32>,
+    ) -> WechatpayResult {
+        let (url, _) = pay_endpoint(trade_type);
+        let (params, generated) = self.build_pay_params(params, trade_type)?;
+        let sent = params.get("total_fee").cloned().unwrap_or_default();
+        let out_trade_no = params.get("out_trade_no").cloned().unwrap_or_default();
+        let operation = metrics::operation_name(url);
+        let trace_id = RequestContext::current().trace_id;
+        self.audit
+            .record_intent(operation, &out_trade_no, &sent, trace_id.as_deref());
+        let result = self
+            .request(url, params, retries, false)
+            .and_then(|pairs| check_total_fee(&sent, &pairs).map(|_| pairs));
+        self.audit.record_outcome(
+            operation,
+            &out_trade_no,
+            result.as_ref().map(|_| ()),
+            trace_id.as_deref(),
+        );
+        let mut pairs = result?;
+        if generated {
+            pairs
+                .entry("out_trade_no".to_string())
+                .or_insert(out_trade_no);
+        }
+        Ok(pairs)
+    }
+
+    /// 检查并补全下单参数, 返回签名前的请求参数和是否生成了 `out_trade_no`
+    fn build_pay_params(
+        &self,
+        params: BTreeMap<String, String>,
+        trade_type: TradeType,
+    ) -> Result<(BTreeMap<String, String>, bool), WechatpayError> {
+        let mut params = params;
+        let generated = self.fill_pay_defaults(&mut params)?;
+        if let Some(e) = self
+            .pay_param_errors(&params, trade_type)
+            .into_iter()
+            .next()
+        {
+            return Err(e);
+        }
+        Ok((self.pay_params(params, trade_type)?, generated))
+    }
+
+    /// 下单参数的所有问题, 按 `pay` 检查的顺序排列; `pay` 返回第一个, `preview` 全部返回
+    pub(crate) fn pay_param_errors(
+        &self,
+        params: &BTreeMap<String, String>,
+        trade_type: TradeType,
+    ) -> Vec<WechatpayError> {
+        let (_, operation) = pay_endpoint(trade_type);
+        let check = |keys: Vec<&str>, check_type: ParamsCheckType| {
+            keys.into_iter()
+                .map(|key| self.check_params(params, vec![key], check_type, operation))
+                .collect::<Vec<_>>()
+        };
+        let mut results = check(vec!["key", "sign", "nonce_str"], ParamsCheckType::Forbidden);
+        results.push(self.check_client_fields(params, operation));
+        results.extend(check(
+            self.pay_required_fields(trade_type),
+            ParamsCheckType::Required,
+        ));
+        results.extend(vec![
+            check_byte_lengths(params),
+            check_openid_params(params),
+            check_profit_sharing_params(params),
+        ]);
+        if trade_type == TradeType::App {
+            results.push(check_app_params(params));
+        }
+        results.push(check_scene_info(params, trade_type));
+        if trade_type != TradeType::Micro {
+            let notify_url = match params.get("notify_url") {
+                Some(url) if !url.is_empty() => url,
+                _ => &self.notify_url,
+            };
+            results.push(check_notify_url(notify_url, self.require_https_notify));
+        }
+        results.into_iter().filter_map(Result::err).collect()
+    }
+
+    /// 参数中的商户字段 (`appid`, `mch_id` 等) 与 client 的设置不一致时返回
+    /// `WechatpayError::RedundantField`, 而不是静默地用 client 的值覆盖;
+    /// 与 client 相同的值允许出现
+    fn check_client_fields(
+        &self,
+        params: &BTreeMap<String, String>,
+        operation: &'static str,
+    ) -> Result<(), WechatpayError> {
+        let fields = [
+            ("appid", Some(&self.appid)),
+            ("mch_id", Some(&self.mch_id)),
+            ("sub_appid", self.sub_appid.as_ref()),
+            ("sub_mch_id", self.sub_mch_id.as_ref()),
+        ];
+        for &(field, value) in fields.iter() {
+            if let (Some(given), Some(value)) = (params.get(field), value) {
+                if given != value {
+                    return Err(WechatpayError::RedundantField {
+                        operation,
+                        field: field.to_string(),
+                    });
+                }
             }
+        }
+        Ok(())
+    }
+
+    /// 下单的必填参数 (不含由 client 补全的参数)
+    ///
+    /// 设置了子商户公众账号 `sub_appid` 时, 公众号支付使用子商户下的用户标识 `sub_openid`
+    fn pay_required_fields(&self, trade_type: TradeType) -> Vec<&'static str> {
+        let mut fields = vec!["body", "out_trade_no", "total_fee", "spbill_create_ip"];
+        match trade_type {
+            TradeType::Native => fields.push("product_id"),
+            TradeType::Jsapi if self.sub_appid.is_some() => fields.push("sub_openid"),
+            TradeType::Jsapi => fields.push("openid"),
+            TradeType::Micro => fields.push("auth_code"),
+            TradeType::Mweb => fields.push("scene_info"),
             _ => {}
         }
+        fields
+    }
 
-        let url = if trade_type == TradeType::Micro {
-            MICROPAY_URL
-        } else {
-            UNIFIEDORDER_URL
-        };
+    /// 补全调用方可以省略的下单参数 (`spbill_create_ip`, `out_trade_no`),
+    /// 返回是否生成了 `out_trade_no`
+    fn fill_pay_defaults(
+        &self,
+        params: &mut BTreeMap<String, String>,
+    ) -> Result<bool, WechatpayError> {
+        if let Some(ref ip) = self.spbill_create_ip {
+            params
+                .entry("spbill_create_ip".to_string())
+                .or_insert_with(|| ip.clone());
+        }
+        let missing = params.get("out_trade_no").is_none_or(|s| s.is_empty());
+        if self.auto_out_trade_no && missing {
+            params.insert("out_trade_no".to_string(), get_order_no()?);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// 补全下单请求中由 client 提供的参数
+    ///
+    /// 调用方在 `params` 中指定的 `notify_url` 优先于 client 的默认值
+    fn pay_params(
+        &self,
+        params: BTreeMap<String, String>,
+        trade_type: TradeType,
+    ) -> Result<BTreeMap<String, String>, WechatpayError> {
         let body = params
             .get("body")
             .unwrap_or(&"Test Request".to_string())
             .to_string();
         let mut params = params;
-        params.insert("trade_type".to_string(), trade_type.to_string());
+        // 刷卡支付的接口地址已经确定了交易类型, 请求中没有 `trade_type` 字段
+        if trade_type == TradeType::Micro {
+            params.remove("trade_type");
+        } else {
+            params.insert("trade_type".to_string(), trade_type.to_string());
+        }
         params.insert("appid".to_string(), self.appid.clone());
         params.insert("mch_id".to_string(), self.mch_id.clone());
-        params.insert("nonce_str".to_string(), get_nonce_str());
+        if let Some(ref sub_mch_id) = self.sub_mch_id {
+            params.insert("sub_mch_id".to_string(), sub_mch_id.clone());
+        }
+        if let Some(ref sub_appid) = self.sub_appid {
+            params.insert("sub_appid".to_string(), sub_appid.clone());
+        }
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
         params.insert("body".to_string(), body);
         if trade_type != TradeType::Micro {
-            params.insert("notify_url".to_string(), self.notify_url.clone());
+            let notify_url = match params.get("notify_url") {
+                Some(url) if !url.is_empty() => url.clone(),
+                _ => self.notify_url.clone(),
+            };
+            check_notify_url(&notify_url, self.require_https_notify)?;
+            params.insert("notify_url".to_string(), notify_url);
         }
-        self.request(url, params, retries, false)
+        Ok(params)
+    }
+
+    /// 统一下单, 返回类型化的结果
+    pub fn unified_order(
+        &self,
+        params: BTreeMap<String, String>,
+        trade_type: TradeType,
+        retries: Option<u32>,
+    ) -> Result<UnifiedOrderResponse, WechatpayError> {
+        let profit_sharing = params.get("profit_sharing").map(|s| s.as_str()) == Some("Y");
+        let pairs = self.pay(params, trade_type, retries)?;
+        Ok(UnifiedOrderResponse::new(pairs, trade_type).with_profit_sharing(profit_sharing))
+    }
+
+    /// 统一下单并指定需要分账 (`profit_sharing=Y`)
+    ///
+    /// 支付成功后资金会被冻结, 商户必须调用分账接口, 最后调用 `profit_sharing_finish`
+    /// 解冻剩余资金, 否则资金会一直冻结。返回结果的
+    /// `requires_profit_sharing_followup()` 为 `true`
+    pub fn unified_order_with_profit_sharing(
+        &self,
+        params: BTreeMap<String, String>,
+        trade_type: TradeType,
+        retries: Option<u32>,
+    ) -> Result<UnifiedOrderResponse, WechatpayError> {
+        let mut params = params;
+        params.insert("profit_sharing".to_string(), "Y".to_string());
+        self.unified_order(params, trade_type, retries)
     }
 
+    /// [提交刷卡支付]
+    ///
+    /// 请求中的 `device_info` (终端设备号) 在返回结果缺少该字段时会补充到结果中,
+    /// 以便按终端对账。设置了 `with_pending_store` 时, 因网络错误失败的请求会保存下来,
+    /// 之后由 `flush_pending` 重新提交。`auth_code` 会先经过 `sanitize_auth_code` 处理
     pub fn micro_pay(
         &self,
         params: BTreeMap<String, String>,
         retries: Option<u32>,
     ) -> WechatpayResult {
-        self.pay(params, TradeType::Micro, retries)
+        let mut params = params;
+        if let Some(auth_code) = params.get_mut("auth_code") {
+            *auth_code = sanitize_auth_code(auth_code);
+        }
+        let device_info = params.get("device_info").cloned();
+        // 先生成 `out_trade_no`, 保存的请求和发出的请求使用同一个订单号
+        let generated = self.fill_pay_defaults(&mut params)?;
+        let mut pairs = match self.pay(params.clone(), TradeType::Micro, retries) {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                if let Some(ref pending) = self.pending {
+                    if e.is_network() {
+                        pending.push(params);
+                    }
+                }
+                return Err(e);
+            }
+        };
+        if let Some(device_info) = device_info {
+            pairs
+                .entry("device_info".to_string())
+                .or_insert(device_info);
+        }
+        if generated {
+            pairs
+                .entry("out_trade_no".to_string())
+                .or_insert_with(|| params["out_trade_no"].clone());
+        }
+        Ok(pairs)
+    }
+
+    /// 重新提交因网络错误保存的刷卡支付
+    ///
+    /// 返回每个请求的 `out_trade_no` 和提交结果。提交前先查询订单, 只有查询结果为订单
+    /// 不存在 (`ORDERNOTEXIST`) 时才重新提交, 其他情况都不提交, 避免重复扣款:
+    ///
+    /// - 订单已支付 (之前的请求实际已经到达网关): 返回查询结果
+    /// - 用户支付中 (`USERPAYING`): 重新保存, 返回错误
+    /// - 其他交易状态: 返回以 `trade_state` 为 `err_code` 的业务错误
+    /// - 查询遇到网络错误: 重新保存, 返回该错误; 其他查询错误直接返回
+    ///
+    /// 重新提交后结果不确定 (如 `USERPAYING`, `ORDERPAID`) 时查询订单, 已支付时返回
+    /// 查询结果, 否则撤销订单, 返回原来的错误。没有设置 `with_pending_store` 时返回空列表
+    pub fn flush_pending(&self) -> Vec<(String, WechatpayResult)> {
+        let pending = match self.pending {
+            Some(ref pending) => pending,
+            None => return Vec::new(),
+        };
+        let mut results = Vec::new();
+        for params in pending.take_all() {
+            let out_trade_no = params.get("out_trade_no").cloned().unwrap_or_default();
+            match self.query_order(OrderIdentifier::OutTradeNo(out_trade_no.clone())) {
+                Err(WechatpayError::Business {
+                    err_code: ErrCode::OrderNotExist,
+                    ..
+                }) => {}
+                Err(e) => {
+                    if e.is_network() {
+                        pending.push(params);
+                    }
+                    results.push((out_trade_no, Err(e)));
+                    continue;
+                }
+                Ok(pairs) => {
+                    let trade_state = pairs.get("trade_state").cloned().unwrap_or_default();
+                    let result = match trade_state.as_str() {
+                        "SUCCESS" | "REFUND" => Ok(pairs),
+                        _ => {
+                            if trade_state == "USERPAYING" {
+                                pending.push(params);
+                            }
+                            Err(WechatpayError::Business {
+                                err_code: ErrCode::from_response(&trade_state),
+                                err_code_des: pairs
+                                    .get("trade_state_desc")
+                                    .cloned()
+                                    .unwrap_or_default(),
+                            })
+                        }
+                    };
+                    results.push((out_trade_no, result));
+                    continue;
+                }
+            }
+            let result = match MicropayOutcome::from_result(self.micro_pay(params, None)) {
+                MicropayOutcome::Paid(pairs) => Ok(pairs),
+                MicropayOutcome::Failed(e) => Err(e),
+                MicropayOutcome::NeedsQuery(e) => {
+                    if e.is_network() {
+                        Err(e)
+                    } else {
+                        self.settle_micropay(&out_trade_no, e)
+                    }
+                }
+            };
+            results.push((out_trade_no, result));
+        }
+        results
+    }
+
+    /// 刷卡支付结果不确定时查询订单: 已支付时返回查询结果; 未支付时撤销订单, 返回 `err`;
+    /// 查询失败时不撤销 (订单可能已经支付), 返回 `err`
+    fn settle_micropay(&self, out_trade_no: &str, err: WechatpayError) -> WechatpayResult {
+        let id = || OrderIdentifier::OutTradeNo(out_trade_no.to_string());
+        let pairs = match self.query_order(id()) {
+            Ok(pairs) => pairs,
+            Err(_) => return Err(err),
+        };
+        if pairs.get("trade_state").map(|s| s.as_str()) == Some("SUCCESS") {
+            return Ok(pairs);
+        }
+        let _ = self.reverse(id());
+        Err(err)
+    }
+
+    /// [撤销订单] (刷卡支付), 使用 `HMAC-SHA256` 签名, 需要商户证书
+    pub fn reverse(&self, id: OrderIdentifier) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("sign_type".to_string(), SignType::HmacSha256.to_string());
+        match id {
+            OrderIdentifier::TransactionId(s) => {
+                params.insert("transaction_id".to_string(), s);
+            }
+            OrderIdentifier::OutTradeNo(s) => {
+                params.insert("out_trade_no".to_string(), s);
+            }
+        }
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+
+        self.request(REVERSE_URL, params, None, true)
     }
 
     pub fn jsapi_pay(
@@ -238,6 +1633,49 @@ impl WechatpayClient {
         self.pay(params, TradeType::Jsapi, retries)
     }
 
+    /// 公众号支付下单, 返回预支付交易会话标识 `prepay_id`
+    pub fn jsapi_prepay_id(
+        &self,
+        params: BTreeMap<String, String>,
+        retries: Option<u32>,
+    ) -> Result<String, WechatpayError> {
+        let response = self.unified_order(params, TradeType::Jsapi, retries)?;
+        if response.prepay_id.is_empty() {
+            return Err(WechatpayError::MissingField {
+                operation: "unifiedorder",
+                field: "prepay_id".to_string(),
+            });
+        }
+        Ok(response.prepay_id)
+    }
+
+    /// [网页端调起支付API] (`WeixinJSBridge.invoke('getBrandWCPayRequest', ...)`) 的参数
+    ///
+    /// 包括 `appId`, `timeStamp`, `nonceStr`, `package`, `signType` 和 `paySign`;
+    /// 设置了子商户公众账号 `sub_appid` 时 `appId` 为 `sub_appid`
+    pub fn jsapi_params(&self, prepay_id: &str) -> BTreeMap<String, String> {
+        let appid = self.sub_appid.as_ref().unwrap_or(&self.appid);
+        let mut params = BTreeMap::new();
+        params.insert("appId".to_string(), appid.clone());
+        params.insert("timeStamp".to_string(), get_timestamp().to_string());
+        params.insert("nonceStr".to_string(), self.nonce.nonce_str());
+        params.insert("package".to_string(), format!("prepay_id={}", prepay_id));
+        params.insert("signType".to_string(), self.sign_type.to_string());
+        let pay_sign = self.signer.sign(&sign_source(&params, &[]), self.sign_type);
+        params.insert("paySign".to_string(), pay_sign);
+        params
+    }
+
+    /// 公众号支付下单并生成网页端调起支付的参数, 即 `jsapi_prepay_id` + `jsapi_params`
+    pub fn jsapi_checkout(
+        &self,
+        params: BTreeMap<String, String>,
+        retries: Option<u32>,
+    ) -> Result<BTreeMap<String, String>, WechatpayError> {
+        let prepay_id = self.jsapi_prepay_id(params, retries)?;
+        Ok(self.jsapi_params(&prepay_id))
+    }
+
     pub fn qrcode_pay(
         &self,
         params: BTreeMap<String, String>,
@@ -254,9 +1692,32 @@ impl WechatpayClient {
         self.pay(params, TradeType::App, retries)
     }
 
+    /// H5 支付下单, `scene_info` 由 `scene` 生成; 返回结果中的 `mweb_url` 为支付跳转链接
+    ///
+    /// `params` 中已有 `scene_info` 时返回 `WechatpayError::RedundantField`
+    pub fn h5_order(
+        &self,
+        params: BTreeMap<String, String>,
+        scene: &H5Info,
+        retries: Option<u32>,
+    ) -> WechatpayResult {
+        let mut params = params;
+        self.check_params(
+            &params,
+            vec!["scene_info"],
+            ParamsCheckType::Forbidden,
+            "unifiedorder",
+        )?;
+        params.insert("scene_info".to_string(), scene.to_scene_info());
+        self.pay(params, TradeType::Mweb, retries)
+    }
+
+    /// [查询订单]
+    ///
+    /// 订单号先经过 `OrderIdentifier::normalize`, 格式不正确时不发送请求
     pub fn query_order(&self, id: OrderIdentifier) -> WechatpayResult {
         let mut params = BTreeMap::new();
-        match id {
+        match id.normalize()? {
             OrderIdentifier::TransactionId(s) => {
                 params.insert("transaction_id".to_string(), s);
             }
@@ -266,279 +1727,4854 @@ impl WechatpayClient {
         }
         params.insert("appid".to_string(), self.appid.clone());
         params.insert("mch_id".to_string(), self.mch_id.clone());
-        params.insert("nonce_str".to_string(), get_nonce_str());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
 
         self.request(ORDERQUERY_URL, params, None, false)
     }
-}
-
-/// [交易类型]
-#[derive(PartialEq)]
-pub enum TradeType {
-    /// `MICRO`
-    Micro,
-    /// `JSAPI`
-    Jsapi,
-    /// `NATIVE`
-    Native,
-    Qrcode,
-    /// `APP` : app支付，统一下单接口trade_type的传参可参考这里
-    App,
-}
-
-/// [交易金额]
-///
-/// 交易金额默认为人民币交易，接口中参数支付金额单位为【分】，参数值不能带小数。
-/// 对账单中的交易金额单位为【元】。
-/// 外币交易的支付金额精确到币种的最小单位，参数值不能带小数点。
-pub fn get_trade_amount(v: f32) -> u32 {
-    // FIXME:: 不同情况下的金额处理
-    (v * 100.0).round() as u32
-}
-
-/// [时间]
-///
-/// 标准北京时间，时区为东八区；如果商户的系统时间为非标准北京时间。
-/// 参数值必须根据商户系统所在时区先换算成标准北京时间，
-/// 例如商户所在地为0时区的伦敦，当地时间为2014年11月11日0时0分0秒，
-/// 换算成北京时间为2014年11月11日8时0分0秒。
-pub fn get_time_str() -> String {
-    // FIXME:: 如果是服务器在海外中国网站就会有问题
-    strftime("%Y%m%d%H%M%S", &time::now()).unwrap()
-}
-
-/// [时间戳]
-///
-/// 标准北京时间，时区为东八区，自1970年1月1日 0点0分0秒以来的秒数。
-/// 注意：部分系统取到的值为毫秒级，需要转换成秒(10位数字)。
-pub fn get_timestamp() -> i64 {
-    time::get_time().sec
-}
-
-/// [生成随机数算法]
-///
-/// 微信支付API接口协议中包含字段nonce_str，主要保证签名不可预测。
-/// 我们推荐生成随机数算法如下：调用随机数函数生成，将得到的值转换为字符串。
-pub fn get_nonce_str() -> String {
-    Uuid::new_v4().simple().to_string()
-}
-
-/// [商户订单号]
-///
-/// 商户支付的订单号由商户自定义生成，微信支付要求商户订单号保持唯一性
-/// （建议根据当前系统时间加随机序列来生成订单号）。
-/// 重新发起一笔支付要使用原订单号，避免重复支付；
-/// 已支付过或已调用关单、撤销（请见后文的API列表）的订单号不能重新发起支付。
-pub fn get_order_no() -> String {
-    get_time_str() + &((&get_nonce_str())[..18])
-}
-
-/// 签名算法 (给请求参数签名)
-///
-/// 详见: 接口规则 > 安全规范
-pub fn get_sign(pairs: &BTreeMap<String, String>, api_key: &str) -> String {
-    // 如果参数的值为空不参与签名；
-    let keys = pairs
-        .iter()
-        .filter(|pair| pair.0.ne("key") && pair.0.ne("sign") && !pair.1.is_empty())
-        .map(|pair| pair.0.to_string())
-        .collect::<Vec<String>>();
 
-    // 参数名ASCII码从小到大排序（字典序）；
-    let mut encoder = form_urlencoded::Serializer::new(String::new());
-    for key in keys {
-        encoder.append_pair(&key, &pairs[&key]);
+    /// [查询订单], 返回类型化的结果
+    pub fn query(&self, id: OrderIdentifier) -> Result<OrderQueryResult, WechatpayError> {
+        OrderQueryResult::try_from(self.query_order(id)?)
     }
 
-    encoder.append_pair("key", api_key);
-    let encoded = encoder.finish();
-
-    // 生成 MD5 字符串
-    let mut context = md5::Context::new();
-    context.consume(encoded.as_bytes());
-    let mut digest = String::with_capacity(32);
-    for x in &context.compute()[..] {
-        digest.push_str(&format!("{:02X}", x));
+    /// 查询订单的交易状态
+    ///
+    /// `result_code` 为 `SUCCESS` 只表示查询成功, 支付结果以返回的状态为准:
+    /// 例如 `TradeState::PayError` 表示支付失败, 用 `TradeState::is_paid` 判断是否已付款
+    pub fn query_order_state(&self, id: OrderIdentifier) -> Result<TradeState, WechatpayError> {
+        let pairs = self.query_order(id)?;
+        let trade_state = pairs.get("trade_state").cloned().unwrap_or_default();
+        TradeState::from_response(&trade_state).ok_or_else(|| WechatpayError::InvalidField {
+            field: "trade_state".to_string(),
+            reason: format!("unknown state {:?}", trade_state),
+        })
     }
-    digest
-}
-
-/// 将`xml`数据解析成`BTreeMap`
-pub fn from_xml_str(data: &str) -> BTreeMap<String, String> {
-    let mut pairs = BTreeMap::new();
 
-    let reader = xml::reader::EventReader::from_str(data);
-    let mut tag: String = "".to_string();
-    for event in reader {
-        match event {
-            Ok(xml::reader::XmlEvent::StartElement { name, .. }) => {
-                tag = name.local_name;
-            }
-            Ok(xml::reader::XmlEvent::CData(value)) => {
-                pairs.insert(tag.clone(), value);
+    /// 每隔 `interval` 查询一次交易状态, 依次返回查询到的状态
+    ///
+    /// 返回终态 (见 `TradeState::is_terminal`) 后结束; 超过 `timeout` 时也会结束;
+    /// 查询出错时返回该错误后结束
+    pub fn watch_order<'a>(
+        &'a self,
+        id: OrderIdentifier,
+        interval: Duration,
+        timeout: Duration,
+    ) -> impl Iterator<Item = Result<TradeState, WechatpayError>> + 'a {
+        let deadline = Instant::now() + timeout;
+        let mut first = true;
+        let mut done = false;
+        ::std::iter::from_fn(move || {
+            if done {
+                return None;
             }
-            Err(e) => {
-                println!("Parse xml error: {:?}", e);
-                break;
+            if !first {
+                if Instant::now() + interval > deadline {
+                    return None;
+                }
+                thread::sleep(interval);
             }
-            _ => {}
-        }
+            first = false;
+            let result = self.query_order_state(id.clone());
+            done = result.as_ref().map_or(true, |state| state.is_terminal());
+            Some(result)
+        })
     }
-    pairs
-}
 
-/// 使用`BTreeMap`生成`xml`数据
-pub fn to_xml_str(pairs: &BTreeMap<String, String>) -> String {
-    let mut target: Vec<u8> = Vec::new();
-    {
-        let mut writer = xml::writer::EmitterConfig::new()
-            .write_document_declaration(false)
-            .create_writer(&mut target);
-        let _ = writer.write::<events::XmlEvent>(events::XmlEvent::start_element("xml").into());
-        for (key, value) in pairs {
-            let _ = writer
-                .write::<events::XmlEvent>(events::XmlEvent::start_element(key.as_ref()).into());
-            let _ = writer
-                .write::<events::XmlEvent>(events::XmlEvent::characters(value.as_ref()));
-            let _ = writer.write::<events::XmlEvent>(events::XmlEvent::end_element().into());
-        }
-        let _ = writer.write::<events::XmlEvent>(events::XmlEvent::end_element().into());
+    /// 查询支付用户的 `openid`, 订单未支付时返回 `None`
+    pub fn query_openid(&self, id: OrderIdentifier) -> Result<Option<String>, WechatpayError> {
+        let pairs = self.query_order(id)?;
+        let state = pairs
+            .get("trade_state")
+            .and_then(|s| TradeState::from_response(s));
+        Ok(if state.is_some_and(|state| state.is_paid()) {
+            pairs.get("openid").cloned()
+        } else {
+            None
+        })
     }
-    String::from_utf8(target).unwrap()
+
+    /// 查询订单, 优先使用 `transaction_id`, 没有时使用 `out_trade_no`
+    ///
+    /// 两者都没有时返回 `WechatpayError::MissingField`
+    pub fn query_order_best(
+        &self,
+        transaction_id: Option<&str>,
+        out_trade_no: Option<&str>,
+    ) -> WechatpayResult {
+        let id = match (transaction_id, out_trade_no) {
+            (Some(transaction_id), _) => OrderIdentifier::TransactionId(transaction_id.to_string()),
+            (None, Some(out_trade_no)) => OrderIdentifier::OutTradeNo(out_trade_no.to_string()),
+            (None, None) => {
+                return Err(WechatpayError::MissingField {
+                    operation: "orderquery",
+                    field: "transaction_id".to_string(),
+                })
+            }
+        };
+        self.query_order(id)
+    }
+
+    /// [申请退款]
+    ///
+    /// `params` 中需要包含 `out_refund_no`, `total_fee`, `refund_fee`。
+    /// `refund_account` 不指定时由网关决定退款资金来源。需要商户证书。
+    /// 可选的 `notify_url` 为退款结果通知地址, 与支付结果通知地址相互独立, 不指定时使用
+    /// 商户平台上配置的地址。
+    ///
+    /// 默认使用 `HMAC-SHA256` 签名 (与 `with_sign_type` 无关), 可在 `params` 中传入 `sign_type` 覆盖。
+    pub fn refund(
+        &self,
+        id: OrderIdentifier,
+        params: BTreeMap<String, String>,
+        refund_account: Option<RefundAccount>,
+        retries: Option<u32>,
+    ) -> WechatpayResult {
+        self.check_params(
+            &params,
+            vec!["out_refund_no", "total_fee", "refund_fee"],
+            ParamsCheckType::Required,
+            "refund",
+        )?;
+
+        let mut params = params;
+        if let Some(account) = refund_account {
+            params.insert("refund_account".to_string(), account.to_string());
+        }
+        if let Some(account) = params.get("refund_account") {
+            let valid = [RefundAccount::UnsettledFunds, RefundAccount::RechargeFunds]
+                .iter()
+                .any(|a| a.to_string() == *account);
+            if !valid {
+                return Err(WechatpayError::InvalidField {
+                    field: "refund_account".to_string(),
+                    reason: format!("unknown refund account {}", account),
+                });
+            }
+        }
+        if let Some(notify_url) = params.get("notify_url") {
+            check_notify_url(notify_url, self.require_https_notify)?;
+        }
+        match id {
+            OrderIdentifier::TransactionId(s) => {
+                params.insert("transaction_id".to_string(), s);
+            }
+            OrderIdentifier::OutTradeNo(s) => {
+                params.insert("out_trade_no".to_string(), s);
+            }
+        }
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params
+            .entry("sign_type".to_string())
+            .or_insert_with(|| SignType::HmacSha256.to_string());
+
+        let out_refund_no = params["out_refund_no"].clone();
+        let trace_id = RequestContext::current().trace_id;
+        self.audit.record_intent(
+            "refund",
+            &out_refund_no,
+            &params["refund_fee"],
+            trace_id.as_deref(),
+        );
+        let result = self.request(REFUND_URL, params, retries, true);
+        self.audit.record_outcome(
+            "refund",
+            &out_refund_no,
+            result.as_ref().map(|_| ()),
+            trace_id.as_deref(),
+        );
+        result
+    }
+
+    /// 全额退款: `refund_fee` 与 `total_fee` 相同, 其他同 `refund`
+    pub fn full_refund(
+        &self,
+        id: OrderIdentifier,
+        total_fee: u32,
+        out_refund_no: &str,
+    ) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("out_refund_no".to_string(), out_refund_no.to_string());
+        params.insert("total_fee".to_string(), total_fee.to_string());
+        params.insert("refund_fee".to_string(), total_fee.to_string());
+        self.refund(id, params, None, None)
+    }
+
+    /// [完结分账]
+    ///
+    /// 分账完成后解冻剩余资金, 使用 `HMAC-SHA256` 签名, 需要商户证书
+    pub fn profit_sharing_finish(
+        &self,
+        transaction_id: &str,
+        out_order_no: &str,
+        description: &str,
+    ) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("transaction_id".to_string(), transaction_id.to_string());
+        params.insert("out_order_no".to_string(), out_order_no.to_string());
+        params.insert("description".to_string(), description.to_string());
+        self.check_params(
+            &params,
+            vec!["transaction_id", "out_order_no", "description"],
+            ParamsCheckType::Required,
+            "profitsharingfinish",
+        )?;
+
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("sign_type".to_string(), SignType::HmacSha256.to_string());
+        self.request(PROFITSHARINGFINISH_URL, params, None, true)
+    }
+
+    /// [添加分账接收方]
+    ///
+    /// `receiver_json` 为接收方的 JSON 对象, 如
+    /// `{"type":"MERCHANT_ID","account":"190001001","name":"示例商户","relation_type":"STORE_OWNER"}`。
+    /// 分账前必须先添加接收方。使用 `HMAC-SHA256` 签名, 需要商户证书
+    pub fn profit_sharing_add_receiver(&self, receiver_json: &str) -> WechatpayResult {
+        self.profit_sharing_receiver(
+            PROFITSHARINGADDRECEIVER_URL,
+            "profitsharingaddreceiver",
+            receiver_json,
+        )
+    }
+
+    /// [删除分账接收方]
+    ///
+    /// `receiver_json` 同 `profit_sharing_add_receiver`, 只需要 `type` 和 `account`
+    pub fn profit_sharing_remove_receiver(&self, receiver_json: &str) -> WechatpayResult {
+        self.profit_sharing_receiver(
+            PROFITSHARINGREMOVERECEIVER_URL,
+            "profitsharingremovereceiver",
+            receiver_json,
+        )
+    }
+
+    fn profit_sharing_receiver(
+        &self,
+        url: &str,
+        operation: &'static str,
+        receiver_json: &str,
+    ) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("receiver".to_string(), receiver_json.to_string());
+        self.check_params(
+            &params,
+            vec!["receiver"],
+            ParamsCheckType::Required,
+            operation,
+        )?;
+        if !json::is_json_object(receiver_json) {
+            return Err(WechatpayError::InvalidField {
+                field: "receiver".to_string(),
+                reason: "not a JSON object".to_string(),
+            });
+        }
+
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("sign_type".to_string(), SignType::HmacSha256.to_string());
+        self.request(url, params, None, true)
+    }
+
+    /// [查询退款]
+    pub fn refund_query(&self, id: OrderIdentifier) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        match id {
+            OrderIdentifier::TransactionId(s) => {
+                params.insert("transaction_id".to_string(), s);
+            }
+            OrderIdentifier::OutTradeNo(s) => {
+                params.insert("out_trade_no".to_string(), s);
+            }
+        }
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+
+        self.request(REFUNDQUERY_URL, params, None, false)
+    }
+
+    /// 订单是否已经全额退款
+    ///
+    /// 先查询订单, `trade_state` 为 `REFUND` 时再查询退款, 比较退款成功 (或处理中)
+    /// 的金额之和与订单金额
+    pub fn is_fully_refunded(&self, id: OrderIdentifier) -> Result<bool, WechatpayError> {
+        let order = self.query_order(id.clone())?;
+        if order.get("trade_state").map(|s| s.as_str()) != Some("REFUND") {
+            return Ok(false);
+        }
+        let total_fee = parse_fee(&order, "total_fee")?;
+
+        let refunds = self.refund_query(id)?;
+        let refund_count = parse_fee(&refunds, "refund_count")?;
+        let mut refunded: u64 = 0;
+        for i in 0..refund_count {
+            let status = refunds
+                .get(&format!("refund_status_{}", i))
+                .map(|s| s.as_str());
+            if status == Some("SUCCESS") || status == Some("PROCESSING") {
+                refunded += parse_fee(&refunds, &format!("refund_fee_{}", i))?;
+            }
+        }
+        Ok(refunded >= total_fee)
+    }
+
+    /// [关闭订单]
+    ///
+    /// 关单是幂等的: 订单已经关闭 (`ORDERCLOSED`) 也视为成功, `SYSTEMERROR` 会重试。
+    /// 返回 `true` 表示本次调用关闭了订单, `false` 表示订单之前已经关闭。
+    pub fn close_order(
+        &self,
+        out_trade_no: &str,
+        retries: Option<u32>,
+    ) -> Result<bool, WechatpayError> {
+        let mut err = WechatpayError::Request;
+        for _ in 0..retries.unwrap_or(1) {
+            let mut params = BTreeMap::new();
+            params.insert("appid".to_string(), self.appid.clone());
+            params.insert("mch_id".to_string(), self.mch_id.clone());
+            params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+            params.insert("out_trade_no".to_string(), out_trade_no.to_string());
+            self.check_params(
+                &params,
+                vec!["out_trade_no"],
+                ParamsCheckType::Required,
+                "closeorder",
+            )?;
+
+            match self.request(CLOSEORDER_URL, params, None, false) {
+                Ok(_) => return Ok(true),
+                Err(WechatpayError::Business { ref err_code, .. })
+                    if *err_code == ErrCode::OrderClosed =>
+                {
+                    return Ok(false);
+                }
+                Err(e) => {
+                    // SYSTEMERROR: 系统异常, 重新调用
+                    if !e.is_unknown_state() {
+                        return Err(e);
+                    }
+                    err = e;
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// [查询代金券批次]
+    ///
+    /// 操作员 `op_user_id` 默认为商户号
+    pub fn query_coupon_stock(&self, coupon_stock_id: &str) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("coupon_stock_id".to_string(), coupon_stock_id.to_string());
+        params.insert("op_user_id".to_string(), self.mch_id.clone());
+        params.insert("version".to_string(), "1.0".to_string());
+        params.insert("type".to_string(), "XML".to_string());
+        self.check_params(
+            &params,
+            vec!["coupon_stock_id", "op_user_id"],
+            ParamsCheckType::Required,
+            "query_coupon_stock",
+        )?;
+
+        self.request(QUERY_COUPON_STOCK_URL, params, None, false)
+    }
+
+    /// [查询代金券信息]
+    ///
+    /// 返回结果中的 `coupon_state` 为代金券状态, `coupon_value` 为面额, `is_partial_use`
+    /// 为是否允许部分使用
+    pub fn query_coupons_info(
+        &self,
+        coupon_id: &str,
+        openid: &str,
+        stock_id: &str,
+    ) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("coupon_id".to_string(), coupon_id.to_string());
+        params.insert("openid".to_string(), openid.to_string());
+        params.insert("stock_id".to_string(), stock_id.to_string());
+        self.check_params(
+            &params,
+            vec!["coupon_id", "openid", "stock_id"],
+            ParamsCheckType::Required,
+            "query_coupon",
+        )?;
+
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("op_user_id".to_string(), self.mch_id.clone());
+        params.insert("version".to_string(), "1.0".to_string());
+        params.insert("type".to_string(), "XML".to_string());
+        self.request(QUERY_COUPON_URL, params, None, false)
+    }
+
+    /// [发放代金券]
+    ///
+    /// `params` 中需要包含 `coupon_stock_id`, `openid_count`, `partner_trade_no`, `openid`;
+    /// 操作员 `op_user_id` 默认为商户号。需要商户证书。
+    pub fn send_coupon(
+        &self,
+        params: BTreeMap<String, String>,
+        retries: Option<u32>,
+    ) -> WechatpayResult {
+        let mut params = params;
+        params
+            .entry("op_user_id".to_string())
+            .or_insert_with(|| self.mch_id.clone());
+        self.check_params(
+            &params,
+            vec![
+                "coupon_stock_id",
+                "openid_count",
+                "partner_trade_no",
+                "openid",
+                "op_user_id",
+            ],
+            ParamsCheckType::Required,
+            "send_coupon",
+        )?;
+
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("version".to_string(), "1.0".to_string());
+        params.insert("type".to_string(), "XML".to_string());
+        self.request(SEND_COUPON_URL, params, retries, true)
+    }
+
+    /// [发放裂变红包]
+    ///
+    /// 公众账号使用 `wxappid` 字段; 红包金额设置方式 `amt_type` 只能为 `ALL_RAND`,
+    /// 与普通红包不同, 裂变红包没有 `client_ip` 字段。需要商户证书。
+    pub fn send_group_red_pack(
+        &self,
+        params: BTreeMap<String, String>,
+        retries: Option<u32>,
+    ) -> WechatpayResult {
+        self.check_params(
+            &params,
+            vec![
+                "mch_billno",
+                "send_name",
+                "re_openid",
+                "total_amount",
+                "total_num",
+                "wishing",
+                "act_name",
+                "remark",
+            ],
+            ParamsCheckType::Required,
+            "sendgroupredpack",
+        )?;
+
+        let mut params = params;
+        params.remove("client_ip");
+        params.insert("amt_type".to_string(), "ALL_RAND".to_string());
+        params.insert("wxappid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        self.request(SENDGROUPREDPACK_URL, params, retries, true)
+    }
+
+    /// [查询红包记录]
+    ///
+    /// 需要商户证书, 返回结果中包含每个用户的领取记录 (`openid_$n`, `amount_$n`,
+    /// `rcv_time_$n`, 数量为 `hbinfo_count`), 可以用 `RedPackInfo::from_pairs` 解析
+    pub fn get_hb_info(&self, mch_billno: &str) -> WechatpayResult {
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("mch_billno".to_string(), mch_billno.to_string());
+        params.insert("bill_type".to_string(), "MCHT".to_string());
+        self.check_params(
+            &params,
+            vec!["mch_billno"],
+            ParamsCheckType::Required,
+            "gethbinfo",
+        )?;
+
+        self.request_with(GETHBINFO_URL, params, None, true, |data| {
+            Ok(response::hb_info_pairs(&decode_xml(data)?))
+        })
+    }
+
+    /// 健康检查
+    ///
+    /// 查询一个不存在的订单, 网关返回带有正确签名的 `ORDERNOTEXIST` 即说明网络、
+    /// 证书和商户密钥都没有问题
+    pub fn ping(&self) -> Result<(), WechatpayError> {
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        // 同样使用 client 的 nonce 生成器, 使用 `SeededNonce` 时请求可以重现
+        let suffix = self.nonce.nonce_str().chars().take(28).collect::<String>();
+        params.insert("out_trade_no".to_string(), format!("ping{}", suffix));
+
+        match self.request(ORDERQUERY_URL, params, None, false) {
+            Ok(_) => Ok(()),
+            Err(WechatpayError::Business { ref err_code, .. })
+                if *err_code == ErrCode::OrderNotExist =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 处理 [支付结果通知]
+    ///
+    /// 依次检查 `return_code`, 签名和 `result_code`, 然后解析为 `PaymentNotification`
+    pub fn handle_notification(&self, body: &str) -> Result<PaymentNotification, WechatpayError> {
+        self.parse_notification_bytes(body.as_bytes())
+    }
+
+    /// 同 `handle_notification`, 直接处理 HTTP 请求的原始数据
+    ///
+    /// 按 `from_xml_bytes` 解码 (支持 BOM 和 `<?xml encoding="..."?>`), 调用方无需先转换为 `&str`
+    pub fn parse_notification_bytes(
+        &self,
+        body: &[u8],
+    ) -> Result<PaymentNotification, WechatpayError> {
+        let pairs = from_xml_bytes(body)?;
+        check_return(&pairs)?;
+        if !verify_sign_with(&pairs, &*self.signer, self.sign_type) {
+            return Err(WechatpayError::SignatureMismatch);
+        }
+        PaymentNotification::from_pairs(check_result(pairs)?)
+    }
+
+    /// 检查回调数据是否可信且与商户订单一致
+    ///
+    /// 依次检查 `return_code`, 签名, `result_code`; `expected` 为商户系统中的
+    /// (`out_trade_no`, 金额【分】), 指定时还检查回调中的订单号和 `total_fee` 与之一致
+    pub fn validate_incoming(
+        &self,
+        pairs: &BTreeMap<String, String>,
+        expected: Option<(&str, u32)>,
+    ) -> Result<(), WechatpayError> {
+        check_return(pairs)?;
+        if !verify_sign_with(pairs, &*self.signer, self.sign_type) {
+            return Err(WechatpayError::SignatureMismatch);
+        }
+        check_result(pairs.clone())?;
+        if let Some((out_trade_no, total_fee)) = expected {
+            let received = pairs.get("out_trade_no").map(|s| s.as_str());
+            if received != Some(out_trade_no) {
+                return Err(WechatpayError::InvalidField {
+                    field: "out_trade_no".to_string(),
+                    reason: format!(
+                        "expected {}, got {}",
+                        out_trade_no,
+                        received.unwrap_or("nothing")
+                    ),
+                });
+            }
+            if !pairs.contains_key("total_fee") {
+                return Err(WechatpayError::MissingField {
+                    operation: "notification",
+                    field: "total_fee".to_string(),
+                });
+            }
+            check_total_fee(&total_fee.to_string(), pairs)?;
+        }
+        Ok(())
+    }
+
+    /// [下载对账单]
+    ///
+    /// `bill_date` 格式为 `20140603`, 成功时返回对账单文本; 日期格式错误或不早于北京时间的
+    /// 当天时返回 `WechatpayError::InvalidField` (当天的对账单次日才生成)
+    pub fn download_bill(
+        &self,
+        bill_date: &str,
+        bill_type: BillType,
+        retries: Option<u32>,
+    ) -> Result<String, WechatpayError> {
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.appid.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), self.nonce.nonce_str());
+        params.insert("bill_date".to_string(), bill_date.to_string());
+        params.insert("bill_type".to_string(), bill_type.to_string());
+        self.check_params(
+            &params,
+            vec!["bill_date"],
+            ParamsCheckType::Required,
+            "downloadbill",
+        )?;
+        bill::check_bill_date(bill_date, &bill::beijing_today())?;
+
+        let trace_id = RequestContext::current().trace_id;
+        self.audit
+            .record_intent("downloadbill", bill_date, "", trace_id.as_deref());
+        let result = self.measure(DOWNLOADBILL_URL, || {
+            let data = self.request_raw(DOWNLOADBILL_URL, params, retries, false)?;
+            let s = String::from_utf8(data).map_err(|_| WechatpayError::Request)?;
+            // 失败时返回的是 xml 数据
+            if s.trim_start().starts_with("<xml>") {
+                check_result(from_xml_str(s.as_ref()))?;
+                return Err(WechatpayError::Request);
+            }
+            Ok(s)
+        });
+        self.audit.record_outcome(
+            "downloadbill",
+            bill_date,
+            result.as_ref().map(|_| ()),
+            trace_id.as_deref(),
+        );
+        result
+    }
+
+    /// 依次下载 `from` 到 `to` (包含) 每天的对账单, 返回 (日期, 对账单文本)
+    ///
+    /// 任意一天下载失败时返回该错误
+    pub fn download_bills_range(
+        &self,
+        from: &str,
+        to: &str,
+        bill_type: BillType,
+        retries: Option<u32>,
+    ) -> Result<Vec<(String, String)>, WechatpayError> {
+        let mut day = bill::parse_bill_date(from)?;
+        let last = bill::parse_bill_date(to)?;
+        if day > last {
+            return Err(WechatpayError::InvalidField {
+                field: "bill_date".to_string(),
+                reason: format!("range start {} is after end {}", from, to),
+            });
+        }
+        let mut bills = Vec::new();
+        while day <= last {
+            let bill_date = bill::format_bill_date(&day);
+            let bill = self.download_bill(&bill_date, bill_type, retries)?;
+            bills.push((bill_date, bill));
+            day = day + time::Duration::days(1);
+        }
+        Ok(bills)
+    }
+
+    /// 下载 `bill_date` 当天成功支付的对账单, 并与商户系统的订单
+    /// (`out_trade_no` -> 金额【分】) 进行对比
+    pub fn reconcile(
+        &self,
+        bill_date: &str,
+        local: &HashMap<String, u32>,
+    ) -> Result<ReconcileReport, WechatpayError> {
+        let bill = self.download_bill(bill_date, BillType::Success, None)?;
+        Ok(reconcile_rows(&parse_bill(&bill)?, local))
+    }
+}
+
+/// [交易类型]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeType {
+    /// `MICRO`
+    Micro,
+    /// `JSAPI`
+    Jsapi,
+    /// `NATIVE`
+    Native,
+    Qrcode,
+    /// `APP` : app支付，统一下单接口trade_type的传参可参考这里
+    App,
+    /// `MWEB`: H5 支付, 必须传 `scene_info` (见 `H5Info`)
+    Mweb,
+}
+
+/// [交易金额]
+///
+/// 交易金额默认为人民币交易，接口中参数支付金额单位为【分】，参数值不能带小数。
+/// 对账单中的交易金额单位为【元】。
+/// 外币交易的支付金额精确到币种的最小单位，参数值不能带小数点。
+///
+/// 金额为负数、不是有限数或超出 `u32` 范围时返回 `WechatpayError::InvalidField`
+pub fn get_trade_amount(v: f32) -> Result<u32, WechatpayError> {
+    // FIXME:: 不同情况下的金额处理
+    let cents = (f64::from(v) * 100.0).round();
+    if !cents.is_finite() || cents < 0.0 || cents > f64::from(u32::MAX) {
+        return Err(WechatpayError::InvalidField {
+            field: "total_fee".to_string(),
+            reason: format!("amount {} is out of range", v),
+        });
+    }
+    Ok(cents as u32)
+}
+
+/// [时间]
+///
+/// 标准北京时间，时区为东八区；如果商户的系统时间为非标准北京时间。
+/// 参数值必须根据商户系统所在时区先换算成标准北京时间，
+/// 例如商户所在地为0时区的伦敦，当地时间为2014年11月11日0时0分0秒，
+/// 换算成北京时间为2014年11月11日8时0分0秒。
+///
+/// 系统时钟返回的时间超出范围时返回 `WechatpayError::InvalidField`
+pub fn get_time_str() -> Result<String, WechatpayError> {
+    // FIXME:: 如果是服务器在海外中国网站就会有问题
+    format_time_str(&time::now())
+}
+
+/// 按 `yyyyMMddHHmmss` 格式化
+///
+/// 直接格式化各字段而不使用 `strftime`, 字段超出范围时返回 `WechatpayError::InvalidField`
+fn format_time_str(tm: &time::Tm) -> Result<String, WechatpayError> {
+    let fields = [
+        ("tm_year", i64::from(tm.tm_year), -1900, 8099),
+        ("tm_mon", i64::from(tm.tm_mon), 0, 11),
+        ("tm_mday", i64::from(tm.tm_mday), 1, 31),
+        ("tm_hour", i64::from(tm.tm_hour), 0, 23),
+        ("tm_min", i64::from(tm.tm_min), 0, 59),
+        // 闰秒
+        ("tm_sec", i64::from(tm.tm_sec), 0, 60),
+    ];
+    for &(field, value, min, max) in &fields {
+        if value < min || value > max {
+            return Err(WechatpayError::InvalidField {
+                field: field.to_string(),
+                reason: format!("{} is out of range {}..={}", value, min, max),
+            });
+        }
+    }
+    Ok(format!(
+        "{}{:02}{:02}{:02}{:02}{:02}",
+        format_year(tm),
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    ))
+}
+
+/// `tm` 的年份, 至少 4 位
+fn format_year(tm: &time::Tm) -> String {
+    format!("{:04}", i64::from(tm.tm_year) + 1900)
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate time;
-    extern crate xml;
+/// [时间戳]
+///
+/// 标准北京时间，时区为东八区，自1970年1月1日 0点0分0秒以来的秒数。
+/// 注意：部分系统取到的值为毫秒级，需要转换成秒(10位数字)。
+pub fn get_timestamp() -> i64 {
+    time::get_time().sec
+}
+
+/// [生成随机数算法]
+///
+/// 微信支付API接口协议中包含字段nonce_str，主要保证签名不可预测。
+/// 我们推荐生成随机数算法如下：调用随机数函数生成，将得到的值转换为字符串。
+pub fn get_nonce_str() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// [商户订单号]
+///
+/// 商户支付的订单号由商户自定义生成，微信支付要求商户订单号保持唯一性
+/// （建议根据当前系统时间加随机序列来生成订单号）。
+/// 重新发起一笔支付要使用原订单号，避免重复支付；
+/// 已支付过或已调用关单、撤销（请见后文的API列表）的订单号不能重新发起支付。
+///
+/// 系统时钟返回的时间超出范围时返回 `WechatpayError::InvalidField`
+pub fn get_order_no() -> Result<String, WechatpayError> {
+    Ok(get_time_str()? + &((&get_nonce_str())[..18]))
+}
+
+/// [终端IP] `spbill_create_ip`
+///
+/// 从 HTTP 请求头中获取用户的真实 IP: 优先取 `X-Forwarded-For` 中最左边的公网地址,
+/// 其次取 `X-Real-IP`。内网、回环等地址会被跳过。
+pub fn client_ip_from_forwarded(headers: &[(String, String)]) -> Option<String> {
+    let values = |name: &str| {
+        headers
+            .iter()
+            .filter(|header| header.0.trim().eq_ignore_ascii_case(name))
+            .flat_map(|header| header.1.split(','))
+            .filter_map(parse_ip)
+            .collect::<Vec<IpAddr>>()
+    };
+    values("X-Forwarded-For")
+        .into_iter()
+        .chain(values("X-Real-IP"))
+        .find(is_public_ip)
+        .map(|ip| ip.to_string())
+}
+
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            // 100.64.0.0/10: 运营商级 NAT
+            let shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || shared)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // fc00::/7: 唯一本地地址, fe80::/10: 链路本地地址
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// 参与签名的非空字段的最少数量: 任何接口都至少有 `nonce_str` 和商户号等字段
+const MIN_SIGNED_FIELDS: usize = 2;
+
+/// 同 `get_sign`, 但参与签名的非空字段少于 2 个时返回 `WechatpayError::InvalidField`
+///
+/// 过滤掉空值后只剩 `key=...` 的签名一定是构造参数时出了错
+pub fn try_get_sign(
+    pairs: &BTreeMap<String, String>,
+    api_key: &str,
+) -> Result<String, WechatpayError> {
+    check_sign_fields(pairs)?;
+    Ok(get_sign(pairs, api_key))
+}
+
+fn check_sign_fields(pairs: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    let count = pairs
+        .iter()
+        .filter(|&(k, v)| k != "key" && k != "sign" && !v.is_empty())
+        .count();
+    if count < MIN_SIGNED_FIELDS {
+        return Err(WechatpayError::InvalidField {
+            field: "sign".to_string(),
+            reason: format!("only {} non-empty fields to sign", count),
+        });
+    }
+    Ok(())
+}
+
+/// 签名算法 (给请求参数签名)
+///
+/// 详见: 接口规则 > 安全规范
+pub fn get_sign(pairs: &BTreeMap<String, String>, api_key: &str) -> String {
+    get_sign_excluding(pairs, api_key, &[])
+}
+
+/// 签名算法, `exclude` 中的字段和 `key`, `sign` 一样不参与签名
+///
+/// 例如退款结果通知中的 `req_info`
+pub fn get_sign_excluding(
+    pairs: &BTreeMap<String, String>,
+    api_key: &str,
+    exclude: &[&str],
+) -> String {
+    api_key.sign(&sign_source(pairs, exclude), SignType::Md5)
+}
+
+/// 使用指定的签名类型签名
+pub fn get_sign_with(
+    pairs: &BTreeMap<String, String>,
+    api_key: &str,
+    sign_type: SignType,
+) -> String {
+    api_key.sign(&sign_source(pairs, &[]), sign_type)
+}
+
+/// 签名前的字符串 (`stringSignTemp`), 即 `k1=v1&k2=v2&...&key=api_key`
+///
+/// 用于排查签名错误: 与微信支付接口签名校验工具生成的字符串逐字对比,
+/// 常见的差异是字段名大小写、空值字段和多余的空白
+pub fn sign_source_string(pairs: &BTreeMap<String, String>, api_key: &str) -> String {
+    let source = sign_source(pairs, &[]);
+    if source.is_empty() {
+        format!("key={}", api_key)
+    } else {
+        format!("{}&key={}", source, api_key)
+    }
+}
+
+/// 参与签名的参数拼接成的 `k1=v1&k2=v2&...`, 不含密钥
+fn sign_source(pairs: &BTreeMap<String, String>, exclude: &[&str]) -> String {
+    // 如果参数的值为空不参与签名；
+    let mut signed = pairs
+        .iter()
+        .filter(|pair| {
+            pair.0.ne("key")
+                && pair.0.ne("sign")
+                && !exclude.contains(&pair.0.as_str())
+                && !pair.1.is_empty()
+        })
+        .collect::<Vec<(&String, &String)>>();
+    // 参数名ASCII码从小到大排序（字典序）: 显式按字节排序, 不依赖 map 的遍历顺序
+    signed.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    // 参数值使用原值, 不做 URL 编码
+    signed
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/// 响应中返回了 `total_fee` 时, 检查与请求中的金额是否一致
+fn check_total_fee(sent: &str, pairs: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    if let Some(received) = pairs.get("total_fee") {
+        if received.parse::<u64>().ok() != sent.parse::<u64>().ok() {
+            return Err(WechatpayError::AmountMismatch {
+                sent: sent.to_string(),
+                received: received.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// 整理扫码枪输入的授权码 (`auth_code`)
+///
+/// 扫码枪可能在末尾带上回车换行或输出全角数字, 这里将全角数字转换为半角,
+/// 并去掉其他所有非数字字符
+pub fn sanitize_auth_code(auth_code: &str) -> String {
+    auth_code
+        .chars()
+        .filter_map(|c| match c {
+            '0'..='9' => Some(c),
+            '０'..='９' => ::std::char::from_u32(c as u32 - '０' as u32 + '0' as u32),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 下单接口的 URL 和接口名
+fn pay_endpoint(trade_type: TradeType) -> (&'static str, &'static str) {
+    if trade_type == TradeType::Micro {
+        (MICROPAY_URL, "micropay")
+    } else {
+        (UNIFIEDORDER_URL, "unifiedorder")
+    }
+}
+
+/// 检查下单参数的字节长度 (文档中的长度限制按 UTF-8 字节计算, 一个汉字占 3 个字节)
+fn check_byte_lengths(params: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    for &(field, max) in [("body", 128), ("attach", 127), ("detail", 6000)].iter() {
+        if let Some(value) = params.get(field) {
+            if value.len() > max {
+                return Err(WechatpayError::InvalidField {
+                    field: field.to_string(),
+                    reason: format!("{} bytes exceeds the limit of {} bytes", value.len(), max),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `openid` 和 `sub_openid` 只能传一个
+fn check_openid_params(params: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    if params.contains_key("openid") && params.contains_key("sub_openid") {
+        return Err(WechatpayError::InvalidField {
+            field: "sub_openid".to_string(),
+            reason: "openid and sub_openid must not both be set".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// `profit_sharing` 只能为 `Y` 或 `N`
+fn check_profit_sharing_params(params: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    match params.get("profit_sharing").map(|s| s.as_str()) {
+        None | Some("Y") | Some("N") => Ok(()),
+        Some(value) => Err(WechatpayError::InvalidField {
+            field: "profit_sharing".to_string(),
+            reason: format!("{} is not Y or N", value),
+        }),
+    }
+}
+
+/// 检查 APP 支付的可选字段: `limit_pay`, `scene_info`, `ext_data`
+fn check_app_params(params: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    if let Some(limit_pay) = params.get("limit_pay") {
+        if limit_pay != "no_credit" {
+            return Err(WechatpayError::InvalidField {
+                field: "limit_pay".to_string(),
+                reason: format!("{} is not no_credit", limit_pay),
+            });
+        }
+    }
+    for field in ["scene_info", "ext_data"].iter() {
+        if let Some(value) = params.get(*field) {
+            if !json::is_json_object(value) {
+                return Err(WechatpayError::InvalidField {
+                    field: field.to_string(),
+                    reason: "not a JSON object".to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 检查 `scene_info` 的格式: H5 支付为 `h5_info`, 刷卡支付为 `store_info`
+fn check_scene_info(
+    params: &BTreeMap<String, String>,
+    trade_type: TradeType,
+) -> Result<(), WechatpayError> {
+    let expected = match trade_type {
+        TradeType::Mweb => "h5_info",
+        TradeType::Micro => "store_info",
+        _ => return Ok(()),
+    };
+    let value = match params.get("scene_info") {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    match json::object_keys(value) {
+        Some(ref keys) if keys.contains(&expected) => Ok(()),
+        Some(_) => Err(WechatpayError::InvalidField {
+            field: "scene_info".to_string(),
+            reason: format!("missing {}", expected),
+        }),
+        None => Err(WechatpayError::InvalidField {
+            field: "scene_info".to_string(),
+            reason: "not a JSON object".to_string(),
+        }),
+    }
+}
+
+/// 读取返回结果中的金额 (或数量) 字段
+fn parse_fee(pairs: &BTreeMap<String, String>, field: &str) -> Result<u64, WechatpayError> {
+    pairs
+        .get(field)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| WechatpayError::InvalidField {
+            field: field.to_string(),
+            reason: format!("{:?} is not a number", pairs.get(field)),
+        })
+}
+
+/// 检查 `notify_url` 是否为合法的 http(s) 地址, `require_https` 时只允许 https
+fn check_notify_url(notify_url: &str, require_https: bool) -> Result<(), WechatpayError> {
+    let invalid = |reason: String| WechatpayError::InvalidField {
+        field: "notify_url".to_string(),
+        reason,
+    };
+    match url::Url::parse(notify_url) {
+        Ok(ref url) if url.scheme() == "https" => Ok(()),
+        Ok(ref url) if url.scheme() == "http" => {
+            if require_https {
+                Err(invalid(format!("{} is not a https url", notify_url)))
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err(invalid(format!("{} is not a http(s) url", notify_url))),
+    }
+}
+
+/// 检查通信结果, `return_code` 不为 `SUCCESS` 时返回 `WechatpayError::Protocol`
+fn check_return(pairs: &BTreeMap<String, String>) -> Result<(), WechatpayError> {
+    match pairs.get("return_code") {
+        Some(code) if code != "SUCCESS" => Err(WechatpayError::Protocol(
+            pairs.get("return_msg").cloned().unwrap_or_default(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// 检查返回结果
+///
+/// `return_code` 为 `FAIL` 时返回 `WechatpayError::Protocol`,
+/// `result_code` 为 `FAIL` 时返回 `WechatpayError::Business`
+fn check_result(pairs: BTreeMap<String, String>) -> WechatpayResult {
+    check_return(&pairs)?;
+    if pairs.get("result_code").map(|s| s.as_str()) == Some("FAIL") {
+        return Err(WechatpayError::Business {
+            err_code: ErrCode::from_response(pairs.get("err_code").map_or("", |s| s.as_str())),
+            err_code_des: pairs.get("err_code_des").cloned().unwrap_or_default(),
+        });
+    }
+    Ok(pairs)
+}
+
+/// 校验网关返回数据的签名, 不区分大小写
+///
+/// 使用数据中 `sign_type` 指定的签名类型, 没有时使用 MD5
+pub fn verify_sign(pairs: &BTreeMap<String, String>, api_key: &str) -> bool {
+    verify_sign_with(pairs, api_key, SignType::Md5)
+}
+
+/// 校验签名, 数据中没有 `sign_type` 时使用 `default` (client 的签名类型)
+fn verify_sign_with<K: SigningKeyProvider + ?Sized>(
+    pairs: &BTreeMap<String, String>,
+    signer: &K,
+    default: SignType,
+) -> bool {
+    let sign_type = if pairs.contains_key("sign_type") {
+        SignType::from_params(pairs)
+    } else {
+        default
+    };
+    match pairs.get("sign") {
+        Some(sign) => sign.eq_ignore_ascii_case(&signer.sign(&sign_source(pairs, &[]), sign_type)),
+        None => false,
+    }
+}
+
+/// 校验接口 `url` 返回数据的签名
+///
+/// `return_code` 不是 `SUCCESS` 时网关不签名, 不做校验; 缺少 `sign` 时只有
+/// `UNSIGNED_URLS` 中的接口允许通过, 其他接口视为签名不正确
+fn check_response_sign<K: SigningKeyProvider + ?Sized>(
+    pairs: &BTreeMap<String, String>,
+    signer: &K,
+    url: &str,
+    sign_type: SignType,
+) -> Result<(), WechatpayError> {
+    if pairs.get("return_code").map(|s| s.as_str()) != Some("SUCCESS") {
+        return Ok(());
+    }
+    if !pairs.contains_key("sign") && UNSIGNED_URLS.contains(&url) {
+        return Ok(());
+    }
+    if verify_sign_with(pairs, signer, sign_type) {
+        Ok(())
+    } else {
+        Err(WechatpayError::SignatureMismatch)
+    }
+}
+
+/// 将`xml`数据解析成`BTreeMap`
+pub fn from_xml_str(data: &str) -> BTreeMap<String, String> {
+    let mut pairs = BTreeMap::new();
+    parse_xml_fields(data, |tag, value| {
+        pairs.insert(tag, value);
+    });
+    pairs
+}
+
+/// 将`xml`数据中每个重复的 `<item>` 元素分别解析成一个`BTreeMap` (按出现顺序)
+///
+/// 用于以重复元素而不是 `_$n` 后缀表示列表的响应, 如红包领取记录中的多个 `<hbinfo>`;
+/// 每条记录单独解析, 某条记录缺少的字段不会影响其他记录。普通响应使用 `from_xml_str`
+pub fn from_xml_str_multi(data: &str, item: &str) -> Vec<BTreeMap<String, String>> {
+    response::parse_xml_list(data, item).1
+}
+
+/// 依次以 (元素名, 文本) 调用 `field`, 只包含有文本的元素
+fn parse_xml_fields<F: FnMut(String, String)>(data: &str, mut field: F) {
+    let reader = xml::reader::EventReader::from_str(data);
+    // 当前元素和其中的文本; 注释或处理指令可能把文本分成多段, 结束标签时再保存,
+    // 结束标签之后的文本不属于任何字段
+    let mut tag: Option<String> = None;
+    let mut value: Option<String> = None;
+    for event in reader {
+        match event {
+            Ok(xml::reader::XmlEvent::StartElement { name, .. }) => {
+                tag = Some(name.local_name);
+                value = None;
+            }
+            Ok(xml::reader::XmlEvent::CData(text))
+            | Ok(xml::reader::XmlEvent::Characters(text))
+                if tag.is_some() =>
+            {
+                value.get_or_insert_with(String::new).push_str(&text);
+            }
+            Ok(xml::reader::XmlEvent::EndElement { .. }) => {
+                if let (Some(tag), Some(value)) = (tag.take(), value.take()) {
+                    field(tag, value);
+                }
+            }
+            Err(e) => {
+                println!("Parse xml error: {:?}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 将原始的`xml`数据解析成`BTreeMap`
+///
+/// 根据 BOM 或`xml`声明中的`encoding`解码, 默认为 UTF-8
+pub fn from_xml_bytes(data: &[u8]) -> Result<BTreeMap<String, String>, WechatpayError> {
+    Ok(from_xml_str(&decode_xml(data)?))
+}
+
+/// 将原始的`xml`数据解码为字符串
+fn decode_xml(data: &[u8]) -> Result<String, WechatpayError> {
+    let label = xml_declared_encoding(data).unwrap_or_else(|| "utf-8".to_string());
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| WechatpayError::Encoding(label.clone()))?;
+    let (text, _, had_errors) = encoding.decode(data);
+    if had_errors {
+        return Err(WechatpayError::Encoding(label));
+    }
+    Ok(text.into_owned())
+}
+
+/// 响应是 xml 数据, 但没有以根元素的结束标签结尾 (如连接在传输中断开)
+///
+/// 不是 xml 的数据 (如对账单) 不检查
+fn is_truncated_xml(data: &[u8]) -> bool {
+    let mut rest = data
+        .strip_prefix(b"\xEF\xBB\xBF")
+        .unwrap_or(data)
+        .trim_ascii_start();
+    if rest.starts_with(b"<?xml") {
+        rest = match rest.windows(2).position(|w| w == b"?>") {
+            Some(end) => rest[end + 2..].trim_ascii_start(),
+            None => return true,
+        };
+    }
+    if !rest.starts_with(b"<") {
+        return false;
+    }
+    let root_len = rest[1..]
+        .iter()
+        .position(|&b| b == b'>' || b == b'/' || b.is_ascii_whitespace())
+        .unwrap_or(rest.len() - 1);
+    let root = &rest[1..1 + root_len];
+    let end = rest.trim_ascii_end();
+    // 没有内容的根元素 `<xml/>`
+    if let Some(tag_end) = end.iter().position(|&b| b == b'>') {
+        if end[..tag_end].ends_with(b"/") {
+            return tag_end + 1 != end.len();
+        }
+    }
+    !(end.ends_with(b">")
+        && end[..end.len() - 1].ends_with(root)
+        && end[..end.len() - 1 - root.len()].ends_with(b"</"))
+}
+
+/// 读取`<?xml ... encoding="..."?>`中声明的编码
+fn xml_declared_encoding(data: &[u8]) -> Option<String> {
+    let data = if data.starts_with(b"\xEF\xBB\xBF") {
+        &data[3..]
+    } else {
+        data
+    };
+    if !data.starts_with(b"<?xml") {
+        return None;
+    }
+    let end = data.windows(2).position(|w| w == b"?>")?;
+    let declaration = String::from_utf8_lossy(&data[..end]).into_owned();
+    let value = declaration.split("encoding").nth(1)?;
+    let value = value.trim_start().trim_start_matches('=').trim_start();
+    let quote = value.chars().next()?;
+    if quote != '"' && quote != '\'' {
+        return None;
+    }
+    value[1..].split(quote).next().map(|s| s.to_string())
+}
+
+/// 使用`BTreeMap`生成`xml`数据
+pub fn to_xml_str(pairs: &BTreeMap<String, String>) -> String {
+    to_xml_str_with_root(pairs, "xml")
+}
+
+/// 使用`BTreeMap`生成根元素为 `root` 的`xml`数据; 微信支付的接口都使用 `xml`, 见 `to_xml_str`
+pub fn to_xml_str_with_root(pairs: &BTreeMap<String, String>, root: &str) -> String {
+    write_xml(pairs, root)
+}
+
+/// 按 `pairs` 的顺序写出根元素为 `root` 的`xml`数据, 所有文本都经过 `xml_text_chunks`
+fn write_xml<'a, I>(pairs: I, root: &str) -> String
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    let mut target: Vec<u8> = Vec::new();
+    {
+        let mut writer = xml::writer::EmitterConfig::new()
+            .write_document_declaration(false)
+            .perform_indent(false)
+            .create_writer(&mut target);
+        let _ = writer.write(events::XmlEvent::start_element(root));
+        for (key, value) in pairs {
+            let _ = writer.write(events::XmlEvent::start_element(key.as_ref()));
+            for text in xml_text_chunks(value) {
+                let _ = writer.write(events::XmlEvent::characters(text));
+            }
+            let _ = writer.write(events::XmlEvent::end_element());
+        }
+        let _ = writer.write(events::XmlEvent::end_element());
+    }
+    String::from_utf8(target).unwrap()
+}
+
+/// 在每个 `<` 和 `&` 之前切分文本
+///
+/// xml-rs 0.3 转义时把字符序号当作字节下标, 需要转义的字符之前有汉字等多字节字符时
+/// 会写出错误的内容甚至 panic; 切分后需要转义的字符只出现在每段的开头, 不受影响
+fn xml_text_chunks(value: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for (i, _) in value.match_indices(['<', '&']) {
+        if i > start {
+            chunks.push(&value[start..i]);
+            start = i;
+        }
+    }
+    chunks.push(&value[start..]);
+    chunks
+}
+
+/// 生成规范形式的`xml`数据: 字段按名称的字节序排列, 没有多余的空白
+///
+/// 相同的字段总是生成完全相同的字节, 可以用于哈希或快照对比请求数据;
+/// `pairs` 可以是任意的 map (如 `HashMap`)
+pub fn to_canonical_xml_str<'a, I>(pairs: I) -> String
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    let mut sorted = pairs.into_iter().collect::<Vec<(&String, &String)>>();
+    sorted.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    write_xml(sorted, "xml")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+    extern crate xml;
+
+    use std::collections::{BTreeMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+
+    use xml::reader::{EventReader, XmlEvent};
+
+    /// 请求记录: (url, body, cert)
+    pub type MockRequest = (String, String, Option<String>);
+
+    /// 模拟微信支付网关: 按顺序返回预设的响应, 并记录收到的请求
+    ///
+    /// 和网关一样给 `return_code` 为 `SUCCESS` 且没有 `sign` 的响应签名 (见 `sign_mock_response`)
+    #[derive(Clone, Default)]
+    pub struct MockTransport {
+        responses: Arc<Mutex<VecDeque<(u32, String)>>>,
+        requests: Arc<Mutex<Vec<MockRequest>>>,
+        unsigned: bool,
+    }
+
+    impl MockTransport {
+        pub fn new(responses: Vec<(u32, &str)>) -> MockTransport {
+            let mock = MockTransport::default();
+            for (status, body) in responses {
+                mock.push(status, body);
+            }
+            mock
+        }
+
+        /// 原样返回响应, 不补充签名
+        pub fn unsigned(responses: Vec<(u32, &str)>) -> MockTransport {
+            MockTransport {
+                unsigned: true,
+                ..MockTransport::new(responses)
+            }
+        }
+
+        pub fn push(&self, status: u32, body: &str) {
+            self.responses
+                .lock()
+                .unwrap()
+                .push_back((status, body.to_string()));
+        }
+
+        pub fn requests(&self) -> Vec<MockRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// 收到的请求参数
+        pub fn request_params(&self) -> Vec<BTreeMap<String, String>> {
+            self.requests()
+                .iter()
+                .map(|request| parse_xml_characters(&request.1))
+                .collect()
+        }
+    }
+
+    impl ::Transport for MockTransport {
+        fn post(
+            &self,
+            url: &str,
+            body: &str,
+            cert: Option<&str>,
+        ) -> Result<(u32, Vec<u8>), ::WechatpayError> {
+            self.requests.lock().unwrap().push((
+                url.to_string(),
+                body.to_string(),
+                cert.map(|s| s.to_string()),
+            ));
+            match self.responses.lock().unwrap().pop_front() {
+                Some((status, response)) if self.unsigned => Ok((status, response.into_bytes())),
+                Some((status, response)) => {
+                    Ok((status, sign_mock_response(&response, body).into_bytes()))
+                }
+                None => Err(::WechatpayError::Request),
+            }
+        }
+    }
+
+    /// 使用测试密钥给 `<xml>...</xml>` 响应签名, 签名类型与请求 `request` 相同;
+    /// 已有 `sign` 或 `return_code` 不是 `SUCCESS` 的响应不变
+    fn sign_mock_response(response: &str, request: &str) -> String {
+        if !response.starts_with("<xml>")
+            || !response.ends_with("</xml>")
+            || response.contains("<sign>")
+        {
+            return response.to_string();
+        }
+        let pairs = ::from_xml_str(response);
+        if pairs.get("return_code").map(|s| s.as_str()) != Some("SUCCESS") {
+            return response.to_string();
+        }
+        let sign_type = ::SignType::from_params(&parse_xml_characters(request));
+        let sign = ::get_sign_with(&pairs, "192006250b4c09247ec02edce69f6a2d", sign_type);
+        format!(
+            "{}<sign>{}</sign></xml>",
+            &response[..response.len() - "</xml>".len()],
+            sign
+        )
+    }
+
+    /// 解析 `to_xml_str` 生成的请求数据
+    fn parse_xml_characters(data: &str) -> BTreeMap<String, String> {
+        let mut pairs = BTreeMap::new();
+        let mut tag = "".to_string();
+        for event in EventReader::from_str(data) {
+            match event {
+                Ok(XmlEvent::StartElement { name, .. }) => tag = name.local_name,
+                Ok(XmlEvent::Characters(s)) => {
+                    pairs.insert(tag.clone(), s);
+                }
+                _ => {}
+            }
+        }
+        pairs
+    }
+
+    pub fn test_client() -> ::WechatpayClient {
+        ::WechatpayClient::new(
+            "wxd930ea5d5a258f4f",
+            "10000100",
+            "192006250b4c09247ec02edce69f6a2d",
+            "https://example.com/notify",
+            "/path/to/apiclient_cert.pem",
+        )
+    }
+
+    /// 生成网关响应
+    pub fn response_xml(pairs: &[(&str, &str)]) -> String {
+        let mut xml = "<xml>".to_string();
+        for &(k, v) in pairs {
+            xml.push_str(&format!("<{0}><![CDATA[{1}]]></{0}>", k, v));
+        }
+        xml.push_str("</xml>");
+        xml
+    }
+
+    /// 生成使用测试密钥签名的网关响应
+    pub fn signed_response_xml(pairs: &[(&str, &str)]) -> String {
+        let mut map = BTreeMap::new();
+        for &(k, v) in pairs {
+            map.insert(k.to_string(), v.to_string());
+        }
+        let sign = ::get_sign(&map, "192006250b4c09247ec02edce69f6a2d");
+        let mut pairs = pairs.to_vec();
+        pairs.push(("sign", &sign));
+        response_xml(&pairs)
+    }
+
+    fn business_fail(err_code: &str) -> String {
+        response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "FAIL"),
+            ("err_code", err_code),
+        ])
+    }
+
+    #[test]
+    fn test_from_xml_str_plain_text() {
+        // 网关返回的数字字段 (如 `total_fee`, `refund_count`) 不使用 CDATA
+        let source = "<xml>\
+                      <return_code><![CDATA[SUCCESS]]></return_code>\
+                      <total_fee>100</total_fee>\
+                      <refund_count>1</refund_count>\
+                      <refund_fee_0>30</refund_fee_0>\
+                      <attach>a &amp; b</attach>\
+                      </xml>";
+        let pairs = ::from_xml_str(source);
+        assert_eq!(pairs["return_code"], "SUCCESS");
+        assert_eq!(pairs["total_fee"], "100");
+        assert_eq!(pairs["refund_count"], "1");
+        assert_eq!(pairs["refund_fee_0"], "30");
+        assert_eq!(pairs["attach"], "a & b");
+        assert_eq!(pairs.len(), 5);
+    }
+
+    #[test]
+    fn test_from_xml_str_comments() {
+        let source = "<xml>\
+                      <return_code><![CDATA[SUCCESS]]></return_code>\
+                      <out_trade_no><!-- injected -->1415757673</out_trade_no>\
+                      <total_fee>12<!-- split -->50</total_fee>\
+                      <?proxy checked?>\
+                      <attach><?proxy checked?><![CDATA[订单额外描述]]></attach>\
+                      stray\
+                      <!-- trailing --></xml>";
+        let pairs = ::from_xml_str(source);
+        assert_eq!(pairs.get("return_code").unwrap(), "SUCCESS");
+        assert_eq!(pairs.get("out_trade_no").unwrap(), "1415757673");
+        assert_eq!(pairs.get("total_fee").unwrap(), "1250");
+        assert_eq!(pairs.get("attach").unwrap(), "订单额外描述");
+        assert_eq!(pairs.len(), 4, "{:?}", pairs);
+    }
+
+    #[test]
+    fn test_from_xml_str_multi() {
+        let source = "<xml>\
+                      <return_code><![CDATA[SUCCESS]]></return_code>\
+                      <hblist>\
+                      <hbinfo><openid><![CDATA[oHkLxtzmyHXX6FW_cAWo_orTSRXs]]></openid>\
+                      <amount>100</amount></hbinfo>\
+                      <hbinfo><openid><![CDATA[oHkLxt_htg84TUEbzvlMwQzVDBqo]]></openid>\
+                      <amount>200</amount></hbinfo>\
+                      <hbinfo><openid><![CDATA[oHkLxtzmyHXX6FW_cAWo_orTSRXs]]></openid>\
+                      <amount>300</amount></hbinfo>\
+                      <hbinfo><amount>400</amount></hbinfo>\
+                      </hblist></xml>";
+        let records = ::from_xml_str_multi(source, "hbinfo");
+        assert_eq!(records.len(), 4);
+        let amounts: Vec<&str> = records.iter().map(|r| r["amount"].as_str()).collect();
+        assert_eq!(amounts, vec!["100", "200", "300", "400"]);
+        assert_eq!(records[1]["openid"], "oHkLxt_htg84TUEbzvlMwQzVDBqo");
+        assert_eq!(records[2]["openid"], "oHkLxtzmyHXX6FW_cAWo_orTSRXs");
+        // 缺少字段的记录不会和其他记录错位
+        assert!(!records[3].contains_key("openid"));
+        assert!(records.iter().all(|r| !r.contains_key("return_code")));
+        // 单值解析只保留最后一个
+        assert_eq!(::from_xml_str(source)["amount"], "400");
+    }
+
+    #[test]
+    fn test_from_xml_str() {
+        let source = r#"
+<xml>
+   <return_code><![CDATA[SUCCESS]]></return_code>
+   <return_msg><![CDATA[OK]]></return_msg>
+   <appid><![CDATA[wx2421b1c4370ec43b]]></appid>
+   <mch_id><![CDATA[10000100]]></mch_id>
+   <device_info><![CDATA[1000]]></device_info>
+   <nonce_str><![CDATA[TN55wO9Pba5yENl8]]></nonce_str>
+   <sign><![CDATA[BDF0099C15FF7BC6B1585FBB110AB635]]></sign>
+   <result_code><![CDATA[SUCCESS]]></result_code>
+   <openid><![CDATA[oUpF8uN95-Ptaags6E_roPHg7AG0]]></openid>
+   <is_subscribe><![CDATA[Y]]></is_subscribe>
+   <trade_type><![CDATA[APP]]></trade_type>
+   <bank_type><![CDATA[CCB_DEBIT]]></bank_type>
+   <total_fee>1</total_fee>
+   <fee_type><![CDATA[CNY]]></fee_type>
+   <transaction_id><![CDATA[1008450740201411110005820873]]></transaction_id>
+   <out_trade_no><![CDATA[1415757673]]></out_trade_no>
+   <attach><![CDATA[订单额外描述]]></attach>
+   <time_end><![CDATA[20141111170043]]></time_end>
+   <trade_state><![CDATA[SUCCESS]]></trade_state>
+</xml>
+"#;
+        let pairs = ::from_xml_str(source);
+        for &(k, v) in [
+            ("return_code", "SUCCESS"),
+            ("return_msg", "OK"),
+            ("appid", "wx2421b1c4370ec43b"),
+            ("mch_id", "10000100"),
+            ("result_code", "SUCCESS"),
+            ("attach", "订单额外描述"),
+            ("transaction_id", "1008450740201411110005820873"),
+            ("time_end", "20141111170043"),
+            ("trade_type", "APP"),
+            ("total_fee", "1"),
+        ]
+        .iter()
+        {
+            assert_eq!(pairs.get(k), Some(&v.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_from_xml_bytes() {
+        let mut data = b"\xEF\xBB\xBF".to_vec();
+        data.extend_from_slice(
+            "<xml><return_code><![CDATA[SUCCESS]]></return_code>\
+             <attach><![CDATA[订单额外描述]]></attach></xml>"
+                .as_bytes(),
+        );
+        assert!(::std::str::from_utf8(&data)
+            .map(::from_xml_str)
+            .unwrap()
+            .is_empty());
+        let pairs = ::from_xml_bytes(&data).unwrap();
+        assert_eq!(pairs.get("return_code").unwrap(), "SUCCESS");
+        assert_eq!(pairs.get("attach").unwrap(), "订单额外描述");
+
+        // GBK 编码的 "测试"
+        let mut data = b"<?xml version=\"1.0\" encoding=\"GBK\"?><xml><body><![CDATA[".to_vec();
+        data.extend_from_slice(&[0xB2, 0xE2, 0xCA, 0xD4]);
+        data.extend_from_slice(b"]]></body></xml>");
+        assert!(String::from_utf8(data.clone()).is_err());
+        let pairs = ::from_xml_bytes(&data).unwrap();
+        assert_eq!(pairs.get("body").unwrap(), "测试");
+
+        match ::from_xml_bytes(b"<xml><body><![CDATA[\xFF]]></body></xml>") {
+            Err(::WechatpayError::Encoding(label)) => assert_eq!(label, "utf-8"),
+            _ => panic!("expected an encoding error"),
+        }
+    }
+
+    fn check_xml_str(pairs: &BTreeMap<String, String>, data: &str) {
+        let reader = EventReader::from_str(data);
+        let mut tag: String = "".to_string();
+        for event in reader {
+            match event {
+                Ok(XmlEvent::StartElement { name, .. }) => {
+                    tag = name.local_name;
+                }
+                Ok(XmlEvent::Characters(s)) => {
+                    assert_eq!(Some(&s), pairs.get(&tag));
+                }
+                Err(e) => {
+                    panic!("Parse error: {:?}", e);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_canonical_xml_str() {
+        use std::collections::HashMap;
+
+        let fields = [
+            ("total_fee", "1"),
+            ("body", "APP支付测试"),
+            ("attach", "a<b&c"),
+            ("appid", "wx2421b1c4370ec43b"),
+        ];
+        let mut ordered = BTreeMap::new();
+        let mut hashed = HashMap::new();
+        for &(k, v) in fields.iter() {
+            ordered.insert(k.to_string(), v.to_string());
+        }
+        for &(k, v) in fields.iter().rev() {
+            hashed.insert(k.to_string(), v.to_string());
+        }
+        let canonical = ::to_canonical_xml_str(&ordered);
+        assert_eq!(canonical, ::to_canonical_xml_str(&hashed));
+        assert_eq!(
+            canonical,
+            "<xml><appid>wx2421b1c4370ec43b</appid><attach>a&lt;b&amp;c</attach>\
+             <body>APP支付测试</body><total_fee>1</total_fee></xml>"
+        );
+
+        // 汉字之后的 `&`, `<`
+        let mut pairs = BTreeMap::new();
+        pairs.insert("attach".to_string(), "测试&".to_string());
+        pairs.insert("body".to_string(), "测试<商品>&".to_string());
+        assert_eq!(
+            ::to_canonical_xml_str(&pairs),
+            "<xml><attach>测试&amp;</attach><body>测试&lt;商品>&amp;</body></xml>"
+        );
+    }
+
+    #[test]
+    fn test_to_xml_str() {
+        let output = r#"
+<xml>
+   <appid>wx2421b1c4370ec43b</appid>
+   <attach>支付测试</attach>
+   <body>APP支付测试</body>
+   <mch_id>10000100</mch_id>
+   <nonce_str>1add1a30ac87aa2db72f57a2375d8fec</nonce_str>
+   <notify_url>http://wxpay.weixin.qq.com/pub_v2/pay/notify.v2.php</notify_url>
+   <out_trade_no>1415659990</out_trade_no>
+   <spbill_create_ip>14.23.150.211</spbill_create_ip>
+   <total_fee>1</total_fee>
+   <trade_type>APP</trade_type>
+   <sign>0CB01533B8C1EF103065174F50BCA001</sign>
+</xml>
+"#;
+        let mut pairs = BTreeMap::new();
+        for &(k, v) in [
+            ("appid", "wx2421b1c4370ec43b"),
+            ("attach", "支付测试"),
+            ("body", "APP支付测试"),
+            ("mch_id", "10000100"),
+            ("nonce_str", "1add1a30ac87aa2db72f57a2375d8fec"),
+            (
+                "notify_url",
+                "http://wxpay.weixin.qq.com/pub_v2/pay/notify.v2.php",
+            ),
+            ("out_trade_no", "1415659990"),
+            ("spbill_create_ip", "14.23.150.211"),
+            ("total_fee", "1"),
+            ("trade_type", "APP"),
+            ("sign", "0CB01533B8C1EF103065174F50BCA001"),
+        ]
+        .iter()
+        {
+            pairs.insert(k.to_string(), v.to_string());
+        }
+
+        check_xml_str(&pairs, output);
+        check_xml_str(&pairs, &(::to_xml_str(&pairs)));
+    }
+
+    #[test]
+    fn test_to_xml_str_with_root() {
+        let mut pairs = BTreeMap::new();
+        pairs.insert("appid".to_string(), "wx2421b1c4370ec43b".to_string());
+        pairs.insert("body".to_string(), "<测试>".to_string());
+        let xml = ::to_xml_str_with_root(&pairs, "request");
+        assert_eq!(
+            xml,
+            "<request><appid>wx2421b1c4370ec43b</appid><body>&lt;测试></body></request>"
+        );
+        assert_eq!(::from_xml_str(&xml), pairs);
+        assert!(::to_xml_str(&pairs).starts_with("<xml><appid>"));
+
+        // 汉字之后的 `<` 和 `&`
+        pairs.insert("attach".to_string(), "测试&a<b>&&".to_string());
+        assert_eq!(::from_xml_str(&::to_xml_str(&pairs)), pairs);
+        assert_eq!(
+            ::xml_text_chunks("测试&a<b>&&"),
+            vec!["测试", "&a", "<b>", "&", "&"]
+        );
+        assert_eq!(::xml_text_chunks(""), vec![""]);
+    }
+
+    #[test]
+    fn test_trade_amount() {
+        assert_eq!(::get_trade_amount(0.99).unwrap(), 99_u32);
+        assert_eq!(::get_trade_amount(0.999).unwrap(), 100_u32);
+        assert_eq!(::get_trade_amount(3.3).unwrap(), 330_u32);
+        assert_eq!(::get_trade_amount(20_f32).unwrap(), 2000_u32);
+    }
+
+    #[test]
+    fn test_trade_amount_overflow() {
+        assert_eq!(::get_trade_amount(0.0).unwrap(), 0);
+        assert_eq!(::get_trade_amount(42_949_672.0).unwrap(), 4_294_967_200);
+        for &v in [42_949_676.0, 50_000_000.0, -0.01, f32::NAN, f32::INFINITY].iter() {
+            match ::get_trade_amount(v) {
+                Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "total_fee"),
+                _ => panic!("expected an invalid field error for {}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_nonce_request() {
+        use NonceGenerator;
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        for _ in 0..2 {
+            test_client()
+                .with_transport(mock.clone())
+                .with_nonce_generator(::SeededNonce::new(7))
+                .query_order(::OrderIdentifier::OutTradeNo("1415659990".to_string()))
+                .unwrap();
+        }
+        let requests = mock.requests();
+        assert_eq!(requests[0].1, requests[1].1);
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent["nonce_str"], ::SeededNonce::new(7).nonce_str());
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+    }
+
+    #[test]
+    fn test_format_time_str() {
+        let tm = ::time::strptime("20141111080000", "%Y%m%d%H%M%S").unwrap();
+        assert_eq!(::format_time_str(&tm).unwrap(), "20141111080000");
+        // 字段超出范围时返回错误而不是 panic 或生成错误的时间
+        let mut tm = ::time::empty_tm();
+        tm.tm_mday = 1;
+        assert_eq!(::format_time_str(&tm).unwrap(), "19000101000000");
+        tm.tm_year = i32::MAX;
+        match ::format_time_str(&tm) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "tm_year"),
+            other => panic!("unexpected {:?}", other),
+        }
+        tm.tm_year = 0;
+        tm.tm_mon = 12;
+        assert!(::format_time_str(&tm).is_err());
+        match ::format_time_str(&::time::empty_tm()) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "tm_mday"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_string_length() {
+        assert_eq!(format!("{}", ::get_timestamp()).len(), 10);
+        assert_eq!(::get_time_str().unwrap().len(), 14);
+        assert_eq!(::get_nonce_str().len(), 32);
+        assert_eq!(::get_order_no().unwrap().len(), 32);
+    }
+
+    fn business_error(err_code: &str) -> ::WechatpayError {
+        ::WechatpayError::Business {
+            err_code: ::ErrCode::from_response(err_code),
+            err_code_des: "".to_string(),
+        }
+    }
+
+    /// 依次返回 `responses`, `Err` 为 curl 错误码
+    struct FlakyTransport {
+        responses: Mutex<VecDeque<Result<&'static str, i32>>>,
+        attempts: Arc<Mutex<u32>>,
+    }
+
+    impl ::Transport for FlakyTransport {
+        fn post(
+            &self,
+            _url: &str,
+            _body: &str,
+            _cert: Option<&str>,
+        ) -> Result<(u32, Vec<u8>), ::WechatpayError> {
+            *self.attempts.lock().unwrap() += 1;
+            match self.responses.lock().unwrap().pop_front() {
+                Some(Ok(body)) => Ok((200, body.as_bytes().to_vec())),
+                Some(Err(code)) => Err(::curl::Error::new(code as _).into()),
+                None => Err(::WechatpayError::Request),
+            }
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_curl_error() {
+        let success = "<xml><return_code>SUCCESS</return_code></xml>";
+        let request = |responses: Vec<Result<&'static str, i32>>, retries| {
+            let attempts = Arc::new(Mutex::new(0));
+            let client = test_client().with_transport(FlakyTransport {
+                responses: Mutex::new(responses.into_iter().collect()),
+                attempts: attempts.clone(),
+            });
+            let mut params = BTreeMap::new();
+            params.insert("appid".to_string(), "wxd930ea5d5a258f4f".to_string());
+            params.insert("mch_id".to_string(), "10000100".to_string());
+            let result = client.request_raw(::ORDERQUERY_URL, params, retries, false);
+            let attempts = *attempts.lock().unwrap();
+            (result, attempts)
+        };
+
+        // CURLE_RECV_ERROR 后重试成功
+        let (result, attempts) = request(vec![Err(56), Ok(success)], Some(3));
+        assert_eq!(result.unwrap(), success.as_bytes());
+        assert_eq!(attempts, 2);
+
+        // CURLE_COULDNT_RESOLVE_HOST 用完重试次数后返回 curl 错误
+        let (result, attempts) = request(vec![Err(6), Err(6)], Some(2));
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(attempts, 2);
+
+        // CURLE_PEER_FAILED_VERIFICATION 不重试
+        let (result, attempts) = request(vec![Err(60), Ok(success)], Some(3));
+        match result {
+            Err(::WechatpayError::Curl(ref e)) => assert_eq!(e.code(), 60),
+            _ => panic!("expected a curl error"),
+        }
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_incomplete_response() {
+        for data in [
+            "<xml><return_code>SUCCESS</return_code><result_co",
+            "<xml><return_code><![CDATA[SUCCESS]]></return_code>",
+            "<?xml version=\"1.0\"?>\n<xml><return_code>SUCCESS</return_code></xm",
+            "<?xml version=\"1.0\"",
+        ]
+        .iter()
+        {
+            assert!(::is_truncated_xml(data.as_bytes()), "{}", data);
+        }
+        for data in [
+            "<xml><return_code>SUCCESS</return_code></xml>\n",
+            "\u{feff}<?xml version=\"1.0\" encoding=\"UTF-8\"?> <xml a=\"1\"></xml>",
+            "<xml/>",
+            "交易时间,公众账号ID,商户号\n",
+            "",
+        ]
+        .iter()
+        {
+            assert!(!::is_truncated_xml(data.as_bytes()), "{}", data);
+        }
+
+        let truncated = "<xml><return_code><![CDATA[SUCCESS]]></return_code><result_code><![CDA";
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, truncated)]);
+        let id = || ::OrderIdentifier::OutTradeNo("1415659990".to_string());
+        match test_client().with_transport(mock.clone()).query_order(id()) {
+            Err(::WechatpayError::IncompleteResponse) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // 不完整的响应会在重试次数内重新请求
+        let mock = MockTransport::unsigned(vec![(200, truncated), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let mut params = BTreeMap::new();
+        params.insert("out_trade_no".to_string(), "1415659990".to_string());
+        params.insert("appid".to_string(), "wxd930ea5d5a258f4f".to_string());
+        let data = client
+            .request_raw(::ORDERQUERY_URL, params, Some(2), false)
+            .unwrap();
+        assert_eq!(data, success.as_bytes());
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_bank_type() {
+        use PaymentMethodCategory::*;
+
+        let category = |code: &str| ::BankType::from_response(code).payment_method_category();
+        assert_eq!(category("CCB_DEBIT"), Debit);
+        assert_eq!(category("ICBC_CREDIT"), Credit);
+        assert_eq!(category("OTHERS"), Other);
+        assert_eq!(
+            ::BankType::from_response("CCB_DEBIT").to_string(),
+            "CCB_DEBIT"
+        );
+
+        let mut pairs = BTreeMap::new();
+        pairs.insert("bank_type".to_string(), "ICBC_CREDIT".to_string());
+        let outcome = ::MicropayOutcome::from_result(Ok(pairs));
+        assert_eq!(outcome.bank_type().unwrap().code(), "ICBC_CREDIT");
+        let outcome = ::MicropayOutcome::from_result(Err(::WechatpayError::Request));
+        assert!(outcome.bank_type().is_none());
+    }
+
+    #[test]
+    fn test_business_error_classification() {
+        let err = business_error("SYSTEMERROR");
+        assert!(err.is_unknown_state());
+        assert!(!err.is_retryable());
+        assert!(!err.is_terminal());
+
+        let err = business_error("BANKERROR");
+        assert!(err.is_retryable());
+        assert!(!err.is_unknown_state());
+
+        for code in ["ORDERPAID", "OUT_TRADE_NO_USED", "NOTENOUGH"].iter() {
+            let err = business_error(code);
+            assert!(err.is_terminal());
+            assert!(!err.is_retryable());
+            assert!(!err.is_unknown_state());
+        }
+
+        let err = business_error("SOMETHING_NEW");
+        assert!(!err.is_terminal() && !err.is_retryable() && !err.is_unknown_state());
+        assert!(!::WechatpayError::Request.is_retryable());
+    }
+
+    #[test]
+    fn test_check_result() {
+        let mut pairs = BTreeMap::new();
+        pairs.insert("return_code".to_string(), "SUCCESS".to_string());
+        pairs.insert("result_code".to_string(), "SUCCESS".to_string());
+        assert!(::check_result(pairs.clone()).is_ok());
+
+        pairs.insert("result_code".to_string(), "FAIL".to_string());
+        pairs.insert("err_code".to_string(), "ORDERPAID".to_string());
+        match ::check_result(pairs) {
+            Err(::WechatpayError::Business { err_code, .. }) => assert_eq!(err_code, "ORDERPAID"),
+            _ => panic!("expected a business error"),
+        }
+    }
+
+    #[test]
+    fn test_protocol_error() {
+        let mock = MockTransport::new(vec![
+            (
+                200,
+                &response_xml(&[("return_code", "FAIL"), ("return_msg", "签名错误")]),
+            ),
+            (200, &business_fail("ORDERPAID")),
+        ]);
+        let client = test_client().with_transport(mock);
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+
+        match client.query_order(id()) {
+            Err(::WechatpayError::Protocol(msg)) => assert_eq!(msg, "签名错误"),
+            _ => panic!("expected a protocol error"),
+        }
+        match client.query_order(id()) {
+            Err(::WechatpayError::Business { err_code, .. }) => assert_eq!(err_code, "ORDERPAID"),
+            _ => panic!("expected a business error"),
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_client_ip_from_forwarded() {
+        let ip = ::client_ip_from_forwarded(&headers(&[("X-Forwarded-For", "14.23.150.211")]));
+        assert_eq!(ip, Some("14.23.150.211".to_string()));
+
+        let ip = ::client_ip_from_forwarded(&headers(&[
+            ("Host", "example.com"),
+            (
+                "x-forwarded-for",
+                "10.0.0.1, 192.168.1.2, 14.23.150.211:5678, 58.60.1.1",
+            ),
+        ]));
+        assert_eq!(ip, Some("14.23.150.211".to_string()));
+
+        let ip = ::client_ip_from_forwarded(&headers(&[
+            ("X-Forwarded-For", "127.0.0.1, 172.16.0.3, 100.64.1.1"),
+            ("X-Real-IP", "58.60.1.1"),
+        ]));
+        assert_eq!(ip, Some("58.60.1.1".to_string()));
+
+        let ip = ::client_ip_from_forwarded(&headers(&[
+            ("X-Forwarded-For", "unknown, fd00::1"),
+            ("X-Real-IP", "10.1.1.1"),
+        ]));
+        assert_eq!(ip, None);
+        assert_eq!(::client_ip_from_forwarded(&[]), None);
+    }
+
+    #[test]
+    fn test_transport() {
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_state", "SUCCESS"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let id = ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+        let pairs = client.query_order(id).unwrap();
+        assert_eq!(pairs.get("trade_state").unwrap(), "SUCCESS");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, ::ORDERQUERY_URL);
+        assert_eq!(requests[0].2, None);
+        let params = mock.request_params();
+        assert_eq!(params[0].get("out_trade_no").unwrap(), "1415757673");
+        assert!(params[0].contains_key("sign"));
+
+        let mock = MockTransport::new(vec![(200, &business_fail("ORDERNOTEXIST"))]);
+        let client = test_client().with_transport(mock);
+        let id = ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+        match client.query_order(id) {
+            Err(::WechatpayError::Business { err_code, .. }) => {
+                assert_eq!(err_code, "ORDERNOTEXIST")
+            }
+            _ => panic!("expected a business error"),
+        }
+
+        // 非 200 状态码和传输层的错误
+        for mock in [
+            MockTransport::new(vec![(500, "")]),
+            MockTransport::new(vec![]),
+        ] {
+            let client = test_client().with_transport(mock);
+            let id = ::OrderIdentifier::TransactionId("1009660380201506130728806387".to_string());
+            assert!(client.query_order(id).is_err());
+        }
+    }
+
+    #[test]
+    fn test_close_order() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client.close_order("1415757673", None).unwrap());
+        let params = mock.request_params();
+        assert_eq!(mock.requests()[0].0, ::CLOSEORDER_URL);
+        assert_eq!(params[0].get("out_trade_no").unwrap(), "1415757673");
+        assert!(params[0].contains_key("sign"));
+
+        let mock = MockTransport::new(vec![(200, &business_fail("ORDERCLOSED"))]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(!client.close_order("1415757673", None).unwrap());
+
+        let mock = MockTransport::new(vec![(200, &business_fail("SYSTEMERROR")), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client.close_order("1415757673", Some(3)).unwrap());
+        assert_eq!(mock.requests().len(), 2);
+
+        let mock = MockTransport::new(vec![(200, &business_fail("SYSTEMERROR"))]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client
+            .close_order("1415757673", None)
+            .unwrap_err()
+            .is_unknown_state());
+
+        let mock = MockTransport::new(vec![(200, &business_fail("ORDERPAID")), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client
+            .close_order("1415757673", Some(3))
+            .unwrap_err()
+            .is_terminal());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_query_coupon_stock() {
+        let mock = MockTransport::new(vec![(
+            200,
+            &response_xml(&[
+                ("return_code", "SUCCESS"),
+                ("result_code", "SUCCESS"),
+                ("coupon_stock_id", "1757"),
+                ("coupon_stock_status", "4"),
+            ]),
+        )]);
+        let client = test_client().with_transport(mock.clone());
+        let pairs = client.query_coupon_stock("1757").unwrap();
+        assert_eq!(pairs.get("coupon_stock_status").unwrap(), "4");
+
+        let requests = mock.requests();
+        assert_eq!(requests[0].0, ::QUERY_COUPON_STOCK_URL);
+        let params = &mock.request_params()[0];
+        assert_eq!(params.get("coupon_stock_id").unwrap(), "1757");
+        assert_eq!(params.get("op_user_id").unwrap(), "10000100");
+
+        match client.query_coupon_stock("") {
+            Err(::WechatpayError::MissingField { operation, field }) => {
+                assert_eq!(operation, "query_coupon_stock");
+                assert_eq!(field, "coupon_stock_id");
+            }
+            _ => panic!("expected a missing field error"),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_query_coupons_info() {
+        let mock = MockTransport::new(vec![(
+            200,
+            &response_xml(&[
+                ("return_code", "SUCCESS"),
+                ("result_code", "SUCCESS"),
+                ("coupon_id", "1565"),
+                ("coupon_state", "2"),
+                ("coupon_value", "100"),
+            ]),
+        )]);
+        let client = test_client().with_transport(mock.clone());
+        let pairs = client
+            .query_coupons_info("1565", "onqOjjrXT-776SpHnfexGm1_P7iE", "58818")
+            .unwrap();
+        assert_eq!(pairs.get("coupon_state").unwrap(), "2");
+        assert_eq!(pairs.get("coupon_value").unwrap(), "100");
+
+        assert_eq!(mock.requests()[0].0, ::QUERY_COUPON_URL);
+        let params = &mock.request_params()[0];
+        assert_eq!(params.get("stock_id").unwrap(), "58818");
+        assert_eq!(
+            params.get("openid").unwrap(),
+            "onqOjjrXT-776SpHnfexGm1_P7iE"
+        );
+
+        match client.query_coupons_info("1565", "", "58818") {
+            Err(::WechatpayError::MissingField { operation, field }) => {
+                assert_eq!(operation, "query_coupon");
+                assert_eq!(field, "openid");
+            }
+            _ => panic!("expected a missing field error"),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_send_coupon() {
+        let mut params = BTreeMap::new();
+        for &(k, v) in [
+            ("coupon_stock_id", "1757"),
+            ("openid_count", "1"),
+            ("partner_trade_no", "1000009820141203515766"),
+            ("openid", "onqOjjrXT-776SpHnfexGm1_P7iE"),
+        ]
+        .iter()
+        {
+            params.insert(k.to_string(), v.to_string());
+        }
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        client.send_coupon(params.clone(), None).unwrap();
+
+        let (url, _, cert) = mock.requests()[0].clone();
+        assert_eq!(url, ::SEND_COUPON_URL);
+        assert!(cert.is_some());
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("op_user_id").unwrap(), "10000100");
+        assert_eq!(sent.get("appid").unwrap(), "wxd930ea5d5a258f4f");
+        assert_eq!(sent.get("version").unwrap(), "1.0");
+
+        // 代金券接口只支持 MD5, 不受 client 的签名类型影响
+        let mock = MockTransport::new(vec![(200, &success)]);
+        test_client()
+            .with_transport(mock.clone())
+            .with_sign_type(::SignType::HmacSha256)
+            .with_explicit_sign_type(true)
+            .send_coupon(params.clone(), None)
+            .unwrap();
+        let sent = &mock.request_params()[0];
+        assert!(!sent.contains_key("sign_type"));
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+
+        params.remove("partner_trade_no");
+        match client.send_coupon(params, None) {
+            Err(::WechatpayError::MissingField { operation, field }) => {
+                assert_eq!(operation, "send_coupon");
+                assert_eq!(field, "partner_trade_no");
+            }
+            _ => panic!("expected a missing field error"),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_send_group_red_pack() {
+        let mut params = BTreeMap::new();
+        for &(k, v) in [
+            ("mch_billno", "10000098201411111234567890"),
+            ("send_name", "天虹百货"),
+            ("re_openid", "oxTWIuGaIt6gTKsQRLau2M0yL16E"),
+            ("total_amount", "1000"),
+            ("total_num", "3"),
+            ("wishing", "感谢您参加猜灯谜活动，祝您元宵节快乐！"),
+            ("act_name", "猜灯谜抢红包活动"),
+            ("remark", "猜越多得越多，快来抢！"),
+            ("client_ip", "127.0.0.1"),
+        ]
+        .iter()
+        {
+            params.insert(k.to_string(), v.to_string());
+        }
+
+        // 红包接口的响应不带签名
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::unsigned(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        client.send_group_red_pack(params.clone(), None).unwrap();
+
+        let (url, _, cert) = mock.requests()[0].clone();
+        assert_eq!(url, ::SENDGROUPREDPACK_URL);
+        assert_eq!(cert, Some("/path/to/apiclient_cert.pem".to_string()));
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("amt_type").unwrap(), "ALL_RAND");
+        assert_eq!(sent.get("wxappid").unwrap(), "wxd930ea5d5a258f4f");
+        assert!(!sent.contains_key("client_ip"));
+        assert!(!sent.contains_key("appid"));
+
+        params.remove("total_num");
+        match client.send_group_red_pack(params, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "total_num"),
+            _ => panic!("expected a missing field error"),
+        }
+    }
+
+    #[test]
+    fn test_get_hb_info() {
+        let source = "<xml><return_code><![CDATA[SUCCESS]]></return_code>\
+                      <result_code><![CDATA[SUCCESS]]></result_code>\
+                      <status><![CDATA[RECEIVED]]></status><hblist>\
+                      <hbinfo><openid><![CDATA[oHkLxtzmyHXX6FW_cAWo_orTSRXs]]></openid>\
+                      <amount>100</amount></hbinfo>\
+                      <hbinfo><openid><![CDATA[oHkLxt_htg84TUEbzvlMwQzVDBqo]]></openid>\
+                      <amount>200</amount></hbinfo></hblist></xml>";
+        // 网关不为查询红包记录的响应签名, 但带有签名时仍然校验
+        let forged = source.replace("</xml>", "<sign>0123456789ABCDEF</sign></xml>");
+        let mock = MockTransport::unsigned(vec![(200, source), (200, &forged)]);
+        let client = test_client().with_transport(mock.clone());
+        let pairs = client.get_hb_info("9010080799701411170000046603").unwrap();
+        assert_eq!(pairs["hbinfo_count"], "2");
+        assert_eq!(pairs["openid_1"], "oHkLxt_htg84TUEbzvlMwQzVDBqo");
+        let info = ::RedPackInfo::from_pairs(pairs);
+        assert_eq!(info.status(), Some("RECEIVED"));
+        assert_eq!(info.hb_list.len(), 2);
+        assert_eq!(info.hb_list[1].amount, 200);
+        assert!(!info.pairs.contains_key("amount_1"));
+        match client.get_hb_info("9010080799701411170000046603") {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            result => panic!("expected a signature mismatch, got {:?}", result),
+        }
+
+        let (url, _, cert) = mock.requests()[0].clone();
+        assert_eq!(url, ::GETHBINFO_URL);
+        assert!(cert.is_some());
+        assert_eq!(mock.request_params()[0].get("bill_type").unwrap(), "MCHT");
+    }
+
+    #[test]
+    fn test_collector() {
+        use curl::easy::Handler;
+
+        let body = ::to_xml_str(&BTreeMap::new());
+        let mut collector = ::Collector::new(body.as_bytes().to_vec(), 16);
+        let mut sent = Vec::new();
+        let mut buf = [0u8; 4];
+        loop {
+            let n = collector.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            sent.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(sent, body.as_bytes());
+        assert_eq!(collector.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(collector.write(b"<xml>").unwrap(), 5);
+        assert_eq!(collector.write(b"</xml>").unwrap(), 6);
+        assert_eq!(collector.take_data(), b"<xml></xml>".to_vec());
+        assert!(collector.take_data().is_empty());
+
+        assert_eq!(collector.write(b"<xml></xml><xml>").unwrap(), 16);
+        assert!(!collector.exceeded);
+        assert_eq!(collector.write(b"<").unwrap(), 0);
+        assert!(collector.exceeded);
+    }
+
+    /// 在本地启动只处理一个请求的 HTTP 服务, 返回端口和收到的请求
+    fn serve_once(body: &'static str) -> (u16, ::std::thread::JoinHandle<String>) {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("</xml>") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn test_curl_post() {
+        let (port, server) =
+            serve_once("<xml><return_code><![CDATA[SUCCESS]]></return_code></xml>");
+        let url = format!("http://127.0.0.1:{}/pay/orderquery", port);
+
+        let body = "<xml><out_trade_no>1415757673</out_trade_no></xml>";
+        let (status, data) = test_client().curl_post(&url, body, None).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(
+            ::from_xml_bytes(&data).unwrap().get("return_code").unwrap(),
+            "SUCCESS"
+        );
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /pay/orderquery"));
+        assert!(request.ends_with(body));
+    }
+
+    #[test]
+    fn test_with_endpoints() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_endpoints(::Endpoints::hong_kong());
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+        client.query_order(id()).unwrap();
+        client.refund(id(), refund_params(), None, None).unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(
+            requests[0].0,
+            "https://apihk.mch.weixin.qq.com/pay/orderquery"
+        );
+        assert_eq!(
+            requests[1].0,
+            "https://apihk.mch.weixin.qq.com/secapi/pay/refund"
+        );
+        assert!(requests[1].2.is_some());
+    }
+
+    #[test]
+    fn test_audit_sink() {
+        #[derive(Clone, Default)]
+        struct RecordingAudit {
+            events: Arc<Mutex<Vec<String>>>,
+        }
+        impl ::AuditSink for RecordingAudit {
+            fn record_intent(
+                &self,
+                operation: &str,
+                reference: &str,
+                amount: &str,
+                trace_id: Option<&str>,
+            ) {
+                self.events.lock().unwrap().push(format!(
+                    "intent {} {} {} {}",
+                    operation,
+                    reference,
+                    amount,
+                    trace_id.unwrap_or("-")
+                ));
+            }
+            fn record_outcome(
+                &self,
+                operation: &str,
+                reference: &str,
+                outcome: Result<(), &::WechatpayError>,
+                trace_id: Option<&str>,
+            ) {
+                let outcome = if outcome.is_ok() { "ok" } else { "error" };
+                self.events.lock().unwrap().push(format!(
+                    "outcome {} {} {} {}",
+                    operation,
+                    reference,
+                    outcome,
+                    trace_id.unwrap_or("-")
+                ));
+            }
+        }
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let audit = RecordingAudit::default();
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_audit_sink(audit.clone());
+        client.app_pay(pay_params(), None).unwrap();
+        // 没有响应
+        ::RequestContext::with_trace_id("4bf92f3577b34da6").scope(|| {
+            assert!(client
+                .refund(
+                    ::OrderIdentifier::OutTradeNo("1415659990".to_string()),
+                    refund_params(),
+                    None,
+                    None
+                )
+                .is_err());
+        });
+        // 参数错误时没有发出请求, 不记录
+        let mut params = pay_params();
+        params.remove("total_fee");
+        assert!(client.app_pay(params, None).is_err());
+        mock.push(200, "bill");
+        client
+            .download_bill("20141110", ::BillType::All, None)
+            .unwrap();
+
+        assert_eq!(
+            *audit.events.lock().unwrap(),
+            vec![
+                "intent unifiedorder 1415659990 1 -",
+                "outcome unifiedorder 1415659990 ok -",
+                "intent refund 1415701182 100 4bf92f3577b34da6",
+                "outcome refund 1415701182 error 4bf92f3577b34da6",
+                "intent downloadbill 20141110  -",
+                "outcome downloadbill 20141110 ok -",
+            ]
+        );
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_metrics() {
+        use std::time::Duration;
+
+        #[derive(Clone, Default)]
+        struct RecordingMetrics {
+            events: Arc<Mutex<Vec<String>>>,
+        }
+        impl ::Metrics for RecordingMetrics {
+            fn on_request_start(&self, operation: &str) {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {}", operation));
+            }
+            fn on_request_end(
+                &self,
+                operation: &str,
+                _duration: Duration,
+                outcome: Result<(), &::WechatpayError>,
+            ) {
+                let outcome = match outcome {
+                    Ok(()) => "ok".to_string(),
+                    Err(::WechatpayError::Business { err_code, .. }) => err_code.to_string(),
+                    Err(_) => "error".to_string(),
+                };
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("end {} {}", operation, outcome));
+            }
+        }
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &business_fail("NOTENOUGH"))]);
+        let metrics = RecordingMetrics::default();
+        let client = test_client()
+            .with_transport(mock)
+            .with_metrics(metrics.clone());
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+        client.query_order(id()).unwrap();
+        assert!(client.close_order("1415757673", None).is_err());
+        // 没有响应
+        assert!(client.reverse(id()).is_err());
+        assert!(client
+            .download_bill("20141110", ::BillType::All, None)
+            .is_err());
+
+        assert_eq!(
+            *metrics.events.lock().unwrap(),
+            vec![
+                "start orderquery",
+                "end orderquery ok",
+                "start closeorder",
+                "end closeorder NOTENOUGH",
+                "start reverse",
+                "end reverse error",
+                "start downloadbill",
+                "end downloadbill error",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_curl_configure() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (port, server) =
+            serve_once("<xml><return_code><![CDATA[SUCCESS]]></return_code></xml>");
+        let called = Arc::new(AtomicBool::new(false));
+        let flag = called.clone();
+        let client = test_client().with_curl_configure(move |easy| {
+            flag.store(true, Ordering::SeqCst);
+            easy.useragent("wechatpay-test/1.0")
+        });
+        let url = format!("http://127.0.0.1:{}/pay/orderquery", port);
+        let (status, _) = client.curl_post(&url, "<xml></xml>", None).unwrap();
+        assert_eq!(status, 200);
+        assert!(called.load(Ordering::SeqCst));
+        assert!(server
+            .join()
+            .unwrap()
+            .contains("User-Agent: wechatpay-test/1.0"));
+
+        // 回调出错时不发送请求
+        let client = test_client().with_curl_configure(|_| Err(::curl::Error::new(43)));
+        match client.curl_post("http://127.0.0.1:9/pay/orderquery", "<xml></xml>", None) {
+            Err(::WechatpayError::Curl(_)) => {}
+            _ => panic!("expected a curl error"),
+        }
+    }
+
+    #[test]
+    fn test_connection_reuse() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+        use std::time::Duration;
+
+        // 只接受一个连接, 在同一个连接上处理两个请求
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let body = "<xml><return_code><![CDATA[SUCCESS]]></return_code></xml>";
+            for _ in 0..2 {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("</xml>") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        let client = test_client()
+            .with_connection_reuse(true)
+            .with_curl_configure(|easy| easy.timeout(Duration::from_secs(5)));
+        let url = format!("http://127.0.0.1:{}/pay/orderquery", port);
+        for _ in 0..2 {
+            let (status, _) = client.curl_post(&url, "<xml></xml>", None).unwrap();
+            assert_eq!(status, 200);
+        }
+        let mut idle = client.idle_handles.lock().unwrap();
+        assert_eq!(idle.len(), 1);
+        assert_eq!(idle[0].num_connects().unwrap(), 0);
+        idle.clear();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_curl_error_detail() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        // 以明文响应 https 请求, TLS 握手失败
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        });
+
+        let url = format!("https://127.0.0.1:{}/pay/orderquery", port);
+        let err = test_client()
+            .curl_post(&url, "<xml></xml>", None)
+            .unwrap_err();
+        let _ = server.join();
+        let detail = match err {
+            ::WechatpayError::Curl(ref e) => e.extra_description().unwrap().to_string(),
+            _ => panic!("expected a curl error"),
+        };
+        assert!(!detail.is_empty());
+        let message = err.to_string();
+        assert!(message.starts_with("curl error ["), "{}", message);
+        assert!(message.contains(&detail), "{}", message);
+
+        assert_eq!(
+            ::WechatpayError::Business {
+                err_code: ::ErrCode::NotEnough,
+                err_code_des: "余额不足".to_string(),
+            }
+            .to_string(),
+            "result_code FAIL: NOTENOUGH 余额不足"
+        );
+    }
+
+    #[test]
+    fn test_low_speed_abort() {
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        // 收到请求后不响应, 直到客户端放弃
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (done, wait) = mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = wait.recv_timeout(Duration::from_secs(10));
+        });
+
+        let client = test_client().with_low_speed(100, Duration::from_secs(1));
+        assert_eq!(client.low_speed_limit, 100);
+        assert_eq!(client.low_speed_time, Duration::from_secs(1));
+        let url = format!("http://127.0.0.1:{}/pay/orderquery", port);
+        let start = Instant::now();
+        match client.curl_post(&url, "<xml></xml>", None) {
+            Err(::WechatpayError::Curl(e)) => assert!(e.is_operation_timedout(), "{}", e),
+            _ => panic!("expected a curl error"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        done.send(()).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let body: &'static str = Box::leak("<xml></xml>".repeat(1024).into_boxed_str());
+        let (port, server) = serve_once(body);
+        let client = test_client().with_max_response_bytes(1024, 1024 * 1024);
+        let url = format!("http://127.0.0.1:{}/pay/orderquery", port);
+        match client.curl_post(&url, "<xml></xml>", None) {
+            Err(::WechatpayError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024),
+            _ => panic!("expected a response too large error"),
+        }
+        let _ = server.join();
+
+        let bill = "`".repeat(2048);
+        let mock = MockTransport::new(vec![(200, body), (200, &bill)]);
+        let client = test_client()
+            .with_max_response_bytes(1024, 1024 * 1024)
+            .with_transport(mock);
+        match client.query_order(::OrderIdentifier::OutTradeNo("1415757673".to_string())) {
+            Err(::WechatpayError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024),
+            _ => panic!("expected a response too large error"),
+        }
+        // 对账单使用单独的上限
+        assert_eq!(
+            client
+                .download_bill("20140603", ::BillType::All, None)
+                .unwrap()
+                .len(),
+            bill.len()
+        );
+    }
+
+    #[test]
+    fn test_download_bills_range() {
+        let mock = MockTransport::new(vec![(200, "day1"), (200, "day2"), (200, "day3")]);
+        let client = test_client().with_transport(mock.clone());
+        let bills = client
+            .download_bills_range("20141130", "20141202", ::BillType::All, None)
+            .unwrap();
+        assert_eq!(
+            bills,
+            vec![
+                ("20141130".to_string(), "day1".to_string()),
+                ("20141201".to_string(), "day2".to_string()),
+                ("20141202".to_string(), "day3".to_string()),
+            ]
+        );
+        let dates = mock
+            .request_params()
+            .iter()
+            .map(|p| p["bill_date"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(dates, vec!["20141130", "20141201", "20141202"]);
+
+        assert!(client
+            .download_bills_range("20141202", "20141130", ::BillType::All, None)
+            .is_err());
+
+        // 当天及之后的对账单还未生成, 不发送请求
+        let future = "29991231";
+        match client.download_bill(future, ::BillType::All, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "bill_date"),
+            result => panic!("expected an invalid field error, got {:?}", result),
+        }
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (port, server) =
+            serve_once("<xml><return_code><![CDATA[SUCCESS]]></return_code></xml>");
+        let client = test_client().with_resolve(
+            "api.mch.weixin.invalid",
+            port,
+            "127.0.0.1".parse().unwrap(),
+        );
+        assert_eq!(
+            client.resolve,
+            vec![format!("api.mch.weixin.invalid:{}:127.0.0.1", port)]
+        );
+
+        let url = format!("http://api.mch.weixin.invalid:{}/pay/orderquery", port);
+        let (status, _) = client.curl_post(&url, "<xml></xml>", None).unwrap();
+        assert_eq!(status, 200);
+        assert!(server
+            .join()
+            .unwrap()
+            .contains(&format!("Host: api.mch.weixin.invalid:{}", port)));
+
+        let client =
+            test_client().with_resolve("api.mch.weixin.qq.com", 443, "::1".parse().unwrap());
+        assert_eq!(
+            client.resolve,
+            vec!["api.mch.weixin.qq.com:443:[::1]".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_ping() {
+        use NonceGenerator;
+
+        let not_exist = [
+            ("return_code", "SUCCESS"),
+            ("result_code", "FAIL"),
+            ("err_code", "ORDERNOTEXIST"),
+            ("nonce_str", "TN55wO9Pba5yENl8"),
+        ];
+        let mock = MockTransport::new(vec![(200, &signed_response_xml(&not_exist))]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client.ping().is_ok());
+        let params = &mock.request_params()[0];
+        assert_eq!(mock.requests()[0].0, ::ORDERQUERY_URL);
+        assert_eq!(params.get("out_trade_no").unwrap().len(), 32);
+
+        let mock = MockTransport::new(vec![(200, &signed_response_xml(&not_exist))]);
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_nonce_generator(::SeededNonce::new(7));
+        assert!(client.ping().is_ok());
+        let nonce = ::SeededNonce::new(7);
+        let nonce_str = nonce.nonce_str();
+        let out_trade_no = format!("ping{}", &nonce.nonce_str()[..28]);
+        let params = &mock.request_params()[0];
+        assert_eq!(params["nonce_str"], nonce_str);
+        assert_eq!(params["out_trade_no"], out_trade_no);
+
+        // 缺少签名
+        let mock = MockTransport::unsigned(vec![(200, &business_fail("ORDERNOTEXIST"))]);
+        let client = test_client().with_transport(mock);
+        match client.ping() {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            _ => panic!("expected a signature mismatch"),
+        }
+
+        let fail = response_xml(&[("return_code", "FAIL"), ("return_msg", "签名错误")]);
+        let client = test_client().with_transport(MockTransport::new(vec![(200, &fail)]));
+        assert!(client.ping().is_err());
+
+        let client = test_client().with_transport(MockTransport::new(vec![]));
+        assert!(client.ping().is_err());
+    }
+
+    #[test]
+    fn test_check_response_sign() {
+        let key = "192006250b4c09247ec02edce69f6a2d";
+        let unsigned = ::from_xml_str(&response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("send_listid", "100000000020150520314766074200"),
+        ]));
+        assert!(
+            ::check_response_sign(&unsigned, key, ::SENDGROUPREDPACK_URL, ::SignType::Md5).is_ok()
+        );
+        match ::check_response_sign(&unsigned, key, ::UNIFIEDORDER_URL, ::SignType::Md5) {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            _ => panic!("expected a signature mismatch"),
+        }
+
+        let mut signed = ::from_xml_str(&signed_response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("prepay_id", "wx201410272009395522657a690389285100"),
+        ]));
+        assert!(::check_response_sign(&signed, key, ::UNIFIEDORDER_URL, ::SignType::Md5).is_ok());
+        assert!(
+            ::check_response_sign(&signed, key, ::SENDGROUPREDPACK_URL, ::SignType::Md5).is_ok()
+        );
+        signed.insert("prepay_id".to_string(), "wx0".to_string());
+        assert!(
+            ::check_response_sign(&signed, key, ::SENDGROUPREDPACK_URL, ::SignType::Md5).is_err()
+        );
+
+        let fail = ::from_xml_str(&response_xml(&[("return_code", "FAIL")]));
+        assert!(::check_response_sign(&fail, key, ::UNIFIEDORDER_URL, ::SignType::Md5).is_ok());
+    }
+
+    #[test]
+    fn test_request_verifies_response_sign() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let id = || ::OrderIdentifier::OutTradeNo("1415659990".to_string());
+        let client = test_client().with_transport(MockTransport::unsigned(vec![(200, &success)]));
+        match client.query_order(id()) {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let forged = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("sign", "0CB01533B8C1EF103065174F50BCA001"),
+        ]);
+        let client = test_client().with_transport(MockTransport::new(vec![(200, &forged)]));
+        match client.query_order(id()) {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let client = test_client().with_transport(MockTransport::new(vec![(200, &success)]));
+        assert!(client.query_order(id()).is_ok());
+
+        // 退款使用 HMAC-SHA256, 响应也按 HMAC-SHA256 验签
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        client
+            .refund(id(), refund_params(), None, None)
+            .map(|_| ())
+            .unwrap();
+        let mut md5_signed = BTreeMap::new();
+        md5_signed.insert("return_code".to_string(), "SUCCESS".to_string());
+        md5_signed.insert("result_code".to_string(), "SUCCESS".to_string());
+        let sign = ::get_sign(&md5_signed, "192006250b4c09247ec02edce69f6a2d");
+        md5_signed.insert("sign".to_string(), sign);
+        let client = test_client().with_transport(MockTransport::unsigned(vec![(
+            200,
+            &::to_xml_str(&md5_signed),
+        )]));
+        assert!(client.refund(id(), refund_params(), None, None).is_err());
+    }
+
+    #[test]
+    fn test_global_client() {
+        assert!(::WechatpayClient::init_global(test_client()).is_ok());
+        assert!(::WechatpayClient::init_global(test_client()).is_err());
+        let client = ::WechatpayClient::global();
+        assert_eq!(client.appid, "wxd930ea5d5a258f4f");
+        assert_eq!(client.mch_id, "10000100");
+    }
+
+    /// 收集日志记录的 logger
+    struct CaptureLogger;
+
+    static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl ::log::Log for CaptureLogger {
+        fn enabled(&self, _: &::log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &::log::Record) {
+            LOGS.lock().unwrap().push(format!("{}", record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_trace_id_in_logs() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        let _ = ::log::set_logger(&LOGGER);
+        ::log::set_max_level(::log::LevelFilter::Trace);
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let client = test_client().with_transport(MockTransport::new(vec![(200, &success)]));
+        ::RequestContext::with_trace_id("4bf92f3577b34da6").scope(|| {
+            client
+                .query_order(::OrderIdentifier::OutTradeNo("1415757673".to_string()))
+                .unwrap();
+        });
+        let logs = LOGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains("trace_id=4bf92f3577b34da6"))
+            .cloned()
+            .collect::<Vec<String>>();
+        assert!(
+            logs.iter().any(|line| line.contains(::ORDERQUERY_URL)),
+            "{:?}",
+            logs
+        );
+        assert!(
+            logs.iter().any(|line| line.contains("status=200")),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn test_set_api_key() {
+        let old_key = "192006250b4c09247ec02edce69f6a2d";
+        let new_key = "8934e7d15453e97507ef794cf7b0519d";
+        let mut success = BTreeMap::new();
+        success.insert("return_code".to_string(), "SUCCESS".to_string());
+        success.insert("result_code".to_string(), "SUCCESS".to_string());
+        let sign = ::get_sign(&success, new_key);
+        success.insert("sign".to_string(), sign);
+        // 更换密钥后网关使用新密钥签名
+        let mock = MockTransport::unsigned(vec![
+            (
+                200,
+                &signed_response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]),
+            ),
+            (200, &::to_xml_str(&success)),
+        ]);
+        let mut client = test_client().with_transport(mock.clone());
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+
+        client.query_order(id()).unwrap();
+        client.set_api_key(new_key);
+        client.query_order(id()).unwrap();
+        let sent = mock.request_params();
+        assert!(::verify_sign(&sent[0], old_key));
+        assert!(!::verify_sign(&sent[1], old_key));
+        assert!(::verify_sign(&sent[1], new_key));
+    }
+
+    #[test]
+    fn test_signing_key_provider() {
+        // 模拟 HSM: 记录收到的待签名字符串, 返回固定的签名
+        #[derive(Clone, Default)]
+        struct MockSigner {
+            sources: Arc<Mutex<Vec<(String, ::SignType)>>>,
+        }
+        impl ::SigningKeyProvider for MockSigner {
+            fn sign(&self, source: &str, sign_type: ::SignType) -> String {
+                self.sources
+                    .lock()
+                    .unwrap()
+                    .push((source.to_string(), sign_type));
+                "HSMSIGNATURE".to_string()
+            }
+        }
+
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("sign", "HSMSIGNATURE"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let signer = MockSigner::default();
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_signing_key_provider(signer.clone());
+        client
+            .query_order(::OrderIdentifier::OutTradeNo("1415757673".to_string()))
+            .unwrap();
+
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("sign").unwrap(), "HSMSIGNATURE");
+        // 请求签名和响应验签
+        let sources = signer.sources.lock().unwrap().clone();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].1, ::SignType::Md5);
+        assert_eq!(sources[1].0, "result_code=SUCCESS&return_code=SUCCESS");
+        assert!(sources[0]
+            .0
+            .starts_with("appid=wxd930ea5d5a258f4f&mch_id=10000100&"));
+        assert!(!sources[0].0.contains("key="));
+
+        // 验签也交给 provider
+        let mut notification = ::from_xml_str(&response_xml(NOTIFICATION));
+        notification.insert("sign".to_string(), "HSMSIGNATURE".to_string());
+        assert!(client
+            .handle_notification(&::to_xml_str(&notification))
+            .is_ok());
+        assert_eq!(signer.sources.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_auto_out_trade_no() {
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("prepay_id", "wx201410272009395522657a690389285100"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let mut params = pay_params();
+        params.remove("out_trade_no");
+
+        // 默认不生成
+        let client = test_client().with_transport(mock.clone());
+        match client.pay(params.clone(), ::TradeType::App, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "out_trade_no"),
+            _ => panic!("expected a missing field error"),
+        }
+
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_auto_out_trade_no(true);
+        let result = client.pay(params, ::TradeType::App, None).unwrap();
+        let sent = mock.request_params()[0]
+            .get("out_trade_no")
+            .cloned()
+            .unwrap();
+        assert_eq!(sent.len(), 32);
+        assert_eq!(result.get("out_trade_no").unwrap(), &sent);
+
+        // 调用方指定的订单号不变, 也不补充到结果中
+        let result = client.pay(pay_params(), ::TradeType::App, None).unwrap();
+        assert!(!result.contains_key("out_trade_no"));
+        assert_eq!(
+            mock.request_params()[1].get("out_trade_no").unwrap(),
+            "1415659990"
+        );
+    }
+
+    #[test]
+    fn test_default_spbill_create_ip() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let client = test_client()
+            .with_spbill_create_ip("10.0.0.8".parse().unwrap())
+            .with_transport(mock.clone());
+
+        let mut params = pay_params();
+        params.remove("spbill_create_ip");
+        client.app_pay(params, None).unwrap();
+        client.app_pay(pay_params(), None).unwrap();
+        let sent = mock.request_params();
+        assert_eq!(sent[0].get("spbill_create_ip").unwrap(), "10.0.0.8");
+        assert_eq!(
+            sent[1].get("spbill_create_ip"),
+            pay_params().get("spbill_create_ip")
+        );
+
+        let mut params = pay_params();
+        params.remove("spbill_create_ip");
+        match test_client().app_pay(params, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => {
+                assert_eq!(field, "spbill_create_ip")
+            }
+            _ => panic!("expected a missing field error"),
+        }
+    }
+
+    #[test]
+    fn test_detect_server_ip() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let mut client = test_client().with_transport(mock.clone());
+        client
+            .detect_server_ip_via("127.0.0.1:9".parse().unwrap())
+            .unwrap();
+        assert_eq!(client.spbill_create_ip.as_ref().unwrap(), "127.0.0.1");
+
+        let mut params = pay_params();
+        params.remove("spbill_create_ip");
+        client.app_pay(params, None).unwrap();
+        assert_eq!(mock.request_params()[0]["spbill_create_ip"], "127.0.0.1");
+
+        let mut client = test_client().with_endpoints(::Endpoints::new("https://invalid."));
+        assert!(client.detect_server_ip().is_err());
+        assert!(client.spbill_create_ip.is_none());
+    }
+
+    #[test]
+    fn test_query() {
+        use std::convert::TryFrom;
+
+        let success = signed_response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("appid", "wx2421b1c4370ec43b"),
+            ("mch_id", "10000100"),
+            ("openid", "oUpF8uN95-Ptaags6E_roPHg7AG0"),
+            ("trade_type", "MICROPAY"),
+            ("trade_state", "SUCCESS"),
+            ("trade_state_desc", "支付成功"),
+            ("bank_type", "CCB_DEBIT"),
+            ("total_fee", "101"),
+            ("cash_fee", "100"),
+            ("transaction_id", "1008450740201411110005820873"),
+            ("out_trade_no", "1415757673"),
+            ("attach", "订单额外描述"),
+            ("time_end", "20141111170043"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock);
+        let result = client
+            .query(::OrderIdentifier::OutTradeNo("1415757673".to_string()))
+            .unwrap();
+        assert_eq!(
+            result.transaction_id.as_ref().unwrap(),
+            "1008450740201411110005820873"
+        );
+        assert_eq!(result.out_trade_no, "1415757673");
+        assert_eq!(result.trade_state, ::TradeState::Success);
+        assert_eq!(result.trade_state_desc, "支付成功");
+        assert_eq!(
+            result.bank_type.as_ref().unwrap().payment_method_category(),
+            ::PaymentMethodCategory::Debit
+        );
+        assert_eq!(result.total_fee.cents(), 101);
+        assert_eq!(result.cash_fee.as_ref().unwrap().cents(), 100);
+        assert_eq!(
+            ::time::strftime("%Y-%m-%d %H:%M:%S", result.time_end.as_ref().unwrap()).unwrap(),
+            "2014-11-11 17:00:43"
+        );
+        assert_eq!(
+            result.openid.as_ref().unwrap(),
+            "oUpF8uN95-Ptaags6E_roPHg7AG0"
+        );
+        assert_eq!(result.attach.as_ref().unwrap(), "订单额外描述");
+
+        let mut pairs = BTreeMap::new();
+        pairs.insert("out_trade_no".to_string(), "1415757673".to_string());
+        pairs.insert("trade_state".to_string(), "NOTPAY".to_string());
+        pairs.insert("total_fee".to_string(), "101".to_string());
+        let result = ::OrderQueryResult::try_from(pairs).unwrap();
+        assert_eq!(result.trade_state, ::TradeState::NotPay);
+        assert!(result.time_end.is_none() && result.bank_type.is_none());
+    }
+
+    const NOTIFICATION: &[(&str, &str)] = &[
+        ("appid", "wx2421b1c4370ec43b"),
+        ("attach", "支付测试"),
+        ("bank_type", "CFT"),
+        ("fee_type", "CNY"),
+        ("is_subscribe", "Y"),
+        ("mch_id", "10000100"),
+        ("nonce_str", "5d2b6c2a8db53831f7eda20af46e531c"),
+        ("openid", "oUpF8uMEb4qRXf22hE3X68TekukE"),
+        ("out_trade_no", "1409811653"),
+        ("result_code", "SUCCESS"),
+        ("return_code", "SUCCESS"),
+        ("time_end", "20140903131540"),
+        ("total_fee", "1"),
+        ("cash_fee", "1"),
+        ("trade_type", "JSAPI"),
+        ("transaction_id", "1004400740201409030005092168"),
+    ];
+
+    #[test]
+    fn test_handle_notification_hmac() {
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+        let mut pairs = BTreeMap::new();
+        for &(k, v) in NOTIFICATION.iter() {
+            pairs.insert(k.to_string(), v.to_string());
+        }
+        let sign = ::get_sign_with(&pairs, api_key, ::SignType::HmacSha256);
+        pairs.insert("sign".to_string(), sign);
+        let client = test_client().with_sign_type(::SignType::HmacSha256);
+        client.handle_notification(&::to_xml_str(&pairs)).unwrap();
+        client.validate_incoming(&pairs, None).unwrap();
+        assert!(test_client()
+            .handle_notification(&::to_xml_str(&pairs))
+            .is_err());
+
+        // 回调中指定了 `sign_type` 时以回调为准
+        pairs.insert("sign_type".to_string(), "HMAC-SHA256".to_string());
+        let sign = ::get_sign_with(&pairs, api_key, ::SignType::HmacSha256);
+        pairs.insert("sign".to_string(), sign);
+        test_client()
+            .handle_notification(&::to_xml_str(&pairs))
+            .unwrap();
+        assert!(::verify_sign(&pairs, api_key));
+    }
+
+    #[test]
+    fn test_handle_notification() {
+        let client = test_client();
+        let notification = client
+            .handle_notification(&signed_response_xml(NOTIFICATION))
+            .unwrap();
+        assert_eq!(notification.out_trade_no, "1409811653");
+        assert_eq!(notification.trade_type, Some(::TradeType::Jsapi));
+        assert_eq!(notification.trade_state, ::TradeState::Success);
+        assert_eq!(notification.total_fee, ::Money::from_cents(1));
+        assert_eq!(notification.cash_fee, ::Money::from_cents(1));
+        assert_eq!(notification.attach.as_ref().unwrap(), "支付测试");
+        let time_end = notification.time_end;
+        assert_eq!(
+            (
+                time_end.tm_year + 1900,
+                time_end.tm_mon + 1,
+                time_end.tm_mday
+            ),
+            (2014, 9, 3)
+        );
+        assert_eq!(
+            (time_end.tm_hour, time_end.tm_min, time_end.tm_sec),
+            (13, 15, 40)
+        );
+
+        // 伪造的通知: 修改金额后签名不变
+        let forged = signed_response_xml(NOTIFICATION).replace(
+            "<total_fee><![CDATA[1]]></total_fee>",
+            "<total_fee><![CDATA[100]]></total_fee>",
+        );
+        match client.handle_notification(&forged) {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            _ => panic!("expected a signature mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_parse_notification_bytes() {
+        let client = test_client();
+        let mut body = b"\xEF\xBB\xBF".to_vec();
+        body.extend_from_slice(signed_response_xml(NOTIFICATION).as_bytes());
+        let notification = client.parse_notification_bytes(&body).unwrap();
+        assert_eq!(notification.out_trade_no, "1409811653");
+        assert_eq!(notification.attach.as_ref().unwrap(), "支付测试");
+
+        let forged = String::from_utf8(body)
+            .unwrap()
+            .replace(
+                "<total_fee><![CDATA[1]]></total_fee>",
+                "<total_fee><![CDATA[100]]></total_fee>",
+            )
+            .into_bytes();
+        match client.parse_notification_bytes(&forged) {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            _ => panic!("expected a signature mismatch"),
+        }
+        match client.parse_notification_bytes(b"<xml>\xFF</xml>") {
+            Err(::WechatpayError::Encoding(_)) => {}
+            _ => panic!("expected an encoding error"),
+        }
+    }
+
+    #[test]
+    fn test_validate_incoming() {
+        let client = test_client();
+        let signed = |pairs: &[(&str, &str)]| ::from_xml_str(&signed_response_xml(pairs));
+        let pairs = signed(NOTIFICATION);
+        client.validate_incoming(&pairs, None).unwrap();
+        client
+            .validate_incoming(&pairs, Some(("1409811653", 1)))
+            .unwrap();
+
+        let mut forged = pairs.clone();
+        forged.insert("total_fee".to_string(), "100".to_string());
+        match client.validate_incoming(&forged, Some(("1409811653", 100))) {
+            Err(::WechatpayError::SignatureMismatch) => {}
+            result => panic!("expected a signature mismatch, got {:?}", result),
+        }
+
+        let mut failed = NOTIFICATION.to_vec();
+        failed.retain(|&(k, _)| k != "result_code");
+        failed.push(("result_code", "FAIL"));
+        failed.push(("err_code", "NOTENOUGH"));
+        match client.validate_incoming(&signed(&failed), None) {
+            Err(::WechatpayError::Business { err_code, .. }) => assert_eq!(err_code, "NOTENOUGH"),
+            result => panic!("expected a business error, got {:?}", result),
+        }
+
+        match client.validate_incoming(&pairs, Some(("1409811653", 100))) {
+            Err(::WechatpayError::AmountMismatch { sent, received }) => {
+                assert_eq!((sent.as_str(), received.as_str()), ("100", "1"))
+            }
+            result => panic!("expected an amount mismatch, got {:?}", result),
+        }
+        match client.validate_incoming(&pairs, Some(("1409811654", 1))) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "out_trade_no"),
+            result => panic!("expected an invalid field error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_call() {
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("openid", "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let url = "https://api.mch.weixin.qq.com/tools/authcodetoopenid";
+        let mut params = BTreeMap::new();
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+        let pairs = client.call(url, params.clone(), false, None).unwrap();
+        assert_eq!(pairs.get("openid").unwrap(), "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o");
+
+        let (sent_url, _, cert) = mock.requests()[0].clone();
+        assert_eq!(sent_url, url);
+        assert!(cert.is_none());
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("auth_code").unwrap(), "120061098828009406");
+        assert_eq!(sent.get("appid").unwrap(), "wxd930ea5d5a258f4f");
+        assert_eq!(sent.get("mch_id").unwrap(), "10000100");
+        assert_eq!(sent.get("nonce_str").unwrap().len(), 32);
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+
+        params.insert("sign".to_string(), "".to_string());
+        match client.call(url, params, false, None) {
+            Err(::WechatpayError::RedundantField { field, .. }) => assert_eq!(field, "sign"),
+            _ => panic!("expected a redundant field error"),
+        }
+    }
+
+    #[test]
+    fn test_query_order_state_pay_error() {
+        let pay_error = signed_response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("out_trade_no", "1415757673"),
+            ("trade_state", "PAYERROR"),
+            ("trade_state_desc", "支付失败，请重新下单支付"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &pay_error), (200, &pay_error)]);
+        let client = test_client().with_transport(mock);
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+
+        // 查询本身成功
+        assert!(client.query_order(id()).is_ok());
+        let state = client.query_order_state(id()).unwrap();
+        assert_eq!(state, ::TradeState::PayError);
+        assert!(!state.is_paid());
+        assert!(state.is_terminal());
+    }
+
+    #[test]
+    fn test_watch_order() {
+        use std::time::Duration;
+
+        let state = |trade_state: &str| {
+            response_xml(&[
+                ("return_code", "SUCCESS"),
+                ("result_code", "SUCCESS"),
+                ("trade_state", trade_state),
+            ])
+        };
+        let mock = MockTransport::new(vec![
+            (200, &state("NOTPAY")),
+            (200, &state("USERPAYING")),
+            (200, &state("SUCCESS")),
+            (200, &state("SUCCESS")),
+        ]);
+        let client = test_client().with_transport(mock.clone());
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+        let states = client
+            .watch_order(id(), Duration::from_millis(1), Duration::from_secs(5))
+            .collect::<Result<Vec<::TradeState>, ::WechatpayError>>()
+            .unwrap();
+        assert_eq!(
+            states,
+            vec![
+                ::TradeState::NotPay,
+                ::TradeState::UserPaying,
+                ::TradeState::Success
+            ]
+        );
+        assert_eq!(mock.requests().len(), 3);
+
+        // 超时: 只查询一次
+        let mock = MockTransport::new(vec![(200, &state("NOTPAY")), (200, &state("NOTPAY"))]);
+        let client = test_client().with_transport(mock.clone());
+        let states = client
+            .watch_order(id(), Duration::from_secs(1), Duration::from_millis(10))
+            .collect::<Result<Vec<::TradeState>, ::WechatpayError>>()
+            .unwrap();
+        assert_eq!(states, vec![::TradeState::NotPay]);
+        assert_eq!(mock.requests().len(), 1);
+
+        // 查询出错时返回错误后结束
+        let mock = MockTransport::new(vec![
+            (200, &state("NOTPAY")),
+            (200, &business_fail("SYSTEMERROR")),
+            (200, &state("SUCCESS")),
+        ]);
+        let client = test_client().with_transport(mock.clone());
+        let results = client
+            .watch_order(id(), Duration::from_millis(1), Duration::from_secs(5))
+            .collect::<Vec<Result<::TradeState, ::WechatpayError>>>();
+        assert_eq!(results.len(), 2);
+        assert_eq!(*results[0].as_ref().unwrap(), ::TradeState::NotPay);
+        assert!(results[1].is_err());
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_query_openid() {
+        let paid = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_state", "SUCCESS"),
+            ("openid", "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o"),
+        ]);
+        let not_paid = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_state", "NOTPAY"),
+        ]);
+        let client =
+            test_client().with_transport(MockTransport::new(vec![(200, &paid), (200, &not_paid)]));
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+        assert_eq!(
+            client.query_openid(id()).unwrap(),
+            Some("oUpF8uMuAJO_M2pxb1Q9zNjWeS6o".to_string())
+        );
+        assert_eq!(client.query_openid(id()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_order_identifier_normalize() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        // 首尾的空白被去掉
+        client
+            .query_order(::OrderIdentifier::TransactionId(
+                " 1217752501201407033233368018\n".to_string(),
+            ))
+            .unwrap();
+        assert_eq!(
+            mock.request_params()[0].get("transaction_id").unwrap(),
+            "1217752501201407033233368018"
+        );
+
+        match client.query_order(::OrderIdentifier::OutTradeNo("1".repeat(33))) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "out_trade_no"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert_eq!(mock.requests().len(), 1);
+
+        match ::OrderIdentifier::transaction_id("12177525012014070332333680") {
+            Err(::WechatpayError::InvalidField { field, .. }) => {
+                assert_eq!(field, "transaction_id")
+            }
+            _ => panic!("expected an invalid field error"),
+        }
+        assert!(::OrderIdentifier::out_trade_no("1415 757673").is_err());
+        assert!(::OrderIdentifier::out_trade_no("").is_err());
+        match ::OrderIdentifier::out_trade_no("  20150806125346_a-1|*  ") {
+            Ok(::OrderIdentifier::OutTradeNo(s)) => assert_eq!(s, "20150806125346_a-1|*"),
+            _ => panic!("expected a valid out_trade_no"),
+        }
+    }
+
+    #[test]
+    fn test_query_order_best() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        client
+            .query_order_best(Some("1009660380201506130728806387"), Some("20150806125346"))
+            .unwrap();
+        client
+            .query_order_best(Some("1009660380201506130728806387"), None)
+            .unwrap();
+        client
+            .query_order_best(None, Some("20150806125346"))
+            .unwrap();
+        let sent = mock.request_params();
+        for params in &sent[..2] {
+            assert_eq!(
+                params.get("transaction_id").unwrap(),
+                "1009660380201506130728806387"
+            );
+            assert!(!params.contains_key("out_trade_no"));
+        }
+        assert_eq!(sent[2].get("out_trade_no").unwrap(), "20150806125346");
+        assert!(!sent[2].contains_key("transaction_id"));
+
+        match client.query_order_best(None, None) {
+            Err(::WechatpayError::MissingField { operation, .. }) => {
+                assert_eq!(operation, "orderquery")
+            }
+            _ => panic!("expected a missing field error"),
+        }
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_flush_pending() {
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("out_trade_no", "1415757673"),
+        ]);
+        let query = |trade_state: &str| {
+            response_xml(&[
+                ("return_code", "SUCCESS"),
+                ("result_code", "SUCCESS"),
+                ("trade_state", trade_state),
+                ("transaction_id", "1009660380201506130728806387"),
+            ])
+        };
+        let not_exist = business_fail("ORDERNOTEXIST");
+        let pending_client = |mock: &MockTransport| {
+            test_client()
+                .with_transport(mock.clone())
+                .with_pending_store(::MemoryPendingStore::default())
+        };
+        let mut params = pay_params();
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+
+        // 默认不保存
+        let mock = MockTransport::new(vec![]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client.micro_pay(params.clone(), None).is_err());
+        assert!(client.flush_pending().is_empty());
+        assert_eq!(mock.requests().len(), 1);
+
+        // 网络中断: 请求被保存, 恢复前查询失败, 仍然保存
+        let mock = MockTransport::new(vec![]);
+        let client = pending_client(&mock);
+        match client.micro_pay(params.clone(), None) {
+            Err(::WechatpayError::Request) => {}
+            _ => panic!("expected a request error"),
+        }
+        let results = client.flush_pending();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_err());
+
+        // 恢复后订单不存在, 重新提交成功, 队列清空
+        mock.push(200, &not_exist);
+        mock.push(200, &success);
+        let results = client.flush_pending();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, params["out_trade_no"]);
+        assert!(results[0].1.is_ok());
+        assert!(client.flush_pending().is_empty());
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[2].0, ::ORDERQUERY_URL);
+        assert_eq!(
+            mock.request_params()[3].get("auth_code").unwrap(),
+            "120061098828009406"
+        );
+
+        // 之前的请求已经到达网关并支付成功: 不再提交
+        let mock = MockTransport::new(vec![]);
+        let client = pending_client(&mock);
+        let _ = client.micro_pay(params.clone(), None);
+        mock.push(200, &query("SUCCESS"));
+        let results = client.flush_pending();
+        assert_eq!(
+            results[0].1.as_ref().unwrap()["transaction_id"],
+            "1009660380201506130728806387"
+        );
+        assert_eq!(mock.requests().len(), 2);
+
+        // 结果不确定时查询, 未支付则撤销订单
+        let mock = MockTransport::new(vec![]);
+        let client = pending_client(&mock);
+        let _ = client.micro_pay(params.clone(), None);
+        mock.push(200, &not_exist);
+        mock.push(200, &business_fail("USERPAYING"));
+        mock.push(200, &query("USERPAYING"));
+        mock.push(200, &success);
+        let results = client.flush_pending();
+        match results[0].1 {
+            Err(::WechatpayError::Business { ref err_code, .. }) => {
+                assert_eq!(err_code, "USERPAYING")
+            }
+            _ => panic!("expected a business error"),
+        }
+        let requests = mock.requests();
+        assert_eq!(requests[3].0, ::ORDERQUERY_URL);
+        assert_eq!(requests[4].0, ::REVERSE_URL);
+        assert!(requests[4].2.is_some());
+        assert!(client.flush_pending().is_empty());
+
+        // 重新提交返回 ORDERPAID: 查询到已支付, 不撤销
+        let mock = MockTransport::new(vec![]);
+        let client = pending_client(&mock);
+        let _ = client.micro_pay(params.clone(), None);
+        mock.push(200, &not_exist);
+        mock.push(200, &business_fail("ORDERPAID"));
+        mock.push(200, &query("SUCCESS"));
+        let results = client.flush_pending();
+        assert!(results[0].1.is_ok());
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[3].0, ::ORDERQUERY_URL);
+
+        // 查询结果不是订单不存在时都不重新提交
+        for (response, requeued) in [
+            (query("NOTPAY"), false),
+            (query("USERPAYING"), true),
+            (business_fail("SYSTEMERROR"), false),
+        ] {
+            let mock = MockTransport::new(vec![]);
+            let client = pending_client(&mock);
+            let _ = client.micro_pay(params.clone(), None);
+            mock.push(200, &response);
+            let results = client.flush_pending();
+            assert!(results[0].1.is_err());
+            assert_eq!(mock.requests().len(), 2);
+            assert_eq!(client.flush_pending().len(), requeued as usize);
+        }
+    }
+
+    #[test]
+    fn test_flush_pending_auto_out_trade_no() {
+        // 自动生成的 `out_trade_no` 随请求一起保存, 恢复后用同一个订单号查询和提交
+        let mock = MockTransport::new(vec![]);
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_auto_out_trade_no(true)
+            .with_pending_store(::MemoryPendingStore::default());
+        let mut params = pay_params();
+        params.remove("out_trade_no");
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+        assert!(client.micro_pay(params, None).is_err());
+        let sent = mock.request_params()[0]["out_trade_no"].clone();
+        assert_eq!(sent.len(), 32);
+
+        // 之前的请求已经到达网关并支付成功
+        mock.push(
+            200,
+            &response_xml(&[
+                ("return_code", "SUCCESS"),
+                ("result_code", "SUCCESS"),
+                ("trade_state", "SUCCESS"),
+            ]),
+        );
+        let results = client.flush_pending();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, sent);
+        assert!(results[0].1.is_ok());
+        let requests = mock.request_params();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1]["out_trade_no"], sent);
+    }
+
+    #[test]
+    fn test_error_categories() {
+        use WechatpayError::*;
+        let field = || "total_fee".to_string();
+        // (错误, network, business, validation, crypto)
+        let cases = vec![
+            (Request, [true, false, false, false]),
+            (IncompleteResponse, [true, false, false, false]),
+            (Curl(::curl::Error::new(7)), [true, false, false, false]),
+            (
+                Business {
+                    err_code: ::ErrCode::NotEnough,
+                    err_code_des: "".to_string(),
+                },
+                [false, true, false, false],
+            ),
+            (
+                MissingField {
+                    operation: "unifiedorder",
+                    field: field(),
+                },
+                [false, false, true, false],
+            ),
+            (
+                RedundantField {
+                    operation: "unifiedorder",
+                    field: field(),
+                },
+                [false, false, true, false],
+            ),
+            (
+                InvalidField {
+                    field: field(),
+                    reason: "".to_string(),
+                },
+                [false, false, true, false],
+            ),
+            (SignatureMismatch, [false, false, false, true]),
+            (
+                AmountMismatch {
+                    sent: "1".to_string(),
+                    received: "100".to_string(),
+                },
+                [false, true, false, false],
+            ),
+            (
+                Protocol("签名错误".to_string()),
+                [false, false, false, false],
+            ),
+            (Encoding("gbk".to_string()), [false, false, false, false]),
+            (
+                ResponseTooLarge { limit: 1024 },
+                [false, false, false, false],
+            ),
+            (Unknown, [false, false, false, false]),
+        ];
+        for (error, expected) in cases {
+            let actual = [
+                error.is_network(),
+                error.is_business(),
+                error.is_validation(),
+                error.is_crypto(),
+            ];
+            assert_eq!(actual, expected, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_pay_total_fee_echo() {
+        let echo = |total_fee: &str| {
+            response_xml(&[
+                ("return_code", "SUCCESS"),
+                ("result_code", "SUCCESS"),
+                ("total_fee", total_fee),
+            ])
+        };
+        let sent = pay_params()["total_fee"].clone();
+        let mock = MockTransport::new(vec![(200, &echo(&sent)), (200, &echo("100"))]);
+        let client = test_client().with_transport(mock);
+        let mut params = pay_params();
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+
+        assert!(client.micro_pay(params.clone(), None).is_ok());
+        match client.micro_pay(params, None) {
+            Err(::WechatpayError::AmountMismatch { sent: s, received }) => {
+                assert_eq!(s, sent);
+                assert_eq!(received, "100");
+            }
+            _ => panic!("expected an amount mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_sanitize_auth_code() {
+        assert_eq!(
+            ::sanitize_auth_code("120061098828009406\r\n"),
+            "120061098828009406"
+        );
+        assert_eq!(
+            ::sanitize_auth_code(" １２００６１０９８８２８００９４０６"),
+            "120061098828009406"
+        );
+        assert_eq!(::sanitize_auth_code("1200-6109 8828"), "120061098828");
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let mut params = pay_params();
+        params.insert(
+            "auth_code".to_string(),
+            "１２００６１０９８８２８００９４０６\r\n".to_string(),
+        );
+        client.micro_pay(params, None).unwrap();
+        assert_eq!(
+            mock.request_params()[0].get("auth_code").unwrap(),
+            "120061098828009406"
+        );
+    }
+
+    #[test]
+    fn test_h5_order() {
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_type", "MWEB"),
+            (
+                "mweb_url",
+                "https://wx.tenpay.com/cgi-bin/mmpayweb-bin/checkmweb",
+            ),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let scene = ::H5Info::Wap {
+            wap_url: "https://pay.qq.com".to_string(),
+            wap_name: "腾讯充值".to_string(),
+        };
+        client.h5_order(pay_params(), &scene, None).unwrap();
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent["trade_type"], "MWEB");
+        assert_eq!(
+            sent["scene_info"],
+            r#"{"h5_info":{"type":"Wap","wap_url":"https://pay.qq.com","wap_name":"腾讯充值"}}"#
+        );
+
+        match client.pay(pay_params(), ::TradeType::Mweb, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "scene_info"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let mut params = pay_params();
+        params.insert(
+            "scene_info".to_string(),
+            r#"{"store_info":{"id":"SZTX001"}}"#.to_string(),
+        );
+        match client.pay(params.clone(), ::TradeType::Mweb, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "scene_info"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match client.h5_order(params, &scene, None) {
+            Err(::WechatpayError::RedundantField { field, .. }) => assert_eq!(field, "scene_info"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_micro_pay_store_info() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let store = ::StoreInfo {
+            id: "SZTX001".to_string(),
+            name: Some("腾大餐厅".to_string()),
+            ..Default::default()
+        };
+        let mut params = pay_params();
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+        params.insert("scene_info".to_string(), store.to_scene_info().unwrap());
+        client.micro_pay(params.clone(), None).unwrap();
+        assert_eq!(
+            mock.request_params()[0]["scene_info"],
+            r#"{"store_info":{"id":"SZTX001","name":"腾大餐厅"}}"#
+        );
+
+        params.insert(
+            "scene_info".to_string(),
+            r#"{"h5_info":{"type":"Wap"}}"#.to_string(),
+        );
+        match client.micro_pay(params, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "scene_info"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_micro_pay_device_info() {
+        let success = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("transaction_id", "1217752501201407033233368018"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        let mut params = pay_params();
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+        params.insert("device_info".to_string(), "POS-013".to_string());
+        let result = client.micro_pay(params, None);
+        assert_eq!(
+            mock.request_params()[0].get("device_info").unwrap(),
+            "POS-013"
+        );
+        match ::MicropayOutcome::from_result(result) {
+            outcome @ ::MicropayOutcome::Paid(_) => {
+                assert_eq!(outcome.device_info(), Some("POS-013"))
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_micro_pay_trade_type() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        let mut params = pay_params();
+        params.insert("auth_code".to_string(), "120061098828009406".to_string());
+        params.insert("trade_type".to_string(), "MICROPAY".to_string());
+        client.micro_pay(params, None).unwrap();
+        let mut params = pay_params();
+        params.insert(
+            "openid".to_string(),
+            "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o".to_string(),
+        );
+        client.jsapi_pay(params, None).unwrap();
+
+        let requests = mock.requests();
+        let sent = mock.request_params();
+        assert_eq!(requests[0].0, ::MICROPAY_URL);
+        assert!(!sent[0].contains_key("trade_type"));
+        assert!(::verify_sign(&sent[0], "192006250b4c09247ec02edce69f6a2d"));
+        assert_eq!(requests[1].0, ::UNIFIEDORDER_URL);
+        assert_eq!(sent[1].get("trade_type").unwrap(), "JSAPI");
+    }
+
+    fn refund_params() -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        params.insert("out_refund_no".to_string(), "1415701182".to_string());
+        params.insert("total_fee".to_string(), "100".to_string());
+        params.insert("refund_fee".to_string(), "100".to_string());
+        params
+    }
+
+    #[test]
+    fn test_refund_sign_type() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+
+        client
+            .refund(
+                ::OrderIdentifier::OutTradeNo("1415659990".to_string()),
+                refund_params(),
+                None,
+                None,
+            )
+            .unwrap();
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("sign_type").unwrap(), "HMAC-SHA256");
+        assert_eq!(
+            *sent.get("sign").unwrap(),
+            ::get_sign_with(sent, api_key, ::SignType::HmacSha256)
+        );
+
+        // 显式传入的 sign_type 优先
+        let mut params = refund_params();
+        params.insert("sign_type".to_string(), "MD5".to_string());
+        client
+            .refund(
+                ::OrderIdentifier::OutTradeNo("1415659990".to_string()),
+                params,
+                None,
+                None,
+            )
+            .unwrap();
+        let sent = &mock.request_params()[1];
+        assert_eq!(sent.get("sign_type").unwrap(), "MD5");
+        assert_eq!(*sent.get("sign").unwrap(), ::get_sign(sent, api_key));
+
+        // 普通接口使用 client 的默认签名类型
+        let client = test_client()
+            .with_transport(mock.clone())
+            .with_sign_type(::SignType::HmacSha256);
+        client
+            .query_order(::OrderIdentifier::OutTradeNo("1415659990".to_string()))
+            .unwrap();
+        let sent = &mock.request_params()[2];
+        assert_eq!(sent.get("sign_type").unwrap(), "HMAC-SHA256");
+        assert_eq!(sent.get("sign").unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_explicit_sign_type() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let id = || ::OrderIdentifier::OutTradeNo("1415659990".to_string());
+
+        test_client()
+            .with_transport(mock.clone())
+            .query_order(id())
+            .unwrap();
+        let sent = &mock.request_params()[0];
+        assert!(!sent.contains_key("sign_type"));
+
+        test_client()
+            .with_transport(mock.clone())
+            .with_explicit_sign_type(true)
+            .query_order(id())
+            .unwrap();
+        let sent = &mock.request_params()[1];
+        assert_eq!(sent.get("sign_type").unwrap(), "MD5");
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+    }
+
+    #[test]
+    fn test_sign_casing() {
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+        let mut pairs = BTreeMap::new();
+        pairs.insert("return_code".to_string(), "SUCCESS".to_string());
+        pairs.insert("result_code".to_string(), "SUCCESS".to_string());
+        let sign = ::get_sign(&pairs, api_key);
+        pairs.insert("sign".to_string(), sign.to_ascii_lowercase());
+        assert!(::verify_sign(&pairs, api_key));
+        pairs.insert("sign".to_string(), "0".repeat(32));
+        assert!(!::verify_sign(&pairs, api_key));
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let id = || ::OrderIdentifier::OutTradeNo("1415659990".to_string());
+        test_client()
+            .with_transport(mock.clone())
+            .query_order(id())
+            .unwrap();
+        test_client()
+            .with_transport(mock.clone())
+            .with_sign_casing(::SignCasing::Lower)
+            .query_order(id())
+            .unwrap();
+        let sent = mock.request_params();
+        assert_eq!(sent[0]["sign"], sent[0]["sign"].to_ascii_uppercase());
+        assert_eq!(sent[1]["sign"], sent[1]["sign"].to_ascii_lowercase());
+        assert!(::verify_sign(&sent[1], api_key));
+    }
+
+    #[test]
+    fn test_conflicting_client_fields() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        for &(field, value) in [
+            ("appid", "wx0000000000000000"),
+            ("mch_id", "10000200"),
+            ("nonce_str", "5K8264ILTKCH16CQ2502SI8ZNMTM67VS"),
+        ]
+        .iter()
+        {
+            let mut params = pay_params();
+            params.insert(field.to_string(), value.to_string());
+            match client.app_pay(params, None) {
+                Err(::WechatpayError::RedundantField {
+                    operation,
+                    field: f,
+                }) => {
+                    assert_eq!(operation, "unifiedorder");
+                    assert_eq!(f, field);
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert!(mock.requests().is_empty());
+
+        // 与 client 相同的值不算冲突
+        let mut params = pay_params();
+        params.insert("appid".to_string(), "wxd930ea5d5a258f4f".to_string());
+        params.insert("mch_id".to_string(), "10000100".to_string());
+        client.app_pay(params, None).unwrap();
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_describe() {
+        let description = test_client()
+            .with_endpoints(::Endpoints::hong_kong())
+            .with_sign_type(::SignType::HmacSha256)
+            .describe();
+        assert!(description.contains("appid = wxd930ea5d5a258f4f\n"));
+        assert!(description.contains("endpoint = https://apihk.mch.weixin.qq.com\n"));
+        assert!(description.contains("sign_type = HMAC-SHA256\n"));
+        assert!(description.contains("api_key = ***\n"));
+        assert!(description.contains("cert = ***\n"));
+        assert!(!description.contains("192006250b4c09247ec02edce69f6a2d"));
+        assert!(!description.contains("apiclient_cert.pem"));
+    }
+
+    #[test]
+    fn test_control_char_policy() {
+        let mut params = pay_params();
+        params.insert("body".to_string(), "APP支付\0测试\t".to_string());
+
+        let mock = MockTransport::new(vec![]);
+        match test_client()
+            .with_transport(mock.clone())
+            .app_pay(params.clone(), None)
+        {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "body"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(mock.requests().is_empty());
+
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        test_client()
+            .with_transport(mock.clone())
+            .with_control_char_policy(::ControlCharPolicy::Strip)
+            .app_pay(params, None)
+            .unwrap();
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent["body"], "APP支付测试\t");
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+    }
+
+    #[test]
+    fn test_refund_notify_url() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let id = || ::OrderIdentifier::OutTradeNo("1415659990".to_string());
+        let notify_url = "https://example.com/refund_notify";
+
+        let mut params = refund_params();
+        params.insert("notify_url".to_string(), notify_url.to_string());
+        client.refund(id(), params, None, None).unwrap();
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent["notify_url"], notify_url);
+        assert_eq!(
+            sent["sign"],
+            ::get_sign_with(
+                sent,
+                "192006250b4c09247ec02edce69f6a2d",
+                ::SignType::HmacSha256
+            )
+        );
+
+        let mut params = refund_params();
+        params.insert("notify_url".to_string(), "refund_notify".to_string());
+        match client.refund(id(), params, None, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "notify_url"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_full_refund() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        client
+            .full_refund(
+                ::OrderIdentifier::OutTradeNo("1415659990".to_string()),
+                888,
+                "1415701182",
+            )
+            .unwrap();
+
+        let (url, _, cert) = mock.requests()[0].clone();
+        assert_eq!(url, ::REFUND_URL);
+        assert!(cert.is_some());
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("total_fee").unwrap(), "888");
+        assert_eq!(sent.get("refund_fee").unwrap(), "888");
+        assert_eq!(sent.get("out_refund_no").unwrap(), "1415701182");
+        assert_eq!(sent.get("out_trade_no").unwrap(), "1415659990");
+    }
+
+    #[test]
+    fn test_refund_account() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let id = || ::OrderIdentifier::TransactionId("1217752501201407033233368018".to_string());
+
+        client.refund(id(), refund_params(), None, None).unwrap();
+        client
+            .refund(
+                id(),
+                refund_params(),
+                Some(::RefundAccount::RechargeFunds),
+                None,
+            )
+            .unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(requests[0].0, ::REFUND_URL);
+        assert!(requests[0].2.is_some());
+        let params = mock.request_params();
+        assert!(!params[0].contains_key("refund_account"));
+        assert_eq!(
+            params[1].get("refund_account").unwrap(),
+            "REFUND_SOURCE_RECHARGE_FUNDS"
+        );
+        assert_eq!(
+            params[1].get("transaction_id").unwrap(),
+            "1217752501201407033233368018"
+        );
+
+        let mut params = refund_params();
+        params.insert(
+            "refund_account".to_string(),
+            "REFUND_SOURCE_OTHER".to_string(),
+        );
+        match client.refund(id(), params, None, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => {
+                assert_eq!(field, "refund_account")
+            }
+            _ => panic!("expected an invalid field error"),
+        }
+        assert_eq!(mock.requests().len(), 2);
+
+        let mut params = refund_params();
+        params.remove("refund_fee");
+        match client.refund(id(), params, None, None) {
+            Err(::WechatpayError::MissingField { operation, field }) => {
+                assert_eq!(operation, "refund");
+                assert_eq!(field, "refund_fee");
+            }
+            _ => panic!("expected a missing field error"),
+        }
+    }
+
+    fn pay_params() -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        for &(k, v) in [
+            ("body", "APP支付测试"),
+            ("out_trade_no", "1415659990"),
+            ("total_fee", "1"),
+            ("spbill_create_ip", "14.23.150.211"),
+        ]
+        .iter()
+        {
+            params.insert(k.to_string(), v.to_string());
+        }
+        params
+    }
+
+    #[test]
+    fn test_jsapi_checkout() {
+        let success = signed_response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_type", "JSAPI"),
+            ("prepay_id", "wx201410272009395522657a690389285100"),
+        ]);
+        let no_prepay_id = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &no_prepay_id)]);
+        let client = test_client().with_transport(mock.clone());
+        let mut params = pay_params();
+        params.insert(
+            "openid".to_string(),
+            "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o".to_string(),
+        );
+
+        let frontend = client.jsapi_checkout(params.clone(), None).unwrap();
+        assert_eq!(mock.requests()[0].0, ::UNIFIEDORDER_URL);
+        assert_eq!(
+            frontend.keys().collect::<Vec<&String>>(),
+            vec![
+                "appId",
+                "nonceStr",
+                "package",
+                "paySign",
+                "signType",
+                "timeStamp"
+            ]
+        );
+        assert_eq!(frontend["appId"], "wxd930ea5d5a258f4f");
+        assert_eq!(
+            frontend["package"],
+            "prepay_id=wx201410272009395522657a690389285100"
+        );
+        assert_eq!(frontend["signType"], "MD5");
+        assert_eq!(frontend["timeStamp"].len(), 10);
+        assert!(!frontend["nonceStr"].is_empty());
+        let mut unsigned = frontend.clone();
+        unsigned.remove("paySign");
+        assert_eq!(
+            frontend["paySign"],
+            ::get_sign(&unsigned, "192006250b4c09247ec02edce69f6a2d")
+        );
+
+        match client.jsapi_checkout(params, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "prepay_id"),
+            _ => panic!("expected a missing field error"),
+        }
+    }
+
+    #[test]
+    fn test_sub_merchant_jsapi() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client()
+            .with_sub_merchant("1900000109", Some("wx8888888888888888"))
+            .with_transport(mock.clone());
+
+        let mut params = pay_params();
+        params.insert(
+            "openid".to_string(),
+            "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o".to_string(),
+        );
+        match client.jsapi_pay(params, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "sub_openid"),
+            _ => panic!("expected a missing field error"),
+        }
+
+        let mut params = pay_params();
+        params.insert(
+            "sub_openid".to_string(),
+            "oUpF8uN95-Ptaags6E_roPHg7AG0".to_string(),
+        );
+        client.jsapi_pay(params, None).unwrap();
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("sub_mch_id").unwrap(), "1900000109");
+        assert_eq!(sent.get("sub_appid").unwrap(), "wx8888888888888888");
+        assert_eq!(
+            sent.get("sub_openid").unwrap(),
+            "oUpF8uN95-Ptaags6E_roPHg7AG0"
+        );
+        assert!(!sent.contains_key("openid"));
+
+        // 没有子商户公众账号时仍然使用 `openid`
+        let client = test_client().with_sub_merchant("1900000109", None);
+        let mut params = pay_params();
+        params.insert(
+            "sub_openid".to_string(),
+            "oUpF8uN95-Ptaags6E_roPHg7AG0".to_string(),
+        );
+        match client.jsapi_pay(params, None) {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "openid"),
+            _ => panic!("expected a missing field error"),
+        }
+    }
+
+    #[test]
+    fn test_openid_sub_openid_exclusive() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let openid = "oUpF8uMuAJO_M2pxb1Q9zNjWeS6o".to_string();
+        let sub_openid = "oUpF8uN95-Ptaags6E_roPHg7AG0".to_string();
+
+        let client = test_client().with_transport(mock.clone());
+        let mut params = pay_params();
+        params.insert("openid".to_string(), openid.clone());
+        client.jsapi_pay(params.clone(), None).unwrap();
+        params.insert("sub_openid".to_string(), sub_openid.clone());
+        match client.jsapi_pay(params, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "sub_openid"),
+            _ => panic!("expected an invalid field error"),
+        }
+
+        let client = test_client()
+            .with_sub_merchant("1900000109", Some("wx8888888888888888"))
+            .with_transport(mock.clone());
+        let mut params = pay_params();
+        params.insert("sub_openid".to_string(), sub_openid);
+        client.jsapi_pay(params.clone(), None).unwrap();
+        params.insert("openid".to_string(), openid);
+        match client.jsapi_pay(params, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "sub_openid"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_notify_url_override() {
+        let client = test_client();
+        let params = client.pay_params(pay_params(), ::TradeType::App).unwrap();
+        assert_eq!(
+            params.get("notify_url").unwrap(),
+            "https://example.com/notify"
+        );
+
+        let mut params = pay_params();
+        params.insert(
+            "notify_url".to_string(),
+            "https://tenant.example.com/notify".to_string(),
+        );
+        let params = client.pay_params(params, ::TradeType::App).unwrap();
+        assert_eq!(
+            params.get("notify_url").unwrap(),
+            "https://tenant.example.com/notify"
+        );
+        let mut params = pay_params();
+        params.insert(
+            "notify_url".to_string(),
+            "ftp://example.com/notify".to_string(),
+        );
+        match client.pay_params(params, ::TradeType::App) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "notify_url"),
+            _ => panic!("expected an invalid field error"),
+        }
+
+        let mut params = pay_params();
+        params.insert("notify_url".to_string(), "not a url".to_string());
+        assert!(client.pay_params(params, ::TradeType::App).is_err());
+    }
 
-    use std::collections::BTreeMap;
+    #[test]
+    fn test_require_https_notify() {
+        let http = "http://example.com/notify";
+        let mut params = pay_params();
+        params.insert("notify_url".to_string(), http.to_string());
+        let client = test_client();
+        let sent = client.pay_params(params.clone(), ::TradeType::App).unwrap();
+        assert_eq!(sent.get("notify_url").unwrap(), http);
 
-    use xml::reader::{EventReader, XmlEvent};
+        let client = test_client().with_require_https_notify(true);
+        match client.pay_params(params, ::TradeType::App) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "notify_url"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert!(client.pay_params(pay_params(), ::TradeType::App).is_ok());
+
+        // client 的默认值同样检查
+        let client = ::WechatpayClient::new(
+            "wxd930ea5d5a258f4f",
+            "10000100",
+            "192006250b4c09247ec02edce69f6a2d",
+            http,
+            "/path/to/apiclient_cert.pem",
+        )
+        .with_require_https_notify(true);
+        assert!(client.pay_params(pay_params(), ::TradeType::App).is_err());
+    }
 
     #[test]
-    fn test_from_xml_str() {
-        let source = r#"
-<xml>
-   <return_code><![CDATA[SUCCESS]]></return_code>
-   <return_msg><![CDATA[OK]]></return_msg>
-   <appid><![CDATA[wx2421b1c4370ec43b]]></appid>
-   <mch_id><![CDATA[10000100]]></mch_id>
-   <device_info><![CDATA[1000]]></device_info>
-   <nonce_str><![CDATA[TN55wO9Pba5yENl8]]></nonce_str>
-   <sign><![CDATA[BDF0099C15FF7BC6B1585FBB110AB635]]></sign>
-   <result_code><![CDATA[SUCCESS]]></result_code>
-   <openid><![CDATA[oUpF8uN95-Ptaags6E_roPHg7AG0]]></openid>
-   <is_subscribe><![CDATA[Y]]></is_subscribe>
-   <trade_type><![CDATA[APP]]></trade_type>
-   <bank_type><![CDATA[CCB_DEBIT]]></bank_type>
-   <total_fee>1</total_fee>
-   <fee_type><![CDATA[CNY]]></fee_type>
-   <transaction_id><![CDATA[1008450740201411110005820873]]></transaction_id>
-   <out_trade_no><![CDATA[1415757673]]></out_trade_no>
-   <attach><![CDATA[订单额外描述]]></attach>
-   <time_end><![CDATA[20141111170043]]></time_end>
-   <trade_state><![CDATA[SUCCESS]]></trade_state>
-</xml>
-"#;
-        let pairs = ::from_xml_str(source);
-        for &(k, v) in [
+    fn test_check_params_forbidden() {
+        let client = test_client();
+        let mut params = pay_params();
+        assert!(client
+            .check_params(
+                &params,
+                vec!["key", "sign"],
+                ::ParamsCheckType::Forbidden,
+                "test"
+            )
+            .is_ok());
+
+        params.insert(
+            "sign".to_string(),
+            "0CB01533B8C1EF103065174F50BCA001".to_string(),
+        );
+        match client.check_params(
+            &params,
+            vec!["key", "sign"],
+            ::ParamsCheckType::Forbidden,
+            "test",
+        ) {
+            Err(::WechatpayError::RedundantField { field, .. }) => assert_eq!(field, "sign"),
+            _ => panic!("expected a redundant field error"),
+        }
+    }
+
+    #[test]
+    fn test_pay_forbidden_fields() {
+        let success = response_xml(&[
             ("return_code", "SUCCESS"),
-            ("return_msg", "OK"),
-            ("appid", "wx2421b1c4370ec43b"),
-            ("mch_id", "10000100"),
             ("result_code", "SUCCESS"),
-            ("attach", "订单额外描述"),
-            ("transaction_id", "1008450740201411110005820873"),
-            ("time_end", "20141111170043"),
-            ("trade_type", "APP"),
+            ("prepay_id", "wx201410272009395522657a690389285100"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let pairs = client.app_pay(pay_params(), None).unwrap();
+        assert_eq!(
+            pairs.get("prepay_id").unwrap(),
+            "wx201410272009395522657a690389285100"
+        );
+        let sent = &mock.request_params()[0];
+        assert_eq!(mock.requests()[0].0, ::UNIFIEDORDER_URL);
+        assert_eq!(sent.get("trade_type").unwrap(), "APP");
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+
+        let mut params = pay_params();
+        params.insert(
+            "key".to_string(),
+            "192006250b4c09247ec02edce69f6a2d".to_string(),
+        );
+        match client.app_pay(params, None) {
+            Err(::WechatpayError::RedundantField { operation, field }) => {
+                assert_eq!(operation, "unifiedorder");
+                assert_eq!(field, "key");
+            }
+            _ => panic!("expected a redundant field error"),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_pay_byte_lengths() {
+        let mock = MockTransport::new(vec![]);
+        let client = test_client().with_transport(mock.clone());
+
+        let mut params = pay_params();
+        let body = "微信支付测试商品充值".repeat(4) + "中心会员卡";
+        assert_eq!(body.chars().count(), 45);
+        params.insert("body".to_string(), body);
+        match client.app_pay(params, None) {
+            Err(::WechatpayError::InvalidField { field, reason }) => {
+                assert_eq!(field, "body");
+                assert!(reason.starts_with("135 bytes"), "{}", reason);
+            }
+            _ => panic!("expected an invalid field error"),
+        }
+
+        let mut params = pay_params();
+        params.insert("attach".to_string(), "a".repeat(128));
+        match client.app_pay(params, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "attach"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn test_app_pay_extra_params() {
+        let scene_info = r#"{"store_info":{"id":"SZTX001","name":"腾大餐厅"}}"#;
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        let mut params = pay_params();
+        params.insert("scene_info".to_string(), scene_info.to_string());
+        params.insert("limit_pay".to_string(), "no_credit".to_string());
+        client.app_pay(params.clone(), None).unwrap();
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("scene_info").unwrap(), scene_info);
+        assert_eq!(sent.get("limit_pay").unwrap(), "no_credit");
+        assert!(::verify_sign(sent, "192006250b4c09247ec02edce69f6a2d"));
+
+        let mut invalid = params.clone();
+        invalid.insert("scene_info".to_string(), "store SZTX001".to_string());
+        match client.app_pay(invalid, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "scene_info"),
+            _ => panic!("expected an invalid field error"),
+        }
+        let mut invalid = params.clone();
+        invalid.insert("ext_data".to_string(), "{".to_string());
+        assert!(client.app_pay(invalid, None).is_err());
+        let mut invalid = params;
+        invalid.insert("limit_pay".to_string(), "credit".to_string());
+        assert!(client.app_pay(invalid, None).is_err());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_force_cert() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client()
+            .with_force_cert(false)
+            .with_transport(mock.clone());
+        client.refund(id(), refund_params(), None, None).unwrap();
+        assert_eq!(mock.requests()[0].2, None);
+
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client()
+            .with_force_cert(true)
+            .with_transport(mock.clone());
+        client.query_order(id()).unwrap();
+        assert_eq!(
+            mock.requests()[0].2,
+            Some("/path/to/apiclient_cert.pem".to_string())
+        );
+    }
+
+    #[test]
+    fn test_is_fully_refunded() {
+        let order = "<xml><return_code><![CDATA[SUCCESS]]></return_code>\
+                     <result_code><![CDATA[SUCCESS]]></result_code>\
+                     <trade_state><![CDATA[REFUND]]></trade_state>\
+                     <total_fee>100</total_fee></xml>";
+        let partial = "<xml><return_code><![CDATA[SUCCESS]]></return_code>\
+                       <result_code><![CDATA[SUCCESS]]></result_code>\
+                       <total_fee>100</total_fee><refund_count>2</refund_count>\
+                       <refund_fee_0>30</refund_fee_0>\
+                       <refund_status_0><![CDATA[SUCCESS]]></refund_status_0>\
+                       <refund_fee_1>70</refund_fee_1>\
+                       <refund_status_1><![CDATA[REFUNDCLOSE]]></refund_status_1></xml>";
+        let full = "<xml><return_code><![CDATA[SUCCESS]]></return_code>\
+                    <result_code><![CDATA[SUCCESS]]></result_code>\
+                    <total_fee>100</total_fee><refund_count>2</refund_count>\
+                    <refund_fee_0>30</refund_fee_0>\
+                    <refund_status_0><![CDATA[SUCCESS]]></refund_status_0>\
+                    <refund_fee_1>70</refund_fee_1>\
+                    <refund_status_1><![CDATA[PROCESSING]]></refund_status_1></xml>";
+        let id = || ::OrderIdentifier::OutTradeNo("1415757673".to_string());
+
+        let mock = MockTransport::new(vec![(200, order), (200, partial)]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(!client.is_fully_refunded(id()).unwrap());
+        assert_eq!(mock.requests()[1].0, ::REFUNDQUERY_URL);
+
+        let mock = MockTransport::new(vec![(200, order), (200, full)]);
+        let client = test_client().with_transport(mock);
+        assert!(client.is_fully_refunded(id()).unwrap());
+
+        let paid = response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_state", "SUCCESS"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &paid)]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(!client.is_fully_refunded(id()).unwrap());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_sign() {
+        let mut pairs = BTreeMap::new();
+        for &(k, v) in [
+            ("appid", "wxd930ea5d5a258f4f"),
+            ("mch_id", "10000100"),
+            ("device_info", "1000"),
+            ("body", "test"),
+            ("nonce_str", "ibuaiVcKdpRxkhJA"),
         ]
         .iter()
         {
-            assert_eq!(pairs.get(k), Some(&v.to_string()));
+            pairs.insert(k.to_string(), v.to_string());
         }
+        let api_key = "192006250b4c09247ec02edce69f6a2d".to_string();
+        assert_eq!(
+            ::get_sign(&pairs, &api_key),
+            "9A0A8659F005D6984697E2CA0A9CF3B7"
+        );
     }
 
-    fn check_xml_str(pairs: &BTreeMap<String, String>, data: &str) {
-        let reader = EventReader::from_str(data);
-        let mut tag: String = "".to_string();
-        for event in reader {
-            match event {
-                Ok(XmlEvent::StartElement { name, .. }) => {
-                    tag = name.local_name;
-                }
-                Ok(XmlEvent::Characters(s)) => {
-                    assert_eq!(Some(&s), pairs.get(&tag));
-                }
-                Err(e) => {
-                    panic!(format!("Parse error: {:?}", e));
-                }
-                _ => {}
-            }
+    #[test]
+    fn test_sign_ascii_order() {
+        let mut pairs = BTreeMap::new();
+        // 按字节排序: 大写字母 < `_` < 小写字母, 数字在字母之前
+        for &(k, v) in [
+            ("nonce_str", "1"),
+            ("nonceStr", "2"),
+            ("Zeta", "3"),
+            ("alpha", "4"),
+            ("a_b", "5"),
+            ("aB", "6"),
+            ("a1", "7"),
+        ]
+        .iter()
+        {
+            pairs.insert(k.to_string(), v.to_string());
         }
+        assert_eq!(
+            ::sign_source_string(&pairs, "key"),
+            "Zeta=3&a1=7&aB=6&a_b=5&alpha=4&nonceStr=2&nonce_str=1&key=key"
+        );
     }
 
     #[test]
-    fn test_to_xml_str() {
-        let output = r#"
-<xml>
-   <appid>wx2421b1c4370ec43b</appid>
-   <attach>支付测试</attach>
-   <body>APP支付测试</body>
-   <mch_id>10000100</mch_id>
-   <nonce_str>1add1a30ac87aa2db72f57a2375d8fec</nonce_str>
-   <notify_url>http://wxpay.weixin.qq.com/pub_v2/pay/notify.v2.php</notify_url>
-   <out_trade_no>1415659990</out_trade_no>
-   <spbill_create_ip>14.23.150.211</spbill_create_ip>
-   <total_fee>1</total_fee>
-   <trade_type>APP</trade_type>
-   <sign>0CB01533B8C1EF103065174F50BCA001</sign>
-</xml>
-"#;
+    fn test_sign_hmac_sha256() {
         let mut pairs = BTreeMap::new();
         for &(k, v) in [
-            ("appid", "wx2421b1c4370ec43b"),
-            ("attach", "支付测试"),
-            ("body", "APP支付测试"),
+            ("appid", "wxd930ea5d5a258f4f"),
             ("mch_id", "10000100"),
-            ("nonce_str", "1add1a30ac87aa2db72f57a2375d8fec"),
-            (
-                "notify_url",
-                "http://wxpay.weixin.qq.com/pub_v2/pay/notify.v2.php",
-            ),
-            ("out_trade_no", "1415659990"),
-            ("spbill_create_ip", "14.23.150.211"),
-            ("total_fee", "1"),
-            ("trade_type", "APP"),
-            ("sign", "0CB01533B8C1EF103065174F50BCA001"),
+            ("device_info", "1000"),
+            ("body", "test"),
+            ("nonce_str", "ibuaiVcKdpRxkhJA"),
         ]
         .iter()
         {
             pairs.insert(k.to_string(), v.to_string());
         }
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+        assert_eq!(
+            ::get_sign_with(&pairs, api_key, ::SignType::HmacSha256),
+            "6A9AE1657590FD6257D693A078E1C3E4BB6BA4DC30B23E0EE2496E54170DACD6"
+        );
+        assert_eq!(
+            ::get_sign_with(&pairs, api_key, ::SignType::Md5),
+            "9A0A8659F005D6984697E2CA0A9CF3B7"
+        );
+    }
 
-        check_xml_str(&pairs, output);
-        check_xml_str(&pairs, &(::to_xml_str(&pairs)));
+    #[test]
+    fn test_unified_order_with_profit_sharing() {
+        let success = signed_response_xml(&[
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("prepay_id", "wx201410272009395522657a690389285100"),
+        ]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+
+        let response = client
+            .unified_order_with_profit_sharing(pay_params(), ::TradeType::App, None)
+            .unwrap();
+        assert!(response.requires_profit_sharing_followup());
+        assert_eq!(mock.request_params()[0]["profit_sharing"], "Y");
+
+        let response = client
+            .unified_order(pay_params(), ::TradeType::App, None)
+            .unwrap();
+        assert!(!response.requires_profit_sharing_followup());
+        assert!(!mock.request_params()[1].contains_key("profit_sharing"));
+
+        let mut params = pay_params();
+        params.insert("profit_sharing".to_string(), "yes".to_string());
+        match client.unified_order(params, ::TradeType::App, None) {
+            Err(::WechatpayError::InvalidField { field, .. }) => {
+                assert_eq!(field, "profit_sharing")
+            }
+            _ => panic!("expected an invalid field error"),
+        }
+        assert_eq!(mock.requests().len(), 2);
     }
 
     #[test]
-    fn test_trade_amount() {
-        assert_eq!(::get_trade_amount(0.99), 99_u32);
-        assert_eq!(::get_trade_amount(0.999), 100_u32);
-        assert_eq!(::get_trade_amount(3.3), 330_u32);
-        assert_eq!(::get_trade_amount(20_f32), 2000_u32);
+    fn test_profit_sharing_finish() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        client
+            .profit_sharing_finish(
+                "4208450740201411110007820472",
+                "P20150806125346",
+                "分账已完成",
+            )
+            .unwrap();
+
+        let (url, _, cert) = mock.requests()[0].clone();
+        assert_eq!(url, ::PROFITSHARINGFINISH_URL);
+        assert_eq!(cert, Some("/path/to/apiclient_cert.pem".to_string()));
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent.get("sign_type").unwrap(), "HMAC-SHA256");
+        let sign = sent.get("sign").unwrap();
+        assert_eq!(sign.len(), 64);
+        assert_eq!(
+            *sign,
+            ::get_sign_with(
+                sent,
+                "192006250b4c09247ec02edce69f6a2d",
+                ::SignType::HmacSha256
+            )
+        );
+
+        match client.profit_sharing_finish("4208450740201411110007820472", "", "分账已完成") {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "out_order_no"),
+            _ => panic!("expected a missing field error"),
+        }
     }
 
     #[test]
-    fn test_string_length() {
-        assert_eq!(format!("{}", ::get_timestamp()).len(), 10);
-        assert_eq!(::get_time_str().len(), 14);
-        assert_eq!(::get_nonce_str().len(), 32);
-        assert_eq!(::get_order_no().len(), 32);
+    fn test_profit_sharing_receiver() {
+        let success = response_xml(&[("return_code", "SUCCESS"), ("result_code", "SUCCESS")]);
+        let mock = MockTransport::new(vec![(200, &success), (200, &success)]);
+        let client = test_client().with_transport(mock.clone());
+        let receiver = r#"{"type":"MERCHANT_ID","account":"190001001","name":"示例商户<&>","relation_type":"STORE_OWNER"}"#;
+        client.profit_sharing_add_receiver(receiver).unwrap();
+        client
+            .profit_sharing_remove_receiver(r#"{"type":"MERCHANT_ID","account":"190001001"}"#)
+            .unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(requests[0].0, ::PROFITSHARINGADDRECEIVER_URL);
+        assert_eq!(requests[1].0, ::PROFITSHARINGREMOVERECEIVER_URL);
+        for (url, _, cert) in requests {
+            assert_eq!(
+                cert,
+                Some("/path/to/apiclient_cert.pem".to_string()),
+                "{}",
+                url
+            );
+        }
+        let sent = &mock.request_params()[0];
+        assert_eq!(sent["receiver"], receiver);
+        assert_eq!(sent["sign_type"], "HMAC-SHA256");
+        assert_eq!(
+            sent["sign"],
+            ::get_sign_with(
+                sent,
+                "192006250b4c09247ec02edce69f6a2d",
+                ::SignType::HmacSha256
+            )
+        );
+
+        match client.profit_sharing_add_receiver("") {
+            Err(::WechatpayError::MissingField { field, .. }) => assert_eq!(field, "receiver"),
+            _ => panic!("expected a missing field error"),
+        }
+        match client.profit_sharing_add_receiver("MERCHANT_ID") {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "receiver"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert_eq!(mock.requests().len(), 2);
     }
 
     #[test]
-    fn test_sign() {
+    fn test_try_get_sign() {
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+        let mut pairs = BTreeMap::new();
+        pairs.insert("appid".to_string(), "".to_string());
+        pairs.insert("mch_id".to_string(), "".to_string());
+        pairs.insert(
+            "sign".to_string(),
+            "9A0A8659F005D6984697E2CA0A9CF3B7".to_string(),
+        );
+        match ::try_get_sign(&pairs, api_key) {
+            Err(::WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "sign"),
+            _ => panic!("expected an invalid field error"),
+        }
+        assert!(::try_get_sign(&BTreeMap::new(), api_key).is_err());
+
+        // 请求在签名前检查, 不会发出
+        let mock = MockTransport::new(vec![]);
+        let client = test_client().with_transport(mock.clone());
+        assert!(client
+            .request_raw(::ORDERQUERY_URL, pairs.clone(), None, false)
+            .is_err());
+        assert!(mock.requests().is_empty());
+
+        pairs.insert("appid".to_string(), "wxd930ea5d5a258f4f".to_string());
+        pairs.insert("nonce_str".to_string(), "ibuaiVcKdpRxkhJA".to_string());
+        assert_eq!(
+            ::try_get_sign(&pairs, api_key).unwrap(),
+            ::get_sign(&pairs, api_key)
+        );
+    }
+
+    #[test]
+    fn test_sign_source_string() {
         let mut pairs = BTreeMap::new();
         for &(k, v) in [
             ("appid", "wxd930ea5d5a258f4f"),
@@ -546,14 +6582,90 @@ mod tests {
             ("device_info", "1000"),
             ("body", "test"),
             ("nonce_str", "ibuaiVcKdpRxkhJA"),
+            ("attach", ""),
         ]
         .iter()
         {
             pairs.insert(k.to_string(), v.to_string());
         }
-        let api_key = "192006250b4c09247ec02edce69f6a2d".to_string();
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
         assert_eq!(
-            ::get_sign(&pairs, &api_key),
+            ::sign_source_string(&pairs, api_key),
+            "appid=wxd930ea5d5a258f4f&body=test&device_info=1000&mch_id=10000100\
+             &nonce_str=ibuaiVcKdpRxkhJA&key=192006250b4c09247ec02edce69f6a2d"
+        );
+
+        // 参数值不做 URL 编码
+        pairs.insert("body".to_string(), "腾讯充值中心-QQ会员充值".to_string());
+        assert!(::sign_source_string(&pairs, api_key).contains("body=腾讯充值中心-QQ会员充值&"));
+    }
+
+    #[test]
+    fn test_get_sign_raw_values() {
+        // 按签名规则直接拼接原值: 中文、空格以及值中的 `&` 和 `=` 都不做 URL 编码,
+        // 否则与网关计算的签名不一致
+        let mut pairs = BTreeMap::new();
+        for &(k, v) in [
+            ("appid", "wxd930ea5d5a258f4f"),
+            ("mch_id", "10000100"),
+            ("body", "腾讯充值中心-QQ会员充值"),
+            ("nonce_str", "ibuaiVcKdpRxkhJA"),
+            ("out_trade_no", "1415659990"),
+            ("spbill_create_ip", "14.23.150.211"),
+            ("total_fee", "1"),
+        ]
+        .iter()
+        {
+            pairs.insert(k.to_string(), v.to_string());
+        }
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+        assert_eq!(
+            ::get_sign(&pairs, api_key),
+            "0465ACE5F1C11EEC4331CE4027A709C5"
+        );
+        pairs.insert("attach".to_string(), "a=1&b=2".to_string());
+        assert_eq!(
+            ::get_sign(&pairs, api_key),
+            "5CFA2A9E27F9C1E950DE896CC0AE6B72"
+        );
+        pairs.remove("attach");
+        pairs.insert("body".to_string(), "Tencent QQ".to_string());
+        assert_eq!(
+            ::get_sign(&pairs, api_key),
+            "642E2E46AAFCF950607FC8CF33EE3F6C"
+        );
+    }
+
+    #[test]
+    fn test_sign_excluding() {
+        let mut pairs = BTreeMap::new();
+        for &(k, v) in [
+            ("appid", "wxd930ea5d5a258f4f"),
+            ("mch_id", "10000100"),
+            ("device_info", "1000"),
+            ("body", "test"),
+            ("nonce_str", "ibuaiVcKdpRxkhJA"),
+        ]
+        .iter()
+        {
+            pairs.insert(k.to_string(), v.to_string());
+        }
+        let api_key = "192006250b4c09247ec02edce69f6a2d";
+        assert_eq!(
+            ::get_sign_excluding(&pairs, api_key, &[]),
+            ::get_sign(&pairs, api_key)
+        );
+
+        pairs.insert(
+            "req_info".to_string(),
+            "T87GAHG17TGAHG1TGHAHAHA1Y1CIOA".to_string(),
+        );
+        assert_ne!(
+            ::get_sign(&pairs, api_key),
+            "9A0A8659F005D6984697E2CA0A9CF3B7"
+        );
+        assert_eq!(
+            ::get_sign_excluding(&pairs, api_key, &["req_info"]),
             "9A0A8659F005D6984697E2CA0A9CF3B7"
         );
     }
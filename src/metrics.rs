@@ -0,0 +1,38 @@
+//! 请求指标
+//!
+//! 每次调用接口前后回调 `Metrics`, 可以接入 Prometheus 等监控系统统计各接口的耗时和错误率。
+
+use std::time::Duration;
+
+use WechatpayError;
+
+/// 接口调用的回调, 默认实现不做任何事
+///
+/// `operation` 为接口名, 即 URL 的最后一段 (如 `unifiedorder`, `refund`)
+pub trait Metrics: Send + Sync {
+    /// 开始调用接口
+    fn on_request_start(&self, operation: &str) {
+        let _ = operation;
+    }
+
+    /// 接口调用结束, `duration` 包括所有重试; 业务错误 (`result_code` 为 `FAIL`) 也算作失败
+    fn on_request_end(
+        &self,
+        operation: &str,
+        duration: Duration,
+        outcome: Result<(), &WechatpayError>,
+    ) {
+        let _ = (operation, duration, outcome);
+    }
+}
+
+/// 不记录任何指标, 为 client 的默认值
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// 接口 `url` 的接口名
+pub(crate) fn operation_name(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or(url)
+}
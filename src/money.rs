@@ -0,0 +1,162 @@
+//! 金额
+//!
+//! 接口中的金额参数 (如 `total_fee`) 都是以【分】为单位的整数, 这里用 `Money` 封装,
+//! 避免【元】和【分】混用以及不同币种之间的运算。
+
+use std::fmt;
+
+use CURRENCY_CNY;
+
+/// 支持的币种 (`fee_type`)
+const CURRENCIES: &[&str] = &[
+    CURRENCY_CNY,
+    "HKD",
+    "USD",
+    "EUR",
+    "GBP",
+    "JPY",
+    "CAD",
+    "AUD",
+    "NZD",
+    "KRW",
+    "SGD",
+];
+
+/// 将返回结果中的 `fee_type` 转换为支持的币种
+pub(crate) fn known_currency(fee_type: &str) -> Option<&'static str> {
+    CURRENCIES.iter().cloned().find(|&c| c == fee_type)
+}
+
+/// 金额, 内部以最小货币单位 (人民币为【分】) 保存
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Money {
+    cents: u64,
+    currency: &'static str,
+}
+
+impl Money {
+    /// 以【分】为单位的人民币金额
+    pub fn from_cents(cents: u64) -> Money {
+        Money {
+            cents,
+            currency: CURRENCY_CNY,
+        }
+    }
+
+    /// 解析以【元】为单位的人民币金额 (如 `12.5`, `0.01`), 最多两位小数
+    pub fn from_yuan(yuan: &str) -> Option<Money> {
+        let mut parts = yuan.splitn(2, '.');
+        let integer = parts.next().unwrap_or("");
+        let fraction = parts.next().unwrap_or("");
+        if integer.is_empty()
+            || !integer.chars().all(|c| c.is_ascii_digit())
+            || fraction.len() > 2
+            || !fraction.chars().all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+        let fraction = format!("{:0<2}", fraction).parse::<u64>().ok()?;
+        let cents = integer
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(100)?
+            .checked_add(fraction)?;
+        Some(Money::from_cents(cents))
+    }
+
+    /// 指定币种 (`fee_type`), 如 `HKD`, `USD`
+    pub fn with_currency(mut self, currency: &'static str) -> Money {
+        self.currency = currency;
+        self
+    }
+
+    /// 最小货币单位的数量
+    pub fn cents(&self) -> u64 {
+        self.cents
+    }
+
+    /// 币种
+    pub fn currency(&self) -> &'static str {
+        self.currency
+    }
+
+    /// 请求参数中的金额: 以【分】为单位的整数字符串
+    pub fn to_fee_string(&self) -> String {
+        self.cents.to_string()
+    }
+
+    /// 相加, 币种不同或溢出时返回 `None`
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money {
+            cents: self.cents.checked_add(other.cents)?,
+            currency: self.currency,
+        })
+    }
+
+    /// 相减, 币种不同或结果为负数时返回 `None`
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money {
+            cents: self.cents.checked_sub(other.cents)?,
+            currency: self.currency,
+        })
+    }
+}
+
+/// 以【元】为单位显示, 保留两位小数
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}", self.cents / 100, self.cents % 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Money;
+
+    #[test]
+    fn test_money_parse_and_format() {
+        assert_eq!(Money::from_yuan("0.01"), Some(Money::from_cents(1)));
+        assert_eq!(Money::from_yuan("12.5"), Some(Money::from_cents(1250)));
+        assert_eq!(Money::from_yuan("3"), Some(Money::from_cents(300)));
+        for invalid in [
+            "",
+            "1.001",
+            "abc",
+            "-1",
+            "1.-1",
+            ".5",
+            "184467440737095516.16",
+        ]
+        .iter()
+        {
+            assert_eq!(Money::from_yuan(invalid), None, "{}", invalid);
+        }
+
+        let money = Money::from_cents(1250);
+        assert_eq!(money.to_string(), "12.50");
+        assert_eq!(money.to_fee_string(), "1250");
+        assert_eq!(money.currency(), "CNY");
+        assert_eq!(Money::from_cents(7).to_string(), "0.07");
+    }
+
+    #[test]
+    fn test_money_checked_arithmetic() {
+        let a = Money::from_cents(100);
+        let b = Money::from_cents(30);
+        assert_eq!(a.checked_add(b), Some(Money::from_cents(130)));
+        assert_eq!(a.checked_sub(b), Some(Money::from_cents(70)));
+        assert_eq!(b.checked_sub(a), None);
+        assert_eq!(Money::from_cents(u64::MAX).checked_add(b), None);
+        assert_eq!(a.checked_add(b.with_currency("HKD")), None);
+        assert_eq!(
+            a.with_currency("HKD").checked_add(b.with_currency("HKD")),
+            Some(Money::from_cents(130).with_currency("HKD"))
+        );
+    }
+}
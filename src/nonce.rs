@@ -0,0 +1,73 @@
+//! 随机字符串 (`nonce_str`) 生成
+//!
+//! 默认使用随机 UUID; 测试中可以替换为 `SeededNonce`, 使签名后的请求数据可以重现,
+//! 用于快照对比。
+
+use std::sync::Mutex;
+
+use get_nonce_str;
+
+/// 生成请求中的 `nonce_str`
+pub trait NonceGenerator: Send + Sync {
+    /// 返回不超过 32 个字符的随机字符串
+    fn nonce_str(&self) -> String;
+}
+
+/// 随机 UUID (`get_nonce_str`), 为 client 的默认值
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomNonce;
+
+impl NonceGenerator for RandomNonce {
+    fn nonce_str(&self) -> String {
+        get_nonce_str()
+    }
+}
+
+/// 由种子确定的伪随机序列, 相同的种子总是生成相同的 `nonce_str` 序列
+///
+/// 不可预测性是 `nonce_str` 的用途之一, 只应在测试中使用
+#[derive(Debug)]
+pub struct SeededNonce {
+    state: Mutex<u64>,
+}
+
+impl SeededNonce {
+    pub fn new(seed: u64) -> SeededNonce {
+        SeededNonce {
+            state: Mutex::new(seed),
+        }
+    }
+}
+
+impl NonceGenerator for SeededNonce {
+    fn nonce_str(&self) -> String {
+        let mut state = self.state.lock().unwrap();
+        let mut next = || {
+            // splitmix64
+            *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = *state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        format!("{:016x}{:016x}", next(), next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NonceGenerator, RandomNonce, SeededNonce};
+
+    #[test]
+    fn test_seeded_nonce() {
+        let nonce = SeededNonce::new(42);
+        let first = nonce.nonce_str();
+        assert_eq!(first.len(), 32);
+        assert_ne!(nonce.nonce_str(), first);
+
+        let nonce = SeededNonce::new(42);
+        assert_eq!(nonce.nonce_str(), first);
+        assert_ne!(SeededNonce::new(43).nonce_str(), first);
+        assert_ne!(RandomNonce.nonce_str(), RandomNonce.nonce_str());
+    }
+}
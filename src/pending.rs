@@ -0,0 +1,31 @@
+//! 刷卡支付离线队列
+//!
+//! 网络中断时无法提交的刷卡支付先保存起来, 恢复后由 `WechatpayClient::flush_pending`
+//! 重新提交。
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// 待提交的刷卡支付请求的存储, 可以替换为持久化实现
+pub trait PendingStore: Send + Sync {
+    /// 保存一个请求参数
+    fn push(&self, params: BTreeMap<String, String>);
+    /// 取出所有保存的请求参数
+    fn take_all(&self) -> Vec<BTreeMap<String, String>>;
+}
+
+/// 保存在内存中的 `PendingStore`, 进程退出后丢失
+#[derive(Debug, Default)]
+pub struct MemoryPendingStore {
+    pending: Mutex<Vec<BTreeMap<String, String>>>,
+}
+
+impl PendingStore for MemoryPendingStore {
+    fn push(&self, params: BTreeMap<String, String>) {
+        self.pending.lock().unwrap().push(params);
+    }
+
+    fn take_all(&self) -> Vec<BTreeMap<String, String>> {
+        self.pending.lock().unwrap().drain(..).collect()
+    }
+}
@@ -0,0 +1,222 @@
+//! 下单预览
+//!
+//! 在提交之前生成订单摘要 (如金额、交易类型), 用于确认界面, 不签名也不发送请求。
+
+use std::collections::BTreeMap;
+
+use {
+    from_xml_str, sign_source, to_xml_str, Money, SignType, TradeType, WechatpayClient,
+    WechatpayError,
+};
+
+/// 订单预览
+#[derive(Debug)]
+pub struct OrderPreview {
+    /// 订单金额, 单位为【元】, `total_fee` 不合法时为空
+    pub amount_yuan: String,
+    /// 交易类型
+    pub trade_type: TradeType,
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 交易结束时间 (`time_expire`)
+    pub time_expire: Option<String>,
+    /// 提交时会失败的参数问题, 为空时表示参数检查通过
+    pub warnings: Vec<WechatpayError>,
+}
+
+impl OrderPreview {
+    /// 参数检查是否通过
+    pub fn is_valid(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl WechatpayClient {
+    /// 预览下单参数, 进行和 `pay` 相同的参数检查但不签名也不发送请求
+    pub fn preview(
+        &self,
+        params: &BTreeMap<String, String>,
+        trade_type: TradeType,
+    ) -> OrderPreview {
+        let mut params = params.clone();
+        let mut warnings = Vec::new();
+        if let Err(e) = self.fill_pay_defaults(&mut params) {
+            warnings.push(e);
+        }
+        warnings.extend(self.pay_param_errors(&params, trade_type));
+
+        let amount_yuan = match params.get("total_fee") {
+            Some(total_fee) => match total_fee.parse() {
+                Ok(cents) => Money::from_cents(cents).to_string(),
+                Err(_) => {
+                    warnings.push(WechatpayError::InvalidField {
+                        field: "total_fee".to_string(),
+                        reason: format!("{} is not a number", total_fee),
+                    });
+                    String::new()
+                }
+            },
+            None => String::new(),
+        };
+
+        OrderPreview {
+            amount_yuan,
+            trade_type,
+            out_trade_no: params.get("out_trade_no").cloned().unwrap_or_default(),
+            time_expire: params.get("time_expire").cloned(),
+            warnings,
+        }
+    }
+
+    /// 生成签名后的下单请求, 序列化为 `xml` 再解析回来, 检查签名仍然正确
+    ///
+    /// 不发送请求, 用于在 CI 中发现签名或序列化中改变了参数值的问题
+    pub fn self_check(
+        &self,
+        params: &BTreeMap<String, String>,
+        trade_type: TradeType,
+    ) -> Result<(), WechatpayError> {
+        self.self_check_with(params, trade_type, to_xml_str)
+    }
+
+    fn self_check_with<F>(
+        &self,
+        params: &BTreeMap<String, String>,
+        trade_type: TradeType,
+        serialize: F,
+    ) -> Result<(), WechatpayError>
+    where
+        F: Fn(&BTreeMap<String, String>) -> String,
+    {
+        let (params, _) = self.build_pay_params(params.clone(), trade_type)?;
+        let signed = self.sign_params(params, false)?;
+        let received = from_xml_str(&serialize(&signed));
+        let expected = self.signer.sign(
+            &sign_source(&received, &[]),
+            SignType::from_params(&received),
+        );
+        // `sign` 的大小写由 `with_sign_casing` 决定, 不影响签名是否正确
+        match received.get("sign") {
+            Some(sign) if sign.eq_ignore_ascii_case(&expected) => Ok(()),
+            _ => Err(WechatpayError::SignatureMismatch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tests::test_client;
+    use {SignCasing, SignType, TradeType, WechatpayError};
+
+    #[test]
+    fn test_preview() {
+        let mut params = BTreeMap::new();
+        for &(k, v) in [
+            ("body", "腾讯充值中心-QQ会员充值"),
+            ("out_trade_no", "20150806125346"),
+            ("total_fee", "1250"),
+            ("spbill_create_ip", "123.12.12.123"),
+            ("product_id", "12235413214070356458058"),
+            ("time_expire", "20091227091010"),
+        ]
+        .iter()
+        {
+            params.insert(k.to_string(), v.to_string());
+        }
+
+        let preview = test_client().preview(&params, TradeType::Native);
+        assert!(preview.is_valid(), "{:?}", preview.warnings);
+        assert_eq!(preview.amount_yuan, "12.50");
+        assert_eq!(preview.trade_type, TradeType::Native);
+        assert_eq!(preview.out_trade_no, "20150806125346");
+        assert_eq!(preview.time_expire, Some("20091227091010".to_string()));
+        // 预览不修改参数, 也不签名
+        assert!(!params.contains_key("sign"));
+        assert!(!params.contains_key("nonce_str"));
+
+        params.remove("product_id");
+        params.insert("total_fee".to_string(), "12.5".to_string());
+        params.insert("sign".to_string(), "".to_string());
+        // 和 `pay` 相同: 不能传 `nonce_str`, 商户字段必须与 client 一致
+        params.insert("nonce_str".to_string(), "5K8264ILTKCH16CQ".to_string());
+        params.insert("appid".to_string(), "wx2421b1c4370ec43b".to_string());
+        params.insert("profit_sharing".to_string(), "yes".to_string());
+        let preview = test_client().preview(&params, TradeType::Native);
+        assert_eq!(preview.amount_yuan, "");
+        let fields = preview
+            .warnings
+            .iter()
+            .map(|e| match *e {
+                WechatpayError::MissingField { ref field, .. }
+                | WechatpayError::RedundantField { ref field, .. }
+                | WechatpayError::InvalidField { ref field, .. } => field.as_str(),
+                _ => panic!("unexpected warning {:?}", e),
+            })
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            fields,
+            vec![
+                "sign",
+                "nonce_str",
+                "appid",
+                "product_id",
+                "profit_sharing",
+                "total_fee"
+            ]
+        );
+        // 预览给出的第一个问题就是 `pay` 返回的错误
+        match test_client().pay(params, TradeType::Native, None) {
+            Err(WechatpayError::RedundantField { field, .. }) => assert_eq!(field, "sign"),
+            result => panic!("expected a redundant field error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_self_check() {
+        let mut params = BTreeMap::new();
+        for &(k, v) in [
+            ("body", "腾讯充值中心-QQ会员充值 "),
+            ("attach", "<a & b>"),
+            ("out_trade_no", "20150806125346"),
+            ("total_fee", "1250"),
+            ("spbill_create_ip", "123.12.12.123"),
+            ("product_id", "12235413214070356458058"),
+        ]
+        .iter()
+        {
+            params.insert(k.to_string(), v.to_string());
+        }
+        test_client()
+            .self_check(&params, TradeType::Native)
+            .unwrap();
+        test_client()
+            .with_sign_type(SignType::HmacSha256)
+            .self_check(&params, TradeType::Native)
+            .unwrap();
+        test_client()
+            .with_sign_casing(SignCasing::Lower)
+            .self_check(&params, TradeType::Native)
+            .unwrap();
+
+        // 去掉首尾空白且不转义的序列化
+        let broken = |pairs: &BTreeMap<String, String>| {
+            let fields = pairs
+                .iter()
+                .map(|(k, v)| format!("<{0}>{1}</{0}>", k, v.trim()))
+                .collect::<String>();
+            format!("<xml>{}</xml>", fields)
+        };
+        match test_client().self_check_with(&params, TradeType::Native, broken) {
+            Err(WechatpayError::SignatureMismatch) => {}
+            result => panic!("expected a signature mismatch, got {:?}", result),
+        }
+
+        params.remove("product_id");
+        match test_client().self_check(&params, TradeType::Native) {
+            Err(WechatpayError::MissingField { field, .. }) => assert_eq!(field, "product_id"),
+            result => panic!("expected a missing field error, got {:?}", result),
+        }
+    }
+}
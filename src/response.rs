@@ -0,0 +1,959 @@
+//! 接口返回结果的类型化封装
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use time::{self, Tm};
+use xml::reader::{EventReader, XmlEvent};
+
+use money::known_currency;
+use {
+    from_xml_str, BankType, ErrCode, Money, TradeType, WechatpayError, WechatpayResult,
+    CURRENCY_CNY,
+};
+
+/// [统一下单] 返回结果
+#[derive(Clone, Debug)]
+pub struct UnifiedOrderResponse {
+    /// 预支付交易会话标识
+    pub prepay_id: String,
+    /// 二维码链接 (`trade_type` 为 `NATIVE` 时返回)
+    pub code_url: Option<String>,
+    /// 网关返回的交易类型
+    pub trade_type: Option<TradeType>,
+    /// 原始返回数据
+    pub pairs: BTreeMap<String, String>,
+    requested_trade_type: TradeType,
+    profit_sharing: bool,
+}
+
+impl UnifiedOrderResponse {
+    /// 由网关返回数据和下单时使用的交易类型生成
+    pub fn new(
+        pairs: BTreeMap<String, String>,
+        requested_trade_type: TradeType,
+    ) -> UnifiedOrderResponse {
+        UnifiedOrderResponse {
+            prepay_id: pairs.get("prepay_id").cloned().unwrap_or_default(),
+            code_url: pairs.get("code_url").cloned(),
+            trade_type: pairs
+                .get("trade_type")
+                .and_then(|s| TradeType::from_response(s)),
+            requested_trade_type,
+            profit_sharing: false,
+            pairs,
+        }
+    }
+
+    pub(crate) fn with_profit_sharing(mut self, profit_sharing: bool) -> UnifiedOrderResponse {
+        self.profit_sharing = profit_sharing;
+        self
+    }
+
+    /// 下单时指定了 `profit_sharing=Y`: 支付成功后资金冻结, 需要调用分账接口并在最后调用
+    /// `WechatpayClient::profit_sharing_finish`, 否则资金不会解冻
+    pub fn requires_profit_sharing_followup(&self) -> bool {
+        self.profit_sharing
+    }
+
+    /// 下单时使用的交易类型
+    ///
+    /// `Native` 和 `Qrcode` 在网关中都是 `NATIVE`, 这里返回调用方实际使用的类型
+    pub fn requested_trade_type(&self) -> TradeType {
+        self.requested_trade_type
+    }
+}
+
+/// [提交刷卡支付] 的处理结果
+#[derive(Debug)]
+pub enum MicropayOutcome {
+    /// 支付成功
+    Paid(BTreeMap<String, String>),
+    /// 支付结果未知 (如 `USERPAYING`, `SYSTEMERROR`, 网络错误),
+    /// 需要调用查询订单确认, 仍未成功时调用撤销订单;
+    /// `ORDERPAID` (重复提交已支付的订单, 如第一次的响应丢失) 也在此列, 查询结果为支付成功
+    NeedsQuery(WechatpayError),
+    /// 支付失败, 不需要查询
+    Failed(WechatpayError),
+}
+
+impl MicropayOutcome {
+    /// 根据 `micro_pay` 的返回结果判断下一步操作
+    pub fn from_result(result: WechatpayResult) -> MicropayOutcome {
+        match result {
+            Ok(pairs) => MicropayOutcome::Paid(pairs),
+            Err(e) => {
+                let needs_query = match e {
+                    WechatpayError::Business { ref err_code, .. } => matches!(
+                        *err_code,
+                        ErrCode::SystemError
+                            | ErrCode::BankError
+                            | ErrCode::UserPaying
+                            | ErrCode::PayError
+                            | ErrCode::OrderPaid
+                    ),
+                    ref e => e.is_network(),
+                };
+                if needs_query {
+                    MicropayOutcome::NeedsQuery(e)
+                } else {
+                    MicropayOutcome::Failed(e)
+                }
+            }
+        }
+    }
+
+    /// 支付成功时的终端设备号 (`device_info`)
+    pub fn device_info(&self) -> Option<&str> {
+        match *self {
+            MicropayOutcome::Paid(ref pairs) => pairs.get("device_info").map(|s| s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// 支付成功时的付款银行 (`bank_type`)
+    pub fn bank_type(&self) -> Option<BankType> {
+        match *self {
+            MicropayOutcome::Paid(ref pairs) => {
+                pairs.get("bank_type").map(|s| BankType::from_response(s))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 交易状态 (`trade_state`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeState {
+    /// `SUCCESS`: 支付成功
+    Success,
+    /// `REFUND`: 转入退款
+    Refund,
+    /// `NOTPAY`: 未支付
+    NotPay,
+    /// `CLOSED`: 已关闭
+    Closed,
+    /// `REVOKED`: 已撤销 (刷卡支付)
+    Revoked,
+    /// `USERPAYING`: 用户支付中
+    UserPaying,
+    /// `PAYERROR`: 支付失败 (其他原因, 如银行返回失败)
+    PayError,
+}
+
+impl TradeState {
+    /// 解析网关返回的 `trade_state`
+    pub fn from_response(s: &str) -> Option<TradeState> {
+        match s {
+            "SUCCESS" => Some(TradeState::Success),
+            "REFUND" => Some(TradeState::Refund),
+            "NOTPAY" => Some(TradeState::NotPay),
+            "CLOSED" => Some(TradeState::Closed),
+            "REVOKED" => Some(TradeState::Revoked),
+            "USERPAYING" => Some(TradeState::UserPaying),
+            "PAYERROR" => Some(TradeState::PayError),
+            _ => None,
+        }
+    }
+
+    /// 用户已付款 (`SUCCESS` 或 `REFUND`); `PAYERROR` 等其他状态都视为未付款
+    pub fn is_paid(&self) -> bool {
+        matches!(*self, TradeState::Success | TradeState::Refund)
+    }
+
+    /// 终态: 状态不会再变化 (`REFUND` 之后仍可能继续退款, 但支付结果已确定)
+    pub fn is_terminal(&self) -> bool {
+        !matches!(*self, TradeState::NotPay | TradeState::UserPaying)
+    }
+}
+
+/// [支付结果通知]
+#[derive(Clone, Debug)]
+pub struct PaymentNotification {
+    /// 公众账号ID
+    pub appid: String,
+    /// 商户号
+    pub mch_id: String,
+    /// 用户标识
+    pub openid: String,
+    /// 微信订单号
+    pub transaction_id: String,
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 交易类型
+    pub trade_type: Option<TradeType>,
+    /// 交易状态, 通知中没有 `trade_state` 时为 `Success`
+    pub trade_state: TradeState,
+    /// 付款银行
+    pub bank_type: BankType,
+    /// 订单金额 (币种为 `fee_type`)
+    pub total_fee: Money,
+    /// 现金支付金额
+    pub cash_fee: Money,
+    /// 支付完成时间
+    pub time_end: Tm,
+    /// 商家数据包
+    pub attach: Option<String>,
+    /// 原始数据
+    pub pairs: BTreeMap<String, String>,
+}
+
+impl PaymentNotification {
+    /// 由已经校验过签名和业务结果的通知数据生成
+    pub fn from_pairs(
+        pairs: BTreeMap<String, String>,
+    ) -> Result<PaymentNotification, WechatpayError> {
+        let field = |name: &str| -> Result<String, WechatpayError> {
+            pairs
+                .get(name)
+                .cloned()
+                .ok_or_else(|| WechatpayError::MissingField {
+                    operation: "notification",
+                    field: name.to_string(),
+                })
+        };
+        let invalid = |name: &str, reason: String| WechatpayError::InvalidField {
+            field: name.to_string(),
+            reason,
+        };
+        let money = |name: &str, currency: &'static str| -> Result<Money, WechatpayError> {
+            let value = field(name)?;
+            value
+                .parse()
+                .map(|cents| Money::from_cents(cents).with_currency(currency))
+                .map_err(|_| invalid(name, format!("{} is not a number", value)))
+        };
+
+        let fee_type = pairs
+            .get("fee_type")
+            .map(|s| s.as_str())
+            .unwrap_or(CURRENCY_CNY);
+        let currency = known_currency(fee_type)
+            .ok_or_else(|| invalid("fee_type", format!("unknown currency {}", fee_type)))?;
+        let cash_fee_type = pairs
+            .get("cash_fee_type")
+            .map(|s| s.as_str())
+            .unwrap_or(CURRENCY_CNY);
+        let cash_currency = known_currency(cash_fee_type).ok_or_else(|| {
+            invalid(
+                "cash_fee_type",
+                format!("unknown currency {}", cash_fee_type),
+            )
+        })?;
+        let trade_state = match pairs.get("trade_state") {
+            Some(state) => TradeState::from_response(state)
+                .ok_or_else(|| invalid("trade_state", format!("unknown state {}", state)))?,
+            None => TradeState::Success,
+        };
+        let time_end = field("time_end")?;
+        let time_end = time::strptime(&time_end, "%Y%m%d%H%M%S")
+            .map_err(|e| invalid("time_end", format!("{}: {}", time_end, e)))?;
+
+        Ok(PaymentNotification {
+            appid: field("appid")?,
+            mch_id: field("mch_id")?,
+            openid: field("openid")?,
+            transaction_id: field("transaction_id")?,
+            out_trade_no: field("out_trade_no")?,
+            trade_type: pairs
+                .get("trade_type")
+                .and_then(|s| TradeType::from_response(s)),
+            trade_state,
+            bank_type: BankType::from_response(&field("bank_type")?),
+            total_fee: money("total_fee", currency)?,
+            cash_fee: money("cash_fee", cash_currency)?,
+            time_end,
+            attach: pairs.get("attach").cloned(),
+            pairs,
+        })
+    }
+}
+
+/// [查询订单] 返回结果
+///
+/// 未支付的订单没有 `bank_type`, `cash_fee`, `time_end` 等字段, 对应的字段为 `None`
+#[derive(Clone, Debug)]
+pub struct OrderQueryResult {
+    /// 微信订单号, 未支付时可能没有
+    pub transaction_id: Option<String>,
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 交易状态
+    pub trade_state: TradeState,
+    /// 交易状态描述
+    pub trade_state_desc: String,
+    /// 付款银行
+    pub bank_type: Option<BankType>,
+    /// 订单金额 (币种为 `fee_type`)
+    pub total_fee: Money,
+    /// 现金支付金额
+    pub cash_fee: Option<Money>,
+    /// 支付完成时间
+    pub time_end: Option<Tm>,
+    /// 用户标识
+    pub openid: Option<String>,
+    /// 商家数据包
+    pub attach: Option<String>,
+    /// 原始数据
+    pub pairs: BTreeMap<String, String>,
+}
+
+impl TryFrom<BTreeMap<String, String>> for OrderQueryResult {
+    type Error = WechatpayError;
+
+    /// 由已经检查过 `result_code` 的查询结果生成
+    fn try_from(pairs: BTreeMap<String, String>) -> Result<OrderQueryResult, WechatpayError> {
+        let field = |name: &str| -> Result<String, WechatpayError> {
+            pairs
+                .get(name)
+                .cloned()
+                .ok_or_else(|| WechatpayError::MissingField {
+                    operation: "orderquery",
+                    field: name.to_string(),
+                })
+        };
+        let invalid = |name: &str, reason: String| WechatpayError::InvalidField {
+            field: name.to_string(),
+            reason,
+        };
+        let currency = |name: &str| -> Result<&'static str, WechatpayError> {
+            let fee_type = pairs.get(name).map(|s| s.as_str()).unwrap_or(CURRENCY_CNY);
+            known_currency(fee_type)
+                .ok_or_else(|| invalid(name, format!("unknown currency {}", fee_type)))
+        };
+        let money = |name: &str, currency: &'static str| -> Result<Money, WechatpayError> {
+            let value = field(name)?;
+            value
+                .parse()
+                .map(|cents| Money::from_cents(cents).with_currency(currency))
+                .map_err(|_| invalid(name, format!("{} is not a number", value)))
+        };
+
+        let trade_state = field("trade_state")?;
+        let trade_state = TradeState::from_response(&trade_state)
+            .ok_or_else(|| invalid("trade_state", format!("unknown state {}", trade_state)))?;
+        let cash_fee = match pairs.get("cash_fee") {
+            Some(_) => Some(money("cash_fee", currency("cash_fee_type")?)?),
+            None => None,
+        };
+        let time_end = match pairs.get("time_end") {
+            Some(time_end) => Some(
+                time::strptime(time_end, "%Y%m%d%H%M%S")
+                    .map_err(|e| invalid("time_end", format!("{}: {}", time_end, e)))?,
+            ),
+            None => None,
+        };
+
+        Ok(OrderQueryResult {
+            transaction_id: pairs.get("transaction_id").cloned(),
+            out_trade_no: field("out_trade_no")?,
+            trade_state,
+            trade_state_desc: pairs.get("trade_state_desc").cloned().unwrap_or_default(),
+            bank_type: pairs.get("bank_type").map(|s| BankType::from_response(s)),
+            total_fee: money("total_fee", currency("fee_type")?)?,
+            cash_fee,
+            time_end,
+            openid: pairs.get("openid").cloned(),
+            attach: pairs.get("attach").cloned(),
+            pairs,
+        })
+    }
+}
+
+/// 红包领取记录 (`hblist` 中的 `hbinfo`)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HbInfo {
+    /// 领取红包的用户
+    pub openid: String,
+    /// 领取金额, 单位为【分】
+    pub amount: u32,
+    /// 领取红包的时间
+    pub rcv_time: String,
+}
+
+/// [查询红包记录] 返回结果
+#[derive(Clone, Debug, Default)]
+pub struct RedPackInfo {
+    /// `hblist` 以外的返回数据
+    pub pairs: BTreeMap<String, String>,
+    /// 领取红包的列表
+    pub hb_list: Vec<HbInfo>,
+}
+
+impl RedPackInfo {
+    /// 解析网关返回的`xml`数据
+    pub fn from_xml_str(data: &str) -> RedPackInfo {
+        RedPackInfo::from_pairs(hb_info_pairs(data))
+    }
+
+    /// 解析 `WechatpayClient::get_hb_info` 的返回结果
+    ///
+    /// 领取记录为 `openid_$n`, `amount_$n`, `rcv_time_$n`, 数量为 `hbinfo_count`
+    pub fn from_pairs(pairs: BTreeMap<String, String>) -> RedPackInfo {
+        let mut pairs = pairs;
+        let count = pairs
+            .remove("hbinfo_count")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(0)
+            .min(pairs.len());
+        let mut hb_list = Vec::new();
+        for i in 0..count {
+            let mut field = |name: &str| pairs.remove(&format!("{}_{}", name, i));
+            hb_list.push(HbInfo {
+                openid: field("openid").unwrap_or_default(),
+                amount: field("amount").and_then(|s| s.parse().ok()).unwrap_or(0),
+                rcv_time: field("rcv_time").unwrap_or_default(),
+            });
+        }
+        RedPackInfo { pairs, hb_list }
+    }
+
+    /// 红包状态: `SENDING`, `SENT`, `FAILED`, `RECEIVED`, `RFUND_ING`, `REFUND`
+    pub fn status(&self) -> Option<&str> {
+        self.pairs.get("status").map(|s| s.as_str())
+    }
+}
+
+/// 解析 [查询红包记录] 返回的`xml`数据, `hblist` 中的每条记录展开为 `_$n` 字段
+///
+/// 通用的解析会把各条记录的字段混在一起, 见 `RedPackInfo::from_pairs`
+pub(crate) fn hb_info_pairs(data: &str) -> BTreeMap<String, String> {
+    let (mut pairs, items) = parse_xml_list(data, "hbinfo");
+    pairs.insert("hbinfo_count".to_string(), items.len().to_string());
+    for (i, item) in items.into_iter().enumerate() {
+        for (key, value) in item {
+            pairs.insert(format!("{}_{}", key, i), value);
+        }
+    }
+    pairs
+}
+
+/// 退款状态 (`refund_status`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefundStatus {
+    /// `SUCCESS`: 退款成功
+    Success,
+    /// `CHANGE`: 退款异常
+    Change,
+    /// `REFUNDCLOSE`: 退款关闭
+    RefundClose,
+}
+
+impl RefundStatus {
+    /// 解析网关返回的 `refund_status`
+    pub fn from_response(s: &str) -> Option<RefundStatus> {
+        match s {
+            "SUCCESS" => Some(RefundStatus::Success),
+            "CHANGE" => Some(RefundStatus::Change),
+            "REFUNDCLOSE" => Some(RefundStatus::RefundClose),
+            _ => None,
+        }
+    }
+}
+
+/// [退款结果通知] 中 `req_info` 解密后的内容
+#[derive(Clone, Debug)]
+pub struct RefundNotification {
+    /// 微信订单号
+    pub transaction_id: String,
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 微信退款单号
+    pub refund_id: String,
+    /// 商户退款单号
+    pub out_refund_no: String,
+    /// 订单金额
+    pub total_fee: Money,
+    /// 申请退款金额
+    pub refund_fee: Money,
+    /// 退款金额 (去掉非充值代金券退款金额)
+    pub settlement_refund_fee: Option<Money>,
+    /// 退款状态
+    pub refund_status: RefundStatus,
+    /// 退款成功时间, 如 `2017-12-15 09:46:01`
+    pub success_time: Option<String>,
+    /// 退款入账账户, 如 `招商银行信用卡0403`
+    pub refund_recv_accout: Option<String>,
+    /// 原始数据
+    pub pairs: BTreeMap<String, String>,
+}
+
+impl RefundNotification {
+    /// 解析解密后的 `req_info` (`<root>...</root>`)
+    pub fn from_xml_str(data: &str) -> Result<RefundNotification, WechatpayError> {
+        let pairs = from_xml_str(data);
+        let field = |name: &str| -> Result<String, WechatpayError> {
+            pairs
+                .get(name)
+                .cloned()
+                .ok_or_else(|| WechatpayError::MissingField {
+                    operation: "refund_notification",
+                    field: name.to_string(),
+                })
+        };
+        let money = |name: &str| -> Result<Money, WechatpayError> {
+            field(name)?
+                .parse()
+                .map(Money::from_cents)
+                .map_err(|_| WechatpayError::InvalidField {
+                    field: name.to_string(),
+                    reason: "not a number".to_string(),
+                })
+        };
+        let refund_status = field("refund_status")?;
+        Ok(RefundNotification {
+            transaction_id: field("transaction_id")?,
+            out_trade_no: field("out_trade_no")?,
+            refund_id: field("refund_id")?,
+            out_refund_no: field("out_refund_no")?,
+            total_fee: money("total_fee")?,
+            refund_fee: money("refund_fee")?,
+            settlement_refund_fee: match pairs.get("settlement_refund_fee") {
+                Some(_) => Some(money("settlement_refund_fee")?),
+                None => None,
+            },
+            refund_status: RefundStatus::from_response(&refund_status).ok_or_else(|| {
+                WechatpayError::InvalidField {
+                    field: "refund_status".to_string(),
+                    reason: format!("unknown status {}", refund_status),
+                }
+            })?,
+            success_time: pairs.get("success_time").cloned(),
+            refund_recv_accout: pairs.get("refund_recv_accout").cloned(),
+            pairs,
+        })
+    }
+}
+
+/// 退款中使用的代金券 (`coupon_refund_id_$n` 等)
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefundCoupon {
+    /// 退款代金券ID
+    pub coupon_refund_id: String,
+    /// 代金券类型: `CASH` (充值代金券) 或 `NO_CASH` (非充值代金券)
+    pub coupon_type: Option<String>,
+    /// 单个代金券退款金额
+    pub coupon_refund_fee: Money,
+}
+
+/// [申请退款] 返回结果
+///
+/// 订单使用了代金券时, 退款金额 `refund_fee` 分为两部分: `cash_refund_fee` 退回给用户,
+/// `coupon_refund_fee` 退回到代金券, 对账时不要重复计算
+#[derive(Clone, Debug)]
+pub struct RefundResponse {
+    /// 微信订单号
+    pub transaction_id: String,
+    /// 商户订单号
+    pub out_trade_no: String,
+    /// 商户退款单号
+    pub out_refund_no: String,
+    /// 微信退款单号
+    pub refund_id: String,
+    /// 申请退款金额
+    pub refund_fee: Money,
+    /// 订单金额
+    pub total_fee: Money,
+    /// 现金支付金额
+    pub cash_fee: Money,
+    /// 现金退款金额
+    pub cash_refund_fee: Option<Money>,
+    /// 代金券退款总金额, 没有使用代金券时为 0
+    pub coupon_refund_fee: Money,
+    /// 退款的代金券
+    pub coupons: Vec<RefundCoupon>,
+    /// 原始数据
+    pub pairs: BTreeMap<String, String>,
+}
+
+impl RefundResponse {
+    /// 由网关返回数据生成
+    pub fn from_pairs(pairs: BTreeMap<String, String>) -> Result<RefundResponse, WechatpayError> {
+        let field = |name: &str| -> Result<String, WechatpayError> {
+            pairs
+                .get(name)
+                .cloned()
+                .ok_or_else(|| WechatpayError::MissingField {
+                    operation: "refund",
+                    field: name.to_string(),
+                })
+        };
+        let parse = |name: &str, value: &str| -> Result<Money, WechatpayError> {
+            value
+                .parse()
+                .map(Money::from_cents)
+                .map_err(|_| WechatpayError::InvalidField {
+                    field: name.to_string(),
+                    reason: format!("{} is not a number", value),
+                })
+        };
+        let money = |name: &str| parse(name, &field(name)?);
+        let optional_money = |name: &str| match pairs.get(name) {
+            Some(value) => parse(name, value).map(Some),
+            None => Ok(None),
+        };
+
+        let count = match pairs.get("coupon_refund_count") {
+            Some(count) => count
+                .parse::<usize>()
+                .map_err(|_| WechatpayError::InvalidField {
+                    field: "coupon_refund_count".to_string(),
+                    reason: format!("{} is not a number", count),
+                })?,
+            None => 0,
+        };
+        // 每张代金券至少有 `coupon_refund_id_$n` 一个字段, 数量不可能超过字段总数
+        if count > pairs.len() {
+            return Err(WechatpayError::InvalidField {
+                field: "coupon_refund_count".to_string(),
+                reason: format!("{} exceeds the number of fields", count),
+            });
+        }
+        let mut coupons = Vec::new();
+        for n in 0..count {
+            coupons.push(RefundCoupon {
+                coupon_refund_id: field(&format!("coupon_refund_id_{}", n))?,
+                coupon_type: pairs.get(&format!("coupon_type_{}", n)).cloned(),
+                coupon_refund_fee: money(&format!("coupon_refund_fee_{}", n))?,
+            });
+        }
+
+        Ok(RefundResponse {
+            transaction_id: field("transaction_id")?,
+            out_trade_no: field("out_trade_no")?,
+            out_refund_no: field("out_refund_no")?,
+            refund_id: field("refund_id")?,
+            refund_fee: money("refund_fee")?,
+            total_fee: money("total_fee")?,
+            cash_fee: money("cash_fee")?,
+            cash_refund_fee: optional_money("cash_refund_fee")?,
+            coupon_refund_fee: optional_money("coupon_refund_fee")?
+                .unwrap_or_else(|| Money::from_cents(0)),
+            coupons,
+            pairs,
+        })
+    }
+
+    /// 实际退回给用户的现金
+    ///
+    /// 优先使用 `cash_refund_fee`, 网关没有返回时为 `refund_fee` 减去代金券退款金额
+    pub fn actual_cash_returned(&self) -> Money {
+        self.cash_refund_fee.unwrap_or_else(|| {
+            self.refund_fee
+                .checked_sub(self.coupon_refund_fee)
+                .unwrap_or_else(|| Money::from_cents(0))
+        })
+    }
+}
+
+/// 解析带有重复元素的`xml`数据
+///
+/// 返回根元素下的字段和每个 `<item>` 元素中的字段, 列表中的字段不会混入根元素的字段
+pub(crate) fn parse_xml_list(
+    data: &str,
+    item: &str,
+) -> (BTreeMap<String, String>, Vec<BTreeMap<String, String>>) {
+    let mut pairs = BTreeMap::new();
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut item_depth = None;
+    let mut tag = "".to_string();
+
+    for event in EventReader::from_str(data) {
+        match event {
+            Ok(XmlEvent::StartElement { name, .. }) => {
+                depth += 1;
+                if item_depth.is_none() && name.local_name == item {
+                    item_depth = Some(depth);
+                    items.push(BTreeMap::new());
+                }
+                tag = name.local_name;
+            }
+            Ok(XmlEvent::EndElement { .. }) => {
+                if item_depth == Some(depth) {
+                    item_depth = None;
+                }
+                depth -= 1;
+                tag.clear();
+            }
+            Ok(XmlEvent::CData(value)) | Ok(XmlEvent::Characters(value)) => match item_depth {
+                Some(d) if depth == d + 1 => {
+                    if let Some(current) = items.last_mut() {
+                        current.insert(tag.clone(), value);
+                    }
+                }
+                None if depth == 2 => {
+                    pairs.insert(tag.clone(), value);
+                }
+                _ => {}
+            },
+            Err(_) => break,
+            _ => {}
+        }
+    }
+    (pairs, items)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{
+        HbInfo, MicropayOutcome, RedPackInfo, RefundCoupon, RefundNotification, RefundResponse,
+        RefundStatus, UnifiedOrderResponse,
+    };
+    use {ErrCode, Money, TradeType, WechatpayError};
+
+    #[test]
+    fn test_requested_trade_type() {
+        let mut pairs = BTreeMap::new();
+        pairs.insert(
+            "prepay_id".to_string(),
+            "wx201410272009395522657a690389285100".to_string(),
+        );
+        pairs.insert(
+            "code_url".to_string(),
+            "weixin://wxpay/s/An4baqw".to_string(),
+        );
+        pairs.insert("trade_type".to_string(), "NATIVE".to_string());
+
+        let response = UnifiedOrderResponse::new(pairs.clone(), TradeType::Qrcode);
+        assert_eq!(response.requested_trade_type(), TradeType::Qrcode);
+        assert_eq!(response.trade_type, Some(TradeType::Native));
+        assert_eq!(response.prepay_id, "wx201410272009395522657a690389285100");
+        assert_eq!(
+            response.code_url,
+            Some("weixin://wxpay/s/An4baqw".to_string())
+        );
+
+        let response = UnifiedOrderResponse::new(pairs, TradeType::Native);
+        assert_eq!(response.requested_trade_type(), TradeType::Native);
+    }
+
+    #[test]
+    fn test_trade_type_from_response() {
+        assert_eq!(TradeType::from_response("JSAPI"), Some(TradeType::Jsapi));
+        assert_eq!(TradeType::from_response("NATIVE"), Some(TradeType::Native));
+        assert_eq!(TradeType::from_response("APP"), Some(TradeType::App));
+        assert_eq!(TradeType::from_response("MICROPAY"), Some(TradeType::Micro));
+        assert_eq!(TradeType::from_response("MWEB"), Some(TradeType::Mweb));
+        assert_eq!(TradeType::from_response("MICROPAY_H5"), None);
+    }
+
+    #[test]
+    fn test_red_pack_info() {
+        let source = r#"
+<xml>
+    <return_code><![CDATA[SUCCESS]]></return_code>
+    <return_msg><![CDATA[OK]]></return_msg>
+    <result_code><![CDATA[SUCCESS]]></result_code>
+    <mch_billno><![CDATA[9010080799701411170000046603]]></mch_billno>
+    <mch_id><![CDATA[11475856]]></mch_id>
+    <detail_id><![CDATA[10000417012016080830956240040]]></detail_id>
+    <status><![CDATA[RECEIVED]]></status>
+    <send_type><![CDATA[ACTIVITY]]></send_type>
+    <hb_type><![CDATA[GROUP]]></hb_type>
+    <total_num>2</total_num>
+    <total_amount>300</total_amount>
+    <send_time><![CDATA[2016-08-08 14:21:47]]></send_time>
+    <hblist>
+        <hbinfo>
+            <openid><![CDATA[oHkLxtzmyHXX6FW_cAWo_orTSRXs]]></openid>
+            <amount>100</amount>
+            <rcv_time><![CDATA[2016-08-08 14:22:02]]></rcv_time>
+        </hbinfo>
+        <hbinfo>
+            <openid><![CDATA[oHkLxt_htg84TUEbzvlMwQzVDBqo]]></openid>
+            <amount>200</amount>
+            <rcv_time><![CDATA[2016-08-08 14:23:11]]></rcv_time>
+        </hbinfo>
+    </hblist>
+</xml>
+"#;
+        let info = RedPackInfo::from_xml_str(source);
+        assert_eq!(info.status(), Some("RECEIVED"));
+        assert_eq!(info.pairs.get("total_amount").unwrap(), "300");
+        assert!(!info.pairs.contains_key("openid"));
+        assert_eq!(
+            info.hb_list,
+            vec![
+                HbInfo {
+                    openid: "oHkLxtzmyHXX6FW_cAWo_orTSRXs".to_string(),
+                    amount: 100,
+                    rcv_time: "2016-08-08 14:22:02".to_string(),
+                },
+                HbInfo {
+                    openid: "oHkLxt_htg84TUEbzvlMwQzVDBqo".to_string(),
+                    amount: 200,
+                    rcv_time: "2016-08-08 14:23:11".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refund_notification() {
+        let source = r#"
+<root>
+<out_refund_no><![CDATA[131811191610442717309]]></out_refund_no>
+<out_trade_no><![CDATA[71106718111915575302817]]></out_trade_no>
+<refund_account><![CDATA[REFUND_SOURCE_RECHARGE_FUNDS]]></refund_account>
+<refund_fee><![CDATA[3960]]></refund_fee>
+<refund_id><![CDATA[50000408942018111907145868882]]></refund_id>
+<refund_recv_accout><![CDATA[支付用户零钱]]></refund_recv_accout>
+<refund_request_source><![CDATA[API]]></refund_request_source>
+<refund_status><![CDATA[SUCCESS]]></refund_status>
+<settlement_refund_fee><![CDATA[3960]]></settlement_refund_fee>
+<settlement_total_fee><![CDATA[3960]]></settlement_total_fee>
+<success_time><![CDATA[2018-11-19 16:24:13]]></success_time>
+<total_fee><![CDATA[3960]]></total_fee>
+<transaction_id><![CDATA[4200000215201811190261405420]]></transaction_id>
+</root>
+"#;
+        let notification = RefundNotification::from_xml_str(source).unwrap();
+        assert_eq!(notification.refund_status, RefundStatus::Success);
+        assert_eq!(notification.out_refund_no, "131811191610442717309");
+        assert_eq!(notification.refund_fee, Money::from_cents(3960));
+        assert_eq!(
+            notification.settlement_refund_fee,
+            Some(Money::from_cents(3960))
+        );
+        assert_eq!(
+            notification.success_time.as_ref().unwrap(),
+            "2018-11-19 16:24:13"
+        );
+        assert_eq!(
+            notification.refund_recv_accout.as_ref().unwrap(),
+            "支付用户零钱"
+        );
+        assert_eq!(
+            notification.pairs.get("refund_account").unwrap(),
+            "REFUND_SOURCE_RECHARGE_FUNDS"
+        );
+
+        let closed = source.replace(
+            "<refund_status><![CDATA[SUCCESS]]>",
+            "<refund_status><![CDATA[REFUNDCLOSE]]>",
+        );
+        let notification = RefundNotification::from_xml_str(&closed).unwrap();
+        assert_eq!(notification.refund_status, RefundStatus::RefundClose);
+
+        let unknown = source.replace(
+            "<refund_status><![CDATA[SUCCESS]]>",
+            "<refund_status><![CDATA[PROCESSING]]>",
+        );
+        match RefundNotification::from_xml_str(&unknown) {
+            Err(WechatpayError::InvalidField { field, .. }) => assert_eq!(field, "refund_status"),
+            _ => panic!("expected an invalid field error"),
+        }
+    }
+
+    #[test]
+    fn test_refund_response_coupon_split() {
+        let mut pairs = BTreeMap::new();
+        for &(k, v) in [
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("transaction_id", "1008450740201411110005820873"),
+            ("out_trade_no", "1415757673"),
+            ("out_refund_no", "1415701182"),
+            ("refund_id", "2008450740201411110000174436"),
+            ("refund_fee", "1000"),
+            ("total_fee", "1000"),
+            ("cash_fee", "800"),
+            ("cash_refund_fee", "800"),
+            ("coupon_refund_fee", "200"),
+            ("coupon_refund_count", "1"),
+            ("coupon_refund_id_0", "10000"),
+            ("coupon_type_0", "NO_CASH"),
+            ("coupon_refund_fee_0", "200"),
+        ]
+        .iter()
+        {
+            pairs.insert(k.to_string(), v.to_string());
+        }
+
+        let response = RefundResponse::from_pairs(pairs.clone()).unwrap();
+        assert_eq!(response.refund_fee, Money::from_cents(1000));
+        assert_eq!(response.actual_cash_returned(), Money::from_cents(800));
+        assert_eq!(response.coupon_refund_fee, Money::from_cents(200));
+        assert_eq!(
+            response.coupons,
+            vec![RefundCoupon {
+                coupon_refund_id: "10000".to_string(),
+                coupon_type: Some("NO_CASH".to_string()),
+                coupon_refund_fee: Money::from_cents(200),
+            }]
+        );
+
+        // 没有返回 cash_refund_fee 时由退款金额减去代金券部分
+        pairs.remove("cash_refund_fee");
+        let response = RefundResponse::from_pairs(pairs.clone()).unwrap();
+        assert_eq!(response.actual_cash_returned(), Money::from_cents(800));
+
+        for count in ["18446744073709551615", "100"].iter() {
+            let mut pairs = pairs.clone();
+            pairs.insert("coupon_refund_count".to_string(), count.to_string());
+            match RefundResponse::from_pairs(pairs) {
+                Err(WechatpayError::InvalidField { field, .. }) => {
+                    assert_eq!(field, "coupon_refund_count")
+                }
+                _ => panic!("expected an invalid field error"),
+            }
+        }
+
+        pairs.remove("coupon_refund_id_0");
+        match RefundResponse::from_pairs(pairs) {
+            Err(WechatpayError::MissingField { field, .. }) => {
+                assert_eq!(field, "coupon_refund_id_0")
+            }
+            _ => panic!("expected a missing field error"),
+        }
+    }
+
+    #[test]
+    fn test_micropay_outcome() {
+        let business = |err_code: &str| {
+            Err(WechatpayError::Business {
+                err_code: ErrCode::from_response(err_code),
+                err_code_des: "".to_string(),
+            })
+        };
+
+        match MicropayOutcome::from_result(Ok(BTreeMap::new())) {
+            MicropayOutcome::Paid(_) => {}
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        for code in [
+            "PAYERROR",
+            "USERPAYING",
+            "SYSTEMERROR",
+            "BANKERROR",
+            "ORDERPAID",
+        ]
+        .iter()
+        {
+            match MicropayOutcome::from_result(business(code)) {
+                MicropayOutcome::NeedsQuery(_) => {}
+                outcome => panic!("unexpected outcome {:?} for {}", outcome, code),
+            }
+        }
+        match MicropayOutcome::from_result(Err(WechatpayError::Request)) {
+            MicropayOutcome::NeedsQuery(_) => {}
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        for code in ["NOTENOUGH", "AUTHCODEEXPIRE"].iter() {
+            match MicropayOutcome::from_result(business(code)) {
+                MicropayOutcome::Failed(_) => {}
+                outcome => panic!("unexpected outcome {:?} for {}", outcome, code),
+            }
+        }
+        match MicropayOutcome::from_result(Err(WechatpayError::MissingField {
+            operation: "micropay",
+            field: "auth_code".to_string(),
+        })) {
+            MicropayOutcome::Failed(_) => {}
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+}
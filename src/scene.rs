@@ -0,0 +1,157 @@
+//! [场景信息] `scene_info` 字段
+//!
+//! H5 支付必须传 `h5_info`, 描述发起支付的网站或应用; 刷卡支付可以传 `store_info`,
+//! 描述门店。两者都是 JSON 字符串, 格式不同, 这里按文档中的格式生成。
+
+use json::json_string;
+use WechatpayError;
+
+/// H5 支付的场景信息 (`h5_info`)
+#[derive(Clone, Debug, PartialEq)]
+pub enum H5Info {
+    /// iOS 应用中的 H5 页面
+    Ios { app_name: String, bundle_id: String },
+    /// Android 应用中的 H5 页面
+    Android {
+        app_name: String,
+        package_name: String,
+    },
+    /// 手机网站
+    Wap { wap_url: String, wap_name: String },
+}
+
+impl H5Info {
+    /// 生成 `scene_info` 字段, 如 `{"h5_info":{"type":"Wap","wap_url":"...","wap_name":"..."}}`
+    pub fn to_scene_info(&self) -> String {
+        let fields = match *self {
+            H5Info::Ios {
+                ref app_name,
+                ref bundle_id,
+            } => [
+                ("type", "IOS"),
+                ("app_name", app_name.as_str()),
+                ("bundle_id", bundle_id.as_str()),
+            ],
+            H5Info::Android {
+                ref app_name,
+                ref package_name,
+            } => [
+                ("type", "Android"),
+                ("app_name", app_name.as_str()),
+                ("package_name", package_name.as_str()),
+            ],
+            H5Info::Wap {
+                ref wap_url,
+                ref wap_name,
+            } => [
+                ("type", "Wap"),
+                ("wap_url", wap_url.as_str()),
+                ("wap_name", wap_name.as_str()),
+            ],
+        };
+        let fields = fields
+            .iter()
+            .map(|&(k, v)| format!("\"{}\":{}", k, json_string(v)))
+            .collect::<Vec<String>>();
+        format!("{{\"h5_info\":{{{}}}}}", fields.join(","))
+    }
+}
+
+/// 门店信息 (`store_info`)
+#[derive(Clone, Debug, Default)]
+pub struct StoreInfo {
+    /// 门店编号, 必填, 最长 32 字节
+    pub id: String,
+    /// 门店名称
+    pub name: Option<String>,
+    /// 门店所在地的行政区划码
+    pub area_code: Option<String>,
+    /// 门店详细地址
+    pub address: Option<String>,
+}
+
+impl StoreInfo {
+    /// 生成 `scene_info` 字段, 未设置的可选字段不输出;
+    /// 门店编号为空或超过 32 字节时返回 `WechatpayError::InvalidField`
+    pub fn to_scene_info(&self) -> Result<String, WechatpayError> {
+        if self.id.is_empty() || self.id.len() > 32 {
+            return Err(WechatpayError::InvalidField {
+                field: "scene_info".to_string(),
+                reason: format!("store id {:?} must be 1 to 32 bytes", self.id),
+            });
+        }
+        let mut fields = vec![format!("\"id\":{}", json_string(&self.id))];
+        for &(key, value) in [
+            ("name", &self.name),
+            ("area_code", &self.area_code),
+            ("address", &self.address),
+        ]
+        .iter()
+        {
+            if let Some(ref value) = *value {
+                fields.push(format!("\"{}\":{}", key, json_string(value)));
+            }
+        }
+        Ok(format!("{{\"store_info\":{{{}}}}}", fields.join(",")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{H5Info, StoreInfo};
+
+    #[test]
+    fn test_h5_info() {
+        let wap = H5Info::Wap {
+            wap_url: "https://pay.qq.com".to_string(),
+            wap_name: "腾讯充值".to_string(),
+        };
+        assert_eq!(
+            wap.to_scene_info(),
+            r#"{"h5_info":{"type":"Wap","wap_url":"https://pay.qq.com","wap_name":"腾讯充值"}}"#
+        );
+        let ios = H5Info::Ios {
+            app_name: "王者荣耀".to_string(),
+            bundle_id: "com.tencent.wzryIOS".to_string(),
+        };
+        assert_eq!(
+            ios.to_scene_info(),
+            r#"{"h5_info":{"type":"IOS","app_name":"王者荣耀","bundle_id":"com.tencent.wzryIOS"}}"#
+        );
+        let android = H5Info::Android {
+            app_name: "王者荣耀".to_string(),
+            package_name: "com.tencent.tmgp.sgame".to_string(),
+        };
+        assert!(android
+            .to_scene_info()
+            .contains(r#""type":"Android","app_name":"王者荣耀","package_name""#));
+    }
+
+    #[test]
+    fn test_store_info() {
+        let store = StoreInfo {
+            id: "SZTX001".to_string(),
+            name: Some("腾大餐厅".to_string()),
+            area_code: Some("440305".to_string()),
+            address: Some("科技园中一路腾讯大厦".to_string()),
+        };
+        assert_eq!(
+            store.to_scene_info().unwrap(),
+            r#"{"store_info":{"id":"SZTX001","name":"腾大餐厅","area_code":"440305","address":"科技园中一路腾讯大厦"}}"#
+        );
+        let store = StoreInfo {
+            id: "SZTX001".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.to_scene_info().unwrap(),
+            r#"{"store_info":{"id":"SZTX001"}}"#
+        );
+        assert!(StoreInfo::default().to_scene_info().is_err());
+        let store = StoreInfo {
+            id: "S".repeat(33),
+            ..Default::default()
+        };
+        assert!(store.to_scene_info().is_err());
+    }
+}
@@ -0,0 +1,54 @@
+//! 签名密钥来源
+//!
+//! 默认使用内存中的 API 密钥签名; 密钥保存在 HSM/KMS 中时可以实现 `SigningKeyProvider`,
+//! 由外部完成 MD5/HMAC 计算, 密钥不进入本进程。
+
+use hmac::{Hmac, Mac};
+use md5;
+use sha2::Sha256;
+
+use SignType;
+
+/// 计算签名
+pub trait SigningKeyProvider: Send + Sync {
+    /// 对 `source` 签名, 返回大写的十六进制字符串
+    ///
+    /// `source` 为排序后的 `k1=v1&k2=v2&...`, 不含 `&key=api_key`, 由实现方拼接密钥后计算
+    fn sign(&self, source: &str, sign_type: SignType) -> String;
+}
+
+/// 内存中的 API 密钥
+impl SigningKeyProvider for str {
+    fn sign(&self, source: &str, sign_type: SignType) -> String {
+        sign_with_key(source, self, sign_type)
+    }
+}
+
+impl SigningKeyProvider for String {
+    fn sign(&self, source: &str, sign_type: SignType) -> String {
+        sign_with_key(source, self, sign_type)
+    }
+}
+
+/// 拼接 `&key=api_key` 后按 `sign_type` 计算签名
+fn sign_with_key(source: &str, api_key: &str, sign_type: SignType) -> String {
+    let source = if source.is_empty() {
+        format!("key={}", api_key)
+    } else {
+        format!("{}&key={}", source, api_key)
+    };
+    let digest = match sign_type {
+        SignType::Md5 => {
+            let mut context = md5::Context::new();
+            context.consume(source.as_bytes());
+            context.compute().to_vec()
+        }
+        SignType::HmacSha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(api_key.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(source.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+    };
+    digest.iter().map(|x| format!("{:02X}", x)).collect()
+}